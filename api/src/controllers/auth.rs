@@ -1,9 +1,11 @@
-use crate::{error::ApiError, extractors::auth_user::AuthUser};
-use axum::{
-    debug_handler,
-    extract::{Path, Query, State},
-    Json,
+use crate::{
+    error::ApiError,
+    extractors::{
+        auth_user::AuthUser,
+        validated::{Json, Path, Query},
+    },
 };
+use axum::{debug_handler, extract::State};
 use base64::engine::Engine;
 use casdoor_rust_sdk::{AuthService, CasdoorConfig};
 use casdoor_rust_sdk::{CasdoorUser, UserService};
@@ -12,12 +14,14 @@ use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::task;
 use util::{AppState, B64_ENGINE};
+use utoipa::IntoParams;
 
 #[utoipa::path(
     post,
     path = "/auth_login",
     responses(
-            (status = 200, description = "Start Auth SSO flow by re-directing to Casdoor", body = String)
+            (status = 200, description = "Start Auth SSO flow by re-directing to Casdoor", body = String),
+            ApiError
         )
 )]
 #[debug_handler]
@@ -44,7 +48,8 @@ pub async fn auth_login(
     post,
     path = "/auth_signup",
     responses(
-            (status = 200, description = "Sign up to service with new SSO user", body = String)
+            (status = 200, description = "Sign up to service with new SSO user", body = String),
+            ApiError
         )
 )]
 #[debug_handler]
@@ -68,9 +73,12 @@ pub async fn auth_signup(
     Err(ApiError::AuthConfigNotConfigured)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct CallbackQuery {
+    /// Auth code returned by the auth service
     pub code: String,
+    /// State passed through the sign in flow
     pub state: String,
 }
 
@@ -78,9 +86,11 @@ pub type Jwt = String;
 #[utoipa::path(
     get,
     path = "/auth_callback",
-    params(
-        ("code" = String, Path, description = "auth code returned by auth service")
-    ),
+    params(CallbackQuery),
+    responses(
+            (status = 200, description = "Token to send as the bearer token of other requests", body = String),
+            ApiError
+        )
 )]
 #[debug_handler]
 pub async fn auth_callback(
@@ -124,10 +134,15 @@ pub async fn auth_callback(
 
 #[utoipa::path(
     get,
-    path = "/auth_user",
+    path = "/auth_users/{name}",
     params(
         ("name" = String, Path, description = "name of auth user in sso service")
     ),
+    responses(
+            (status = 200, description = "Get an SSO user", body = Object),
+            ApiError
+        ),
+    security(("bearer" = []))
 )]
 #[debug_handler]
 pub async fn get_auth_user(
@@ -152,7 +167,15 @@ pub async fn get_auth_user(
     Err(ApiError::AuthConfigNotConfigured)
 }
 
-#[utoipa::path(get, path = "/auth_users")]
+#[utoipa::path(
+    get,
+    path = "/auth_users",
+    responses(
+            (status = 200, description = "List SSO users", body = Vec<Object>),
+            ApiError
+        ),
+    security(("bearer" = []))
+)]
 #[debug_handler]
 pub async fn get_auth_users(
     State(api_state): State<Arc<ModelState>>,
@@ -174,7 +197,16 @@ pub async fn get_auth_users(
     Err(ApiError::AuthConfigNotConfigured)
 }
 
-#[utoipa::path(delete, path = "/auth_user")]
+#[utoipa::path(
+    delete,
+    path = "/auth_users",
+    request_body(content = Object, description = "The SSO user to delete"),
+    responses(
+            (status = 200, description = "Status code returned by the SSO service", body = u16),
+            ApiError
+        ),
+    security(("bearer" = []))
+)]
 #[debug_handler]
 pub async fn delete_auth_user(
     State(api_state): State<Arc<ModelState>>,
@@ -197,7 +229,16 @@ pub async fn delete_auth_user(
     Err(ApiError::AuthConfigNotConfigured)
 }
 
-#[utoipa::path(post, path = "/auth_user")]
+#[utoipa::path(
+    post,
+    path = "/auth_users",
+    request_body(content = Object, description = "The SSO user to add"),
+    responses(
+            (status = 200, description = "Status code returned by the SSO service", body = u16),
+            ApiError
+        ),
+    security(("bearer" = []))
+)]
 pub async fn add_auth_user(
     State(api_state): State<Arc<ModelState>>,
     _auth_user: AuthUser,
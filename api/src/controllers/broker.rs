@@ -0,0 +1,201 @@
+use crate::{
+    error::ApiError,
+    extractors::{auth_user::AdminUser, validated::Path},
+};
+use axum::{debug_handler, extract::State, Json};
+use broker::{
+    admin::{ConsumerInfo, GroupInfo, TopicInfo},
+    Broker, BrokerLayer,
+};
+use model::State as ModelState;
+use std::sync::Arc;
+use tracing::instrument;
+
+fn get_broker(api_state: &ModelState) -> Result<&Broker, ApiError> {
+    api_state
+        .broker
+        .as_ref()
+        .ok_or(ApiError::BrokerNotConfigured)
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/broker/topics",
+    responses(
+            (status = 200, description = "List broker topics", body = Vec<TopicInfo>),
+            ApiError
+        ),
+    security(("bearer" = []))
+)]
+#[instrument(skip(api_state, _admin))]
+#[debug_handler]
+pub async fn get_topics(
+    State(api_state): State<Arc<ModelState>>,
+    _admin: AdminUser,
+) -> Result<Json<Vec<TopicInfo>>, ApiError> {
+    let broker = get_broker(&api_state)?;
+    Ok(Json(broker.list_topics().await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/broker/topics/{topic}",
+    params(
+        ("topic" = String, Path, description = "name of the topic")
+    ),
+    responses(
+            (status = 200, description = "Create an empty topic"),
+            ApiError
+        ),
+    security(("bearer" = []))
+)]
+#[instrument(skip(api_state, _admin))]
+#[debug_handler]
+pub async fn add_topic(
+    State(api_state): State<Arc<ModelState>>,
+    Path(topic): Path<String>,
+    _admin: AdminUser,
+) -> Result<(), ApiError> {
+    let broker = get_broker(&api_state)?;
+    Ok(broker.add_topic(&topic).await?)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/broker/topics/{topic}",
+    params(
+        ("topic" = String, Path, description = "name of the topic")
+    ),
+    responses(
+            (status = 200, description = "Delete a topic with its groups and messages"),
+            ApiError
+        ),
+    security(("bearer" = []))
+)]
+#[instrument(skip(api_state, _admin))]
+#[debug_handler]
+pub async fn remove_topic(
+    State(api_state): State<Arc<ModelState>>,
+    Path(topic): Path<String>,
+    _admin: AdminUser,
+) -> Result<(), ApiError> {
+    let broker = get_broker(&api_state)?;
+    Ok(broker.remove_queue(&topic).await?)
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/broker/topics/{topic}/groups",
+    params(
+        ("topic" = String, Path, description = "name of the topic")
+    ),
+    responses(
+            (status = 200, description = "List consumer groups of a topic", body = Vec<GroupInfo>),
+            ApiError
+        ),
+    security(("bearer" = []))
+)]
+#[instrument(skip(api_state, _admin))]
+#[debug_handler]
+pub async fn get_groups(
+    State(api_state): State<Arc<ModelState>>,
+    Path(topic): Path<String>,
+    _admin: AdminUser,
+) -> Result<Json<Vec<GroupInfo>>, ApiError> {
+    let broker = get_broker(&api_state)?;
+    Ok(Json(broker.list_groups(&topic).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/broker/topics/{topic}/groups/{group}/consumers",
+    params(
+        ("topic" = String, Path, description = "name of the topic"),
+        ("group" = String, Path, description = "name of the consumer group")
+    ),
+    responses(
+            (status = 200, description = "List consumers in a group", body = Vec<ConsumerInfo>),
+            ApiError
+        ),
+    security(("bearer" = []))
+)]
+#[instrument(skip(api_state, _admin))]
+#[debug_handler]
+pub async fn get_consumers(
+    State(api_state): State<Arc<ModelState>>,
+    Path((topic, group)): Path<(String, String)>,
+    _admin: AdminUser,
+) -> Result<Json<Vec<ConsumerInfo>>, ApiError> {
+    let broker = get_broker(&api_state)?;
+    Ok(Json(broker.list_consumers(&topic, &group).await?))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::{memory_state, send, test_env};
+    use axum::body::{to_bytes, Body};
+    use broker::admin::TopicInfo;
+    use http::{header::AUTHORIZATION, Method, Request, StatusCode};
+    use model::State as ModelState;
+    use std::sync::Arc;
+
+    async fn admin(
+        app_state: &Arc<ModelState>,
+        method: Method,
+        uri: &str,
+        token: &str,
+    ) -> (StatusCode, Vec<u8>) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = send(app_state.clone(), request).await;
+        let status = response.status();
+        let body = to_bytes(response.into_body(), 1024 * 1024).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    async fn topics(app_state: &Arc<ModelState>) -> Vec<TopicInfo> {
+        let (status, body) = admin(app_state, Method::GET, "/admin/broker/topics", "admin").await;
+        assert_eq!(status, StatusCode::OK);
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn admins_manage_topics() {
+        let app_state = memory_state(test_env()).await;
+        let uri = "/admin/broker/topics/orders";
+        let (status, _) = admin(&app_state, Method::POST, uri, "admin").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            topics(&app_state).await,
+            vec![TopicInfo {
+                name: "orders".to_string(),
+                length: 0
+            }]
+        );
+
+        let groups = "/admin/broker/topics/orders/groups";
+        let (status, body) = admin(&app_state, Method::GET, groups, "admin").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"[]");
+
+        let (status, _) = admin(&app_state, Method::DELETE, uri, "admin").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(topics(&app_state).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_other_users() {
+        let app_state = memory_state(test_env()).await;
+        let uri = "/admin/broker/topics/orders";
+        let (status, body) = admin(&app_state, Method::POST, uri, "valid").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["code"], "forbidden");
+        assert_eq!(problem["instance"], uri);
+        assert!(topics(&app_state).await.is_empty());
+    }
+}
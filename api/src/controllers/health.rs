@@ -0,0 +1,14 @@
+use axum::http::StatusCode;
+use tracing::instrument;
+
+#[utoipa::path(
+    get,
+    path = "/healthcheck",
+    responses(
+            (status = 200, description = "The server is up", body = String)
+        )
+)]
+#[instrument]
+pub async fn healthcheck() -> (StatusCode, &'static str) {
+    (StatusCode::OK, "OK")
+}
@@ -1,4 +1,7 @@
 pub mod auth;
+pub mod broker;
+pub mod health;
+pub mod scheduler;
 pub mod user;
 
 use axum::{
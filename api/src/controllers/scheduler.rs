@@ -0,0 +1,70 @@
+use crate::{
+    error::ApiError,
+    extractors::{
+        auth_user::AdminUser,
+        validated::{Path, Query},
+    },
+};
+use axum::{debug_handler, extract::State, Json};
+use broker::scheduler::JobRun;
+use model::State as ModelState;
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::instrument;
+use util::AppState;
+use utoipa::IntoParams;
+
+/// Runs returned when `limit` is not given
+const DEFAULT_RUNS: i64 = 20;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct JobRunsQuery {
+    /// Most runs to return, newest first
+    pub limit: Option<i64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/jobs",
+    responses(
+            (status = 200, description = "Latest run of every scheduled job", body = Vec<JobRun>),
+            ApiError
+        ),
+    security(("bearer" = []))
+)]
+#[instrument(skip(api_state, _admin))]
+#[debug_handler]
+pub async fn get_jobs(
+    State(api_state): State<Arc<ModelState>>,
+    _admin: AdminUser,
+) -> Result<Json<Vec<JobRun>>, ApiError> {
+    Ok(Json(JobRun::latest(api_state.get_ro_store()).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/jobs/{job}/runs",
+    params(
+        ("job" = String, Path, description = "name of the job"),
+        JobRunsQuery
+    ),
+    responses(
+            (status = 200, description = "Runs of a scheduled job, newest first", body = Vec<JobRun>),
+            ApiError
+        ),
+    security(("bearer" = []))
+)]
+#[instrument(skip(api_state, _admin))]
+#[debug_handler]
+pub async fn get_job_runs(
+    State(api_state): State<Arc<ModelState>>,
+    Path(job): Path<String>,
+    Query(query): Query<JobRunsQuery>,
+    _admin: AdminUser,
+) -> Result<Json<Vec<JobRun>>, ApiError> {
+    let limit = query.limit.unwrap_or(DEFAULT_RUNS).clamp(1, 1000);
+    Ok(Json(
+        JobRun::history(api_state.get_ro_store(), &job, limit).await?,
+    ))
+}
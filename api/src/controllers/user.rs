@@ -1,18 +1,21 @@
 use crate::{
     controllers::JsonOrHtml,
     error::ApiError,
-    extractors::content_type::{ContentType, ContentTypes},
+    extractors::{
+        content_type::{ContentType, ContentTypes},
+        validated::{Path, Query},
+    },
     respond_with,
 };
 use axum::{
     debug_handler,
-    extract::{Json, Path, Query, State},
+    extract::{Json, State},
     response::IntoResponse,
 };
 use model::{
-    user_permission::{Query as UserPermissionQuery, UserPermission},
-    user_readmodel::{Query as ReadModelQuery, UserReadModel},
-    State as ModelState,
+    user_permission::{Query as UserPermissionQuery, UserPermission, UserPermissionSort},
+    user_readmodel::{Query as ReadModelQuery, UserReadModel, UserReadModelSort},
+    Paging, State as ModelState,
 };
 use std::sync::Arc;
 use tracing::instrument;
@@ -25,8 +28,10 @@ use uuid::Uuid;
 #[utoipa::path(
     get,
     path = "/users",
+    params(ReadModelQuery, UserReadModelSort, Paging),
     responses(
-            (status = 200, description = "Get all users", body = PaginatedResult<UserReadModel>)
+            (status = 200, description = "Get all users, as html when requested", body = PaginatedResult<UserReadModel>),
+            ApiError
         )
 )]
 #[instrument(skip(api_state))]
@@ -49,9 +54,13 @@ pub async fn get_users(
 
 #[utoipa::path(
     get,
-    path = "/users/:id",
+    path = "/users/{id}",
+    params(
+        ("id" = Uuid, Path, description = "id of the user")
+    ),
     responses(
-            (status = 200, description = "Get user by id", body = UserReadModel)
+            (status = 200, description = "Get user by id, as html when requested", body = UserReadModel),
+            ApiError
         )
 )]
 #[instrument(skip(api_state))]
@@ -79,8 +88,10 @@ pub async fn get_user(
 #[utoipa::path(
     get,
     path = "/users_permissions",
+    params(UserPermissionQuery, UserPermissionSort, Paging),
     responses(
-            (status = 200, description = "Get User permissions", body = String)
+            (status = 200, description = "Get User permissions", body = PaginatedResult<UserPermission>),
+            ApiError
         )
 )]
 #[instrument(skip(api_state))]
@@ -2,7 +2,7 @@ use derive_axum_errors::ErrorResponse;
 use http::StatusCode;
 use model::error::ModelError;
 use thiserror::Error as ThisError;
-use util::error::UtilError;
+use util::error::{FieldError, UtilError};
 
 #[derive(ThisError, ErrorResponse)]
 pub enum ApiError {
@@ -11,11 +11,41 @@ pub enum ApiError {
     #[error("{0}")]
     #[status(StatusCode::UNAUTHORIZED)]
     Auth(String),
+    #[error("Admin access is required")]
+    #[status(StatusCode::FORBIDDEN)]
+    Forbidden,
+    #[error("Message broker is not configured")]
+    #[status(StatusCode::SERVICE_UNAVAILABLE)]
+    BrokerNotConfigured,
+    #[error("Too many requests, retry in {0} seconds")]
+    #[status(StatusCode::TOO_MANY_REQUESTS)]
+    RateLimited(u64),
+    #[error("`Idempotency-Key` header must be between 1 and 255 visible characters")]
+    #[status(StatusCode::BAD_REQUEST)]
+    InvalidIdempotencyKey,
+    #[error("A request with this `Idempotency-Key` is still being processed")]
+    #[status(StatusCode::CONFLICT)]
+    IdempotencyKeyInUse,
+    #[error("`Idempotency-Key` was already used for a different request")]
+    #[status(StatusCode::UNPROCESSABLE_ENTITY)]
+    IdempotencyKeyMismatch,
+    #[error("Request body is larger than {0} bytes")]
+    #[status(StatusCode::PAYLOAD_TOO_LARGE)]
+    PayloadTooLarge(usize),
+    #[error("Request is invalid")]
+    #[status(StatusCode::BAD_REQUEST)]
+    #[error_code("invalid_request")]
+    InvalidRequest(#[field_errors] Vec<FieldError>),
+    #[error("Invalid rate limit {0}, expected path=limit/seconds:ip|user|api_key")]
+    InvalidRateLimit(String),
+    #[error("Invalid trusted proxy {0}, expected an address or a range like 10.0.0.0/8")]
+    InvalidTrustedProxy(String),
     #[error(transparent)]
     StandardError(#[from] Box<dyn std::error::Error>),
     #[error(transparent)]
     IoError(#[from] std::io::Error),
     #[error(transparent)]
+    #[status(transparent)]
     Model(#[from] ModelError),
     #[error(transparent)]
     JoinHandle(#[from] tokio::task::JoinError),
@@ -29,6 +59,7 @@ pub enum ApiError {
     #[error("`Authorization` header must be a bearer token")]
     HeaderDecodeBearer,
     #[error(transparent)]
+    #[status(transparent)]
     Util(#[from] UtilError),
     #[error(transparent)]
     #[status(StatusCode::UNAUTHORIZED)]
@@ -20,6 +20,31 @@ pub struct AuthUserHeaderCustom(pub String);
 #[derive(Deserialize, Serialize, Default)]
 pub struct AuthUser(pub CasdoorUser);
 
+/// An `AuthUser` that is an admin in the SSO service
+#[derive(Deserialize, Serialize, Default)]
+pub struct AdminUser(pub CasdoorUser);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AdminUser
+where
+    Arc<ModelState>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    #[instrument(skip_all)]
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<AdminUser, Self::Rejection> {
+        let AuthUser(user) = AuthUser::from_request_parts(parts, state).await?;
+        if user.is_admin {
+            return Ok(AdminUser(user));
+        }
+        Err(ApiError::Forbidden)
+    }
+}
+
 /// When running tests mock the auth call to make test results more predictable
 #[cfg(any(test, debug_assertions))]
 #[async_trait]
@@ -37,6 +62,11 @@ where
         if let Some(_auth) = app_state.get_env().auth.clone() {
             if token.0 == "valid" {
                 return Ok(AuthUser(CasdoorUser::default()));
+            } else if token.0 == "admin" {
+                return Ok(AuthUser(CasdoorUser {
+                    is_admin: true,
+                    ..CasdoorUser::default()
+                }));
             } else {
                 return Err(ApiError::Auth(
                     "Bearer token invalid or expired".to_string(),
@@ -1,2 +1,3 @@
 pub mod auth_user;
 pub mod content_type;
+pub mod validated;
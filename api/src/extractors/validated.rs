@@ -0,0 +1,162 @@
+use crate::error::ApiError;
+use async_trait::async_trait;
+use axum::{
+    extract::{
+        path::ErrorKind,
+        rejection::{JsonRejection, PathRejection},
+        FromRequest, Request,
+    },
+    response::{IntoResponse, Response},
+};
+use axum_core::extract::FromRequestParts;
+use http::request::Parts;
+use serde::{de::DeserializeOwned, Serialize};
+use std::error::Error;
+use util::error::FieldError;
+
+fn invalid(field: impl ToString, message: impl ToString) -> ApiError {
+    ApiError::InvalidRequest(vec![FieldError {
+        field: field.to_string(),
+        message: message.to_string(),
+    }])
+}
+
+/// `axum::extract::Query` rejecting invalid queries with the field that is invalid
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query = parts.uri.query().unwrap_or_default();
+        let deserializer =
+            serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+        serde_path_to_error::deserialize(deserializer)
+            .map(Query)
+            .map_err(|e| invalid(e.path(), e.inner()))
+    }
+}
+
+/// `axum::extract::Path` rejecting invalid params with the name of the param
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match axum::extract::Path::<T>::from_request_parts(parts, state).await {
+            Ok(axum::extract::Path(value)) => Ok(Path(value)),
+            Err(PathRejection::FailedToDeserializePathParams(e)) => match e.kind() {
+                ErrorKind::ParseErrorAtKey { key, .. }
+                | ErrorKind::InvalidUtf8InPathParam { key } => Err(invalid(key, e.kind())),
+                kind => Err(invalid("path", kind)),
+            },
+            Err(rejection) => Err(invalid("path", rejection.body_text())),
+        }
+    }
+}
+
+/// `axum::Json` rejecting invalid bodies with the path of the field that is invalid
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match axum::Json::<T>::from_request(request, state).await {
+            Ok(axum::Json(value)) => Ok(Json(value)),
+            Err(JsonRejection::JsonDataError(e)) => {
+                let mut source = e.source();
+                while let Some(error) = source {
+                    if let Some(error) =
+                        error.downcast_ref::<serde_path_to_error::Error<serde_json::Error>>()
+                    {
+                        return Err(invalid(error.path(), error.inner()));
+                    }
+                    source = error.source();
+                }
+                Err(invalid("body", e.body_text()))
+            }
+            Err(rejection) => Err(invalid("body", rejection.body_text())),
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::Body;
+    use model::{user_readmodel::Query as ReadModelQuery, Paging};
+    use util::JsonNum;
+    use uuid::Uuid;
+
+    fn field_errors(error: ApiError) -> Vec<FieldError> {
+        match error {
+            ApiError::InvalidRequest(errors) => errors,
+            other => panic!("expected field errors, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn names_invalid_query_fields() {
+        let request = Request::get("/users?email=ann%40example.com&page=2").body(());
+        let (mut parts, _) = request.unwrap().into_parts();
+        let Query(query) = Query::<ReadModelQuery>::from_request_parts(&mut parts, &())
+            .await
+            .unwrap();
+        assert_eq!(query.email.as_deref(), Some("ann@example.com"));
+        assert_eq!(
+            query.paging,
+            Some(Paging {
+                page: Some(JsonNum::S("2".to_string())),
+                ..Paging::default()
+            })
+        );
+
+        let (mut parts, _) = Request::get("/users?id=1").body(()).unwrap().into_parts();
+        let error = Query::<ReadModelQuery>::from_request_parts(&mut parts, &())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(field_errors(error)[0].field, "id");
+    }
+
+    #[tokio::test]
+    async fn names_invalid_body_fields() {
+        #[derive(serde::Deserialize)]
+        #[allow(dead_code)]
+        struct Ids {
+            ids: Vec<Uuid>,
+        }
+        let request = Request::post("/")
+            .header("content-type", "application/json")
+            .body(Body::from(format!(
+                r#"{{"ids": ["{}", "1"]}}"#,
+                Uuid::nil()
+            )))
+            .unwrap();
+        let error = Json::<Ids>::from_request(request, &()).await.err().unwrap();
+        assert_eq!(field_errors(error)[0].field, "ids[1]");
+    }
+}
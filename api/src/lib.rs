@@ -3,12 +3,21 @@ mod error;
 mod extractors;
 mod macros;
 mod middleware;
+mod openapi;
 
-use crate::controllers::{auth, user};
+use crate::controllers::{auth, broker, health, scheduler, user};
 use crate::error::ApiError;
+use crate::middleware::{
+    cache::cache_request,
+    client_ip::TrustedProxies,
+    idempotency::{idempotent_request, Idempotency},
+    problem::problem_instance,
+    rate_limit::{rate_limit_request, RateLimitRule, RateLimiter},
+};
+use crate::openapi::{ApiRouter, SecurityAddon};
 use axum::{
-    http::StatusCode,
-    routing::{get, post},
+    middleware::{from_fn, from_fn_with_state},
+    routing::get,
     Router,
 };
 use log::info;
@@ -17,39 +26,18 @@ use std::{
     net::{SocketAddr, TcpListener},
     sync::Arc,
 };
-use tracing::instrument;
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
-#[instrument]
-async fn healthcheck() -> (StatusCode, &'static str) {
-    (StatusCode::OK, "OK")
-}
-
-pub(crate) fn routes(app_state: Arc<ModelState>) -> Router {
-    Router::new()
-        .merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", ApiDoc::openapi()))
-        .route("/", get(frontend::index::index))
-        .route("/users", get(user::get_users))
-        .route("/users/:id", get(user::get_user))
-        .route("/healthcheck", get(healthcheck))
-        .route("/auth_login", post(auth::auth_login))
-        .route("/auth_signup", post(auth::auth_signup))
-        .route("/auth_callback", get(auth::auth_callback))
-        .route("/auth_users/:name", get(auth::get_auth_user))
-        .route(
-            "/auth_users",
-            get(auth::get_auth_users)
-                .delete(auth::delete_auth_user)
-                .post(auth::add_auth_user),
-        )
-        //.layer(from_fn_with_state(app_state.clone(),cache_request))
-        .with_state(app_state)
-}
-
-#[derive(OpenApi)]
-#[openapi(
-    paths(
+/// Every route of the api and the spec documenting them
+pub(crate) fn api_router() -> ApiRouter {
+    let router = ApiRouter::new(ApiDoc::openapi()).route("/", get(frontend::index::index));
+    document!(
+        router,
+        health::healthcheck,
+        user::get_users,
+        user::get_user,
+        user::get_user_permissions,
         auth::auth_login,
         auth::auth_signup,
         auth::auth_callback,
@@ -57,14 +45,54 @@ pub(crate) fn routes(app_state: Arc<ModelState>) -> Router {
         auth::get_auth_users,
         auth::delete_auth_user,
         auth::add_auth_user,
-    ),
-    components(schemas())
+        broker::get_topics,
+        broker::add_topic,
+        broker::remove_topic,
+        broker::get_groups,
+        broker::get_consumers,
+        scheduler::get_jobs,
+        scheduler::get_job_runs,
+    )
+}
+
+pub(crate) fn routes(app_state: Arc<ModelState>) -> Result<Router, ApiError> {
+    let trusted_proxies = Arc::new(TrustedProxies::from_env(
+        app_state.env.trusted_proxies.as_deref(),
+    )?);
+    let limiter = RateLimiter {
+        rules: Arc::new(RateLimitRule::from_env(
+            app_state.env.rate_limits.as_deref(),
+        )?),
+        api_state: app_state.clone(),
+        trusted_proxies: trusted_proxies.clone(),
+    };
+    let idempotency = Idempotency {
+        api_state: app_state.clone(),
+        trusted_proxies,
+    };
+    let ApiRouter {
+        router, openapi, ..
+    } = api_router();
+    Ok(router
+        .merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", openapi))
+        .layer(from_fn_with_state(app_state.clone(), cache_request))
+        .layer(from_fn_with_state(idempotency, idempotent_request))
+        .layer(from_fn_with_state(limiter, rate_limit_request))
+        .layer(from_fn(problem_instance))
+        .with_state(app_state))
+}
+
+/// Paths are added by `api_router` as handlers are routed
+#[derive(OpenApi)]
+#[openapi(
+    modifiers(&SecurityAddon)
 )]
 pub struct ApiDoc;
 
 pub async fn start_server(app_state: ModelState) -> Result<(), ApiError> {
     let release = env!("CARGO_PKG_VERSION");
-    let app = routes(Arc::new(app_state.clone())).into_make_service();
+    let app =
+        routes(Arc::new(app_state.clone()))?.into_make_service_with_connect_info::<SocketAddr>();
     let port = app_state.env.server_port.unwrap_or(8080);
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     let socket_http = TcpListener::bind(addr)?;
@@ -72,3 +100,53 @@ pub async fn start_server(app_state: ModelState) -> Result<(), ApiError> {
     info!("starting server version {release} on port {port}");
     server_http.await.map_err(ApiError::from)
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use ::broker::{Broker, MemoryBroker, RetryPolicy};
+    use axum::{body::Body, extract::Request, response::Response};
+    use tower::ServiceExt;
+    use util::{
+        cache::{AppCache, MemoryCache},
+        env::{Auth, Env},
+        store::{CacheLayer, RODB, RWDB},
+        tests::get_test_env,
+    };
+
+    /// Env of an api with auth configured, `Bearer admin` is an admin in tests
+    pub(crate) fn test_env() -> Env {
+        Env {
+            auth: Some(Auth {
+                endpoint: "http://localhost".to_string(),
+                client_id: String::new(),
+                client_secret: String::new(),
+                certificate: String::new(),
+                org_name: String::new(),
+                app_name: None,
+                redirect_url: String::new(),
+            }),
+            ..get_test_env()
+        }
+    }
+
+    /// State of a single node running without Redis, the database is only reached once a
+    /// query is made
+    pub(crate) async fn memory_state(env: Env) -> Arc<ModelState> {
+        Arc::new(ModelState {
+            rw_db: RWDB::connect_lazy(&env).unwrap(),
+            ro_db: RODB::connect_lazy(&env).unwrap(),
+            cache: AppCache::Memory(MemoryCache::new(&env).await.unwrap()),
+            broker: Some(Broker::Memory(MemoryBroker::with_retry_policy(
+                RetryPolicy::default(),
+            ))),
+            env,
+            template_env: None,
+        })
+    }
+
+    /// Response of every route with its middleware to `request`
+    pub(crate) async fn send(app_state: Arc<ModelState>, request: Request<Body>) -> Response {
+        routes(app_state).unwrap().oneshot(request).await.unwrap()
+    }
+}
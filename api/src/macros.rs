@@ -7,3 +7,16 @@ macro_rules! respond_with {
         }
     };
 }
+
+/// Route handlers with `ApiRouter::document`, naming the struct `#[utoipa::path]` generates
+/// for each
+#[macro_export]
+macro_rules! document {
+    ($router: expr, $($module: ident :: $handler: ident),+ $(,)?) => {
+        paste::paste! {{
+            let router = $router;
+            $(let router = router.document::<$module::[<__path_ $handler>], _, _>($module::$handler);)+
+            router
+        }}
+    };
+}
@@ -0,0 +1,269 @@
+use crate::middleware::{fits, MAX_BODY_LEN};
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{
+    header::{
+        ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        LAST_MODIFIED,
+    },
+    HeaderMap, HeaderValue, Method, StatusCode, Uri,
+};
+use model::State as ModelState;
+use serde::{Deserialize, Serialize};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+use std::time::SystemTime;
+use util::{
+    cache::AppCache,
+    error::UtilError,
+    store::{CacheLayer, QueryCache},
+};
+
+/// A route whose GET responses are cached, `path` matches itself and every path below it.
+/// Responses are invalidated by any write to `tables` through a model
+struct CachedRoute {
+    path: &'static str,
+    tables: &'static [&'static str],
+    max_age: u64,
+}
+
+const CACHED_ROUTES: &[CachedRoute] = &[CachedRoute {
+    path: "/users",
+    tables: &["user_readmodels"],
+    max_age: 60,
+}];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedResponse {
+    content_type: Option<String>,
+    body: String,
+    etag: String,
+    last_modified: String,
+}
+
+fn cached_route(path: &str) -> Option<&'static CachedRoute> {
+    CACHED_ROUTES.iter().find(|route| {
+        path.strip_prefix(route.path)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+fn cache_control(headers: &HeaderMap, directive: &str) -> bool {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|d| d.trim().eq_ignore_ascii_case(directive))
+}
+
+/// Responses are cached per path, query, content type and credentials, and under the
+/// current versions of the route's tables so a write moves every request to a new key
+async fn cache_key(
+    cache: &AppCache,
+    route: &CachedRoute,
+    uri: &Uri,
+    headers: &HeaderMap,
+) -> Result<String, UtilError> {
+    let mut hasher = DefaultHasher::new();
+    for table in route.tables {
+        QueryCache::version(cache, table).await?.hash(&mut hasher);
+    }
+    uri.path().hash(&mut hasher);
+    uri.query().hash(&mut hasher);
+    for header in [ACCEPT, CONTENT_TYPE, AUTHORIZATION] {
+        headers.get(header).map(|v| v.as_bytes()).hash(&mut hasher);
+    }
+    Ok(format!(
+        "response_cache:{}:{:x}",
+        route.path,
+        hasher.finish()
+    ))
+}
+
+fn etag(body: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!("\"{:x}\"", hasher.finish())
+}
+
+/// Whether the client's copy is current, `If-None-Match` takes precedence over
+/// `If-Modified-Since`
+fn not_modified(request_headers: &HeaderMap, cached: &CachedResponse) -> bool {
+    if let Some(if_none_match) = request_headers.get(IF_NONE_MATCH) {
+        return if_none_match.to_str().is_ok_and(|tags| {
+            tags.split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == cached.etag)
+        });
+    }
+    let since = request_headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    match (since, httpdate::parse_http_date(&cached.last_modified)) {
+        (Some(since), Ok(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+fn add_validators(headers: &mut HeaderMap, cached: &CachedResponse, max_age: u64) {
+    for (name, value) in [
+        (ETAG, cached.etag.clone()),
+        (LAST_MODIFIED, cached.last_modified.clone()),
+        (CACHE_CONTROL, format!("private, max-age={max_age}")),
+    ] {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+fn respond(request_headers: &HeaderMap, cached: CachedResponse, max_age: u64) -> Response {
+    let mut response = if not_modified(request_headers, &cached) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let mut response = Response::new(Body::from(cached.body.clone()));
+        if let Some(content_type) = cached
+            .content_type
+            .as_ref()
+            .and_then(|c| HeaderValue::from_str(c).ok())
+        {
+            response.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+        response
+    };
+    add_validators(response.headers_mut(), &cached, max_age);
+    response
+}
+
+/// Serves GET requests to the routes in `CACHED_ROUTES` from Redis, storing successful
+/// responses the first time they are requested. Adds `ETag` and `Last-Modified` and answers
+/// conditional requests for current copies with 304. Requests with `Cache-Control: no-store`
+/// bypass the cache and `no-cache` refreshes it, responses marked `no-store` or `private`
+/// are not stored. A cache that cannot be reached is logged and the request handled as usual
+pub async fn cache_request(
+    State(api_state): State<Arc<ModelState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = match cached_route(request.uri().path()) {
+        Some(route) if request.method() == Method::GET => route,
+        _ => return next.run(request).await,
+    };
+    if cache_control(request.headers(), "no-store") {
+        return next.run(request).await;
+    }
+
+    let cache = &api_state.cache;
+    let request_headers = request.headers().clone();
+    let key = match cache_key(cache, route, request.uri(), &request_headers).await {
+        Ok(key) => key,
+        Err(e) => {
+            log::warn!("Response cache key for {} failed {:?}", route.path, e);
+            return next.run(request).await;
+        }
+    };
+    if !cache_control(&request_headers, "no-cache") {
+        match cache.get::<CachedResponse>(&key).await {
+            Ok(Some(cached)) => return respond(&request_headers, cached, route.max_age),
+            Ok(None) => {}
+            Err(e) => log::warn!("Response cache read of {} failed {:?}", key, e),
+        }
+    }
+
+    let response = next.run(request).await;
+    if response.status() != StatusCode::OK
+        || cache_control(response.headers(), "no-store")
+        || cache_control(response.headers(), "private")
+        || !fits(response.body())
+    {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = match to_bytes(body, MAX_BODY_LEN).await {
+        Ok(body) => body,
+        Err(e) => {
+            log::error!("Failed to read response body of {} {:?}", route.path, e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    // only text bodies are cached, anything else is passed through untouched
+    let Ok(text) = String::from_utf8(body.to_vec()) else {
+        return Response::from_parts(parts, Body::from(body));
+    };
+
+    let cached = CachedResponse {
+        content_type: parts
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|c| c.to_str().ok())
+            .map(str::to_owned),
+        etag: etag(&text),
+        last_modified: httpdate::fmt_http_date(SystemTime::now()),
+        body: text,
+    };
+    if let Err(e) = cache.set(&key, &cached, Some(route.max_age)).await {
+        log::warn!("Response cache write of {} failed {:?}", key, e);
+    }
+    if not_modified(&request_headers, &cached) {
+        return respond(&request_headers, cached, route.max_age);
+    }
+    add_validators(&mut parts.headers, &cached, route.max_age);
+    Response::from_parts(parts, Body::from(cached.body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cached() -> CachedResponse {
+        CachedResponse {
+            content_type: None,
+            body: "[]".to_string(),
+            etag: etag("[]"),
+            last_modified: "Mon, 19 Oct 2026 10:00:00 GMT".to_string(),
+        }
+    }
+
+    #[test]
+    fn matches_routes_below_path() {
+        assert!(cached_route("/users").is_some());
+        assert!(cached_route("/users/1").is_some());
+        assert!(cached_route("/users_admin").is_none());
+        assert!(cached_route("/auth_users").is_none());
+    }
+
+    #[test]
+    fn answers_conditional_requests() {
+        let mut headers = HeaderMap::new();
+        assert!(!not_modified(&headers, &cached()));
+
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_str(&etag("[]")).unwrap());
+        assert!(not_modified(&headers, &cached()));
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        assert!(!not_modified(&headers, &cached()));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_static("Mon, 19 Oct 2026 11:00:00 GMT"),
+        );
+        assert!(not_modified(&headers, &cached()));
+    }
+
+    #[test]
+    fn reads_cache_control_directives() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("max-age=0, No-Store"),
+        );
+        assert!(cache_control(&headers, "no-store"));
+        assert!(!cache_control(&headers, "no-cache"));
+    }
+}
@@ -0,0 +1,110 @@
+use crate::error::ApiError;
+use http::{HeaderMap, HeaderName};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+const FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// Proxies in front of the api whose `X-Forwarded-For` is believed, none by default
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl FromStr for TrustedProxies {
+    type Err = ApiError;
+
+    /// Parses comma separated addresses or ranges, e.g. `10.0.0.0/8,192.168.1.10`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| {
+                proxy
+                    .parse::<IpNet>()
+                    .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| ApiError::InvalidTrustedProxy(proxy.to_owned()))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl TrustedProxies {
+    /// The proxies in `trusted_proxies`, none if it is not set
+    pub fn from_env(trusted_proxies: Option<&str>) -> Result<Self, ApiError> {
+        trusted_proxies.map_or_else(|| Ok(Self::default()), str::parse)
+    }
+
+    fn trusts(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|proxy| proxy.contains(ip))
+    }
+
+    /// The peer address, or when the peer is a trusted proxy the nearest `X-Forwarded-For`
+    /// entry that is not. Entries left of an untrusted one could be set by the client
+    pub fn client_ip(&self, headers: &HeaderMap, peer: Option<SocketAddr>) -> String {
+        let Some(mut ip) = peer.map(|peer| peer.ip()) else {
+            return "unknown".to_owned();
+        };
+        let forwarded = headers
+            .get_all(FORWARDED_FOR)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect::<Vec<_>>();
+        for entry in forwarded.into_iter().rev() {
+            if !self.trusts(&ip) {
+                break;
+            }
+            match entry.trim().parse() {
+                Ok(forwarded_ip) => ip = forwarded_ip,
+                Err(_) => break,
+            }
+        }
+        ip.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn parses_proxies() {
+        let proxies: TrustedProxies = "10.0.0.0/8, 192.168.1.10,::1".parse().unwrap();
+        assert!(proxies.trusts(&"10.1.2.3".parse().unwrap()));
+        assert!(proxies.trusts(&"192.168.1.10".parse().unwrap()));
+        assert!(proxies.trusts(&"::1".parse().unwrap()));
+        assert!(!proxies.trusts(&"192.168.1.11".parse().unwrap()));
+
+        assert_eq!(
+            TrustedProxies::from_env(None).unwrap(),
+            TrustedProxies::default()
+        );
+        assert!(TrustedProxies::from_env(Some("10.0.0.0/33")).is_err());
+        assert!(TrustedProxies::from_env(Some("proxy.local")).is_err());
+    }
+
+    #[test]
+    fn trusts_configured_proxies_only() {
+        let proxy = Some(SocketAddr::from(([10, 0, 0, 1], 4000)));
+        let client = Some(SocketAddr::from(([198, 51, 100, 2], 4000)));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            FORWARDED_FOR,
+            HeaderValue::from_static("1.1.1.1, 203.0.113.7, 10.0.0.2"),
+        );
+
+        let none = TrustedProxies::default();
+        assert_eq!(none.client_ip(&headers, proxy), "10.0.0.1");
+
+        let proxies: TrustedProxies = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(proxies.client_ip(&headers, proxy), "203.0.113.7");
+        assert_eq!(proxies.client_ip(&headers, client), "198.51.100.2");
+        assert_eq!(proxies.client_ip(&HeaderMap::new(), proxy), "10.0.0.1");
+        assert_eq!(proxies.client_ip(&headers, None), "unknown");
+
+        headers.insert(FORWARDED_FOR, HeaderValue::from_static("not an ip"));
+        assert_eq!(proxies.client_ip(&headers, proxy), "10.0.0.1");
+    }
+}
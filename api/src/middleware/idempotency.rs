@@ -0,0 +1,337 @@
+use crate::{
+    error::ApiError,
+    extractors::auth_user::AuthUser,
+    middleware::{client_ip::TrustedProxies, fits, too_large, MAX_BODY_LEN},
+};
+use axum::{
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_core::extract::FromRequestParts;
+use http::{HeaderName, HeaderValue, Method, StatusCode, Uri};
+use model::State as ModelState;
+use serde::{Deserialize, Serialize};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use util::{error::UtilError, store::CacheLayer};
+
+const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+/// Longest key accepted, keys are meant to be uuids
+const MAX_KEY_LEN: usize = 255;
+/// Seconds a request holds its key, a process that dies mid request frees it after this
+const IN_PROGRESS_TTL: u64 = 60;
+/// Seconds a response is replayed for
+const COMPLETED_TTL: u64 = 24 * 60 * 60;
+
+/// A stored response, header values that are not valid utf8 are not kept
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct StoredResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// What a key was used for, `fingerprint` identifies the request so reuse with a different
+/// request can be refused
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum IdempotencyRecord {
+    InProgress {
+        fingerprint: String,
+    },
+    Completed {
+        fingerprint: String,
+        response: StoredResponse,
+    },
+}
+
+#[derive(Clone)]
+pub struct Idempotency {
+    pub api_state: Arc<ModelState>,
+    /// Identify requesters without credentials by their address
+    pub trusted_proxies: Arc<TrustedProxies>,
+}
+
+fn fingerprint(method: &Method, uri: &Uri, body: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    method.as_str().hash(&mut hasher);
+    uri.path().hash(&mut hasher);
+    uri.query().hash(&mut hasher);
+    body.hash(&mut hasher);
+    format!("{:x}", hasher.finish())
+}
+
+fn replay(stored: StoredResponse) -> Response {
+    let mut response = Response::new(Body::from(stored.body));
+    *response.status_mut() = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+    for (name, value) in stored.headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            response.headers_mut().append(name, value);
+        }
+    }
+    response
+        .headers_mut()
+        .insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+    response
+}
+
+/// The response to a request whose key is already taken
+fn existing(record: Option<IdempotencyRecord>, request_fingerprint: &str) -> Response {
+    match record {
+        Some(IdempotencyRecord::Completed {
+            fingerprint,
+            response,
+        }) if fingerprint == request_fingerprint => replay(response),
+        Some(IdempotencyRecord::InProgress { fingerprint })
+            if fingerprint == request_fingerprint =>
+        {
+            ApiError::IdempotencyKeyInUse.into_response()
+        }
+        Some(_) => ApiError::IdempotencyKeyMismatch.into_response(),
+        // finished with a server error or expired since the key was found taken
+        None => ApiError::IdempotencyKeyInUse.into_response(),
+    }
+}
+
+async fn get_record(
+    api_state: &ModelState,
+    key: &str,
+) -> Result<Option<IdempotencyRecord>, UtilError> {
+    api_state
+        .cache
+        .get_value(key)
+        .await?
+        .map(|record| serde_json::from_str(&record).map_err(UtilError::from))
+        .transpose()
+}
+
+/// Runs a mutating request with an `Idempotency-Key` header once per key and requester,
+/// storing its response for 24 hours and replaying it, marked `Idempotent-Replayed`, for
+/// repeats of the same request. Reusing a key while its request is still running is a 409
+/// and reusing it for a different request a 422. Server errors are not stored so the
+/// request can be retried, neither are responses too large to buffer. Request bodies over
+/// `MAX_BODY_LEN` are refused with 413. A cache that cannot be reached is logged and the
+/// request handled as usual
+pub async fn idempotent_request(
+    State(idempotency): State<Idempotency>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let api_state = idempotency.api_state;
+    if matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        return next.run(request).await;
+    }
+    let Some(idempotency_key) = request.headers().get(IDEMPOTENCY_KEY) else {
+        return next.run(request).await;
+    };
+    let idempotency_key = match idempotency_key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => key.to_owned(),
+        _ => return ApiError::InvalidIdempotencyKey.into_response(),
+    };
+
+    let (mut parts, body) = request.into_parts();
+    let body = match to_bytes(body, MAX_BODY_LEN).await {
+        Ok(body) => body,
+        Err(e) if too_large(&e) => {
+            return ApiError::PayloadTooLarge(MAX_BODY_LEN).into_response();
+        }
+        Err(e) => {
+            log::error!("Failed to read request body of {} {:?}", parts.uri, e);
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+    };
+    let requester = match AuthUser::from_request_parts(&mut parts, &api_state).await {
+        Ok(AuthUser(user)) => format!("user:{}/{}", user.owner, user.name),
+        Err(_) => {
+            let peer = parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(peer)| *peer);
+            let ip = idempotency.trusted_proxies.client_ip(&parts.headers, peer);
+            format!("ip:{}", ip)
+        }
+    };
+    let request_fingerprint = fingerprint(&parts.method, &parts.uri, &body);
+    let key = format!("idempotency:{requester}:{idempotency_key}");
+    let request = Request::from_parts(parts, Body::from(body));
+
+    let in_progress = IdempotencyRecord::InProgress {
+        fingerprint: request_fingerprint.clone(),
+    };
+    let claimed = match serde_json::to_string(&in_progress) {
+        Ok(record) => {
+            api_state
+                .cache
+                .set_value_if_absent(&key, &record, Some(IN_PROGRESS_TTL))
+                .await
+        }
+        Err(e) => Err(UtilError::from(e)),
+    };
+    match claimed {
+        Ok(true) => {}
+        Ok(false) => {
+            return match get_record(&api_state, &key).await {
+                Ok(record) => existing(record, &request_fingerprint),
+                Err(e) => {
+                    log::error!("Idempotency record {} could not be read {:?}", key, e);
+                    ApiError::IdempotencyKeyInUse.into_response()
+                }
+            };
+        }
+        Err(e) => {
+            log::warn!("Idempotency key {} could not be claimed {:?}", key, e);
+            return next.run(request).await;
+        }
+    }
+
+    let response = next.run(request).await;
+    // streamed or large responses are not stored, the request can be made again
+    if response.status().is_server_error() || !fits(response.body()) {
+        if let Err(e) = api_state.cache.delete_value(&key).await {
+            log::warn!("Idempotency key {} could not be freed {:?}", key, e);
+        }
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, MAX_BODY_LEN).await {
+        Ok(body) => body,
+        Err(e) => {
+            log::error!("Failed to read response body for {} {:?}", key, e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let completed = IdempotencyRecord::Completed {
+        fingerprint: request_fingerprint,
+        response: StoredResponse {
+            status: parts.status.as_u16(),
+            headers: parts
+                .headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    value
+                        .to_str()
+                        .ok()
+                        .map(|value| (name.to_string(), value.to_owned()))
+                })
+                .collect(),
+            body: body.to_vec(),
+        },
+    };
+    let stored = match serde_json::to_string(&completed) {
+        Ok(record) => {
+            api_state
+                .cache
+                .set_value(&key, &record, Some(COMPLETED_TTL))
+                .await
+        }
+        Err(e) => Err(UtilError::from(e)),
+    };
+    if let Err(e) = stored {
+        log::warn!("Idempotent response {} could not be stored {:?}", key, e);
+    }
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{memory_state, send, test_env};
+    use http::header::AUTHORIZATION;
+
+    fn completed(fingerprint: &str) -> IdempotencyRecord {
+        IdempotencyRecord::Completed {
+            fingerprint: fingerprint.to_string(),
+            response: StoredResponse {
+                status: 201,
+                headers: vec![("content-type".to_string(), "application/json".to_string())],
+                body: b"{}".to_vec(),
+            },
+        }
+    }
+
+    #[test]
+    fn replays_matching_requests_only() {
+        let uri = Uri::from_static("/auth_users");
+        let request = fingerprint(&Method::POST, &uri, b"{\"name\":\"a\"}");
+        assert_ne!(
+            request,
+            fingerprint(&Method::POST, &uri, b"{\"name\":\"b\"}")
+        );
+        let query = Uri::from_static("/auth_users?notify=true");
+        assert_ne!(
+            request,
+            fingerprint(&Method::POST, &query, b"{\"name\":\"a\"}")
+        );
+
+        let replayed = existing(Some(completed(&request)), &request);
+        assert_eq!(replayed.status(), StatusCode::CREATED);
+        assert_eq!(replayed.headers()[IDEMPOTENT_REPLAYED], "true");
+        assert_eq!(replayed.headers()["content-type"], "application/json");
+
+        let mismatched = existing(Some(completed("other")), &request);
+        assert_eq!(mismatched.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let running = IdempotencyRecord::InProgress {
+            fingerprint: request.clone(),
+        };
+        assert_eq!(
+            existing(Some(running), &request).status(),
+            StatusCode::CONFLICT
+        );
+    }
+
+    #[test]
+    fn stores_records_as_json() {
+        let record = completed("a");
+        let json = serde_json::to_string(&record).unwrap();
+        assert!(json.contains("\"state\":\"completed\""));
+        assert_eq!(
+            serde_json::from_str::<IdempotencyRecord>(&json).unwrap(),
+            record
+        );
+    }
+
+    fn post(uri: &str, key: &str, body: Body) -> Request {
+        Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(AUTHORIZATION, "Bearer admin")
+            .header(IDEMPOTENCY_KEY, key)
+            .body(body)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn replays_through_middleware() {
+        let app_state = memory_state(test_env()).await;
+        let uri = "/admin/broker/topics/orders";
+        let first = send(app_state.clone(), post(uri, "a", Body::empty())).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert!(first.headers().get(IDEMPOTENT_REPLAYED).is_none());
+
+        let repeated = send(app_state.clone(), post(uri, "a", Body::empty())).await;
+        assert_eq!(repeated.status(), StatusCode::OK);
+        assert_eq!(repeated.headers()[IDEMPOTENT_REPLAYED], "true");
+
+        let query = format!("{uri}?retention=1");
+        let other = send(app_state.clone(), post(&query, "a", Body::empty())).await;
+        assert_eq!(other.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let large = Body::from(vec![b' '; MAX_BODY_LEN + 1]);
+        let refused = send(app_state, post(uri, "b", large)).await;
+        assert_eq!(refused.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = to_bytes(refused.into_body(), MAX_BODY_LEN).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["code"], "payload_too_large");
+    }
+}
@@ -1 +1,26 @@
-//pub mod cache;
+use axum::body::{Body, HttpBody};
+use http_body_util::LengthLimitError;
+use std::error::Error;
+
+pub mod cache;
+pub mod client_ip;
+pub mod idempotency;
+pub mod problem;
+pub mod rate_limit;
+
+/// Largest body the middleware buffer, axum's default limit for extractors
+pub(crate) const MAX_BODY_LEN: usize = 2 * 1024 * 1024;
+
+/// Whether reading a body failed because it was longer than the limit
+pub(crate) fn too_large(e: &axum::Error) -> bool {
+    e.source()
+        .is_some_and(|source| source.is::<LengthLimitError>())
+}
+
+/// Whether the body is known to fit in `MAX_BODY_LEN`, streamed bodies of unknown length
+/// are passed on without being buffered
+pub(crate) fn fits(body: &Body) -> bool {
+    body.size_hint()
+        .upper()
+        .is_some_and(|len| len <= MAX_BODY_LEN as u64)
+}
@@ -0,0 +1,138 @@
+use crate::middleware::{fits, MAX_BODY_LEN};
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    middleware::Next,
+    response::Response,
+};
+use http::{header::CONTENT_LENGTH, header::CONTENT_TYPE, HeaderValue};
+
+const PROBLEM_JSON: HeaderValue = HeaderValue::from_static("application/problem+json");
+
+/// `body` with `instance` set, `None` if it is not a problem document
+fn with_instance(body: &[u8], instance: &str) -> Option<Vec<u8>> {
+    let mut problem = serde_json::from_slice::<serde_json::Value>(body).ok()?;
+    let problem_fields = problem.as_object_mut()?;
+    problem_fields
+        .entry("instance")
+        .or_insert_with(|| instance.into());
+    serde_json::to_vec(&problem).ok()
+}
+
+/// Sets the `instance` of `application/problem+json` responses to the path of the request,
+/// errors are turned into responses without access to the request
+pub async fn problem_instance(request: Request, next: Next) -> Response {
+    let instance = request.uri().path().to_owned();
+    let response = next.run(request).await;
+    if response.headers().get(CONTENT_TYPE) != Some(&PROBLEM_JSON) || !fits(response.body()) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = match to_bytes(body, MAX_BODY_LEN).await {
+        Ok(body) => body,
+        Err(e) => {
+            log::warn!("Problem response body could not be read {:?}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    match with_instance(&body, &instance) {
+        Some(problem) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(problem))
+        }
+        None => Response::from_parts(parts, Body::from(body)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ApiError;
+    use axum::response::IntoResponse;
+    use http::StatusCode;
+    use model::error::ModelError;
+    use serde_json::{json, Value};
+    use util::error::{FieldError, UtilError};
+
+    async fn problem(error: ApiError) -> (StatusCode, Value) {
+        let response = error.into_response();
+        assert_eq!(response.headers()[CONTENT_TYPE], PROBLEM_JSON);
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn writes_problem_documents() {
+        let (status, body) = problem(ApiError::Forbidden).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(
+            body,
+            json!({
+                "type": "urn:problem-type:forbidden",
+                "title": "Forbidden",
+                "status": 403,
+                "code": "forbidden",
+                "detail": "Admin access is required",
+            })
+        );
+
+        let (_, body) = problem(ApiError::InvalidRequest(vec![FieldError {
+            field: "id".to_string(),
+            message: "UUID parsing failed".to_string(),
+        }]))
+        .await;
+        assert_eq!(body["code"], "invalid_request");
+        assert_eq!(
+            body["errors"],
+            json!([{"field": "id", "message": "UUID parsing failed"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn redacts_server_errors() {
+        let error = UtilError::SqlError("relation \"users\" does not exist".to_string());
+        let (status, body) = problem(ApiError::Util(error)).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["title"], "Internal Server Error");
+        assert!(body.get("detail").is_none());
+        assert!(!body.to_string().contains("users"));
+    }
+
+    #[tokio::test]
+    async fn delegates_to_wrapped_errors() {
+        let (status, body) = problem(ApiError::Util(UtilError::SqlFailedToFindRecord)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "not_found");
+
+        let duplicate = ModelError::Util(UtilError::SqlDuplicateRecord("email".to_string()));
+        let (status, body) = problem(ApiError::Model(duplicate)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "duplicate_record");
+        assert_eq!(body["detail"], "Duplicate record found email");
+
+        for error in [
+            UtilError::SqlRelationMissing("user_id".to_string()),
+            UtilError::SqlNotNullViolation("email".to_string()),
+            UtilError::SqlCheckFailed("age".to_string()),
+        ] {
+            let (status, _) = problem(ApiError::Util(error)).await;
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+
+        let error = ModelError::RowCantMaterialize;
+        assert_eq!(
+            ApiError::Model(error).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn adds_instance() {
+        let body = with_instance(br#"{"status":404}"#, "/users/1").unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({"status": 404, "instance": "/users/1"}));
+        assert!(with_instance(b"[]", "/users").is_none());
+    }
+}
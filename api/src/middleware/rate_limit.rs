@@ -0,0 +1,250 @@
+use crate::{
+    error::ApiError, extractors::auth_user::AuthUser, middleware::client_ip::TrustedProxies,
+};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_core::extract::FromRequestParts;
+use http::{header::RETRY_AFTER, HeaderMap, HeaderName, HeaderValue};
+use model::State as ModelState;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use util::store::RateLimit;
+
+/// Used when `rate_limits` is not set
+const DEFAULT_RATE_LIMITS: &str = "/auth_login=10/60:ip,/auth_signup=10/60:ip,/users=120/60:user";
+
+const RATE_LIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATE_LIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATE_LIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+const RATE_LIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+const API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+
+/// Who a limit is counted against, `user` and `api_key` fall back to the client's address
+/// for requests without valid credentials
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitKey {
+    Ip,
+    User,
+    ApiKey,
+}
+
+impl FromStr for RateLimitKey {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(Self::Ip),
+            "user" => Ok(Self::User),
+            "api_key" => Ok(Self::ApiKey),
+            _ => Err(ApiError::InvalidRateLimit(s.to_owned())),
+        }
+    }
+}
+
+/// At most `limit` requests in any `period` to `path` or a path below it
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitRule {
+    pub path: String,
+    pub limit: u64,
+    pub period: Duration,
+    pub key: RateLimitKey,
+}
+
+impl FromStr for RateLimitRule {
+    type Err = ApiError;
+
+    /// Parses `path=limit/seconds:key`, the key defaults to `ip`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ApiError::InvalidRateLimit(s.to_owned());
+        let (path, rate) = s.trim().split_once('=').ok_or_else(invalid)?;
+        let (rate, key) = rate.split_once(':').unwrap_or((rate, "ip"));
+        let (limit, seconds) = rate.split_once('/').ok_or_else(invalid)?;
+        let limit: u64 = limit.parse().map_err(|_| invalid())?;
+        let seconds: u64 = seconds.parse().map_err(|_| invalid())?;
+        if !path.starts_with('/') || limit == 0 || seconds == 0 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            limit,
+            period: Duration::from_secs(seconds),
+            key: key.parse()?,
+        })
+    }
+}
+
+impl RateLimitRule {
+    /// The rules in `rate_limits`, or the defaults if it is not set
+    pub fn from_env(rate_limits: Option<&str>) -> Result<Vec<Self>, ApiError> {
+        rate_limits
+            .unwrap_or(DEFAULT_RATE_LIMITS)
+            .split(',')
+            .filter(|rule| !rule.trim().is_empty())
+            .map(str::parse)
+            .collect()
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        path.strip_prefix(self.path.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    pub api_state: Arc<ModelState>,
+    pub rules: Arc<Vec<RateLimitRule>>,
+    pub trusted_proxies: Arc<TrustedProxies>,
+}
+
+/// Api keys are hashed so they are not stored in Redis
+fn hashed(value: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    format!("{:x}", hasher.finish())
+}
+
+fn add_rate_limit_headers(headers: &mut HeaderMap, rule: &RateLimitRule, limited: &RateLimit) {
+    let reset = limited.reset_after.as_millis().div_ceil(1000);
+    let mut values = vec![
+        (RATE_LIMIT_LIMIT, limited.limit.to_string()),
+        (RATE_LIMIT_REMAINING, limited.remaining.to_string()),
+        (RATE_LIMIT_RESET, reset.to_string()),
+        (
+            RATE_LIMIT_POLICY,
+            format!("{};w={}", rule.limit, rule.period.as_secs()),
+        ),
+    ];
+    if !limited.allowed {
+        let retry = limited.retry_after.as_millis().div_ceil(1000);
+        values.push((RETRY_AFTER, retry.to_string()));
+    }
+    for (name, value) in values {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// Counts requests to the paths in the configured rules, answering those over the limit
+/// with 429 and `Retry-After`. Every counted response carries the `RateLimit-*` headers.
+/// A limiter that cannot reach Redis is logged and lets the request through
+pub async fn rate_limit_request(
+    State(limiter): State<RateLimiter>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(rule) = limiter
+        .rules
+        .iter()
+        .find(|rule| rule.matches(request.uri().path()))
+    else {
+        return next.run(request).await;
+    };
+
+    let (mut parts, body) = request.into_parts();
+    let peer = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| *peer);
+    let ip = format!(
+        "ip:{}",
+        limiter.trusted_proxies.client_ip(&parts.headers, peer)
+    );
+    let identity = match rule.key {
+        RateLimitKey::Ip => ip,
+        RateLimitKey::User => {
+            match AuthUser::from_request_parts(&mut parts, &limiter.api_state).await {
+                Ok(AuthUser(user)) => format!("user:{}/{}", user.owner, user.name),
+                Err(_) => ip,
+            }
+        }
+        RateLimitKey::ApiKey => match parts.headers.get(API_KEY) {
+            Some(key) => format!("api_key:{}", hashed(key.as_bytes())),
+            None => ip,
+        },
+    };
+    let request = Request::from_parts(parts, body);
+
+    let key = format!("{}:{}", rule.path, identity);
+    let limited = match limiter
+        .api_state
+        .cache
+        .rate_limit(&key, rule.limit, rule.period)
+        .await
+    {
+        Ok(limited) => limited,
+        Err(e) => {
+            log::warn!("Rate limit of {} failed {:?}", rule.path, e);
+            return next.run(request).await;
+        }
+    };
+
+    let mut response = if limited.allowed {
+        next.run(request).await
+    } else {
+        let retry = limited.retry_after.as_millis().div_ceil(1000) as u64;
+        ApiError::RateLimited(retry).into_response()
+    };
+    add_rate_limit_headers(response.headers_mut(), rule, &limited);
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_rules() {
+        let rules =
+            RateLimitRule::from_env(Some("/auth_login=5/60, /users=100/3600:user")).unwrap();
+        assert_eq!(
+            rules,
+            vec![
+                RateLimitRule {
+                    path: "/auth_login".to_string(),
+                    limit: 5,
+                    period: Duration::from_secs(60),
+                    key: RateLimitKey::Ip,
+                },
+                RateLimitRule {
+                    path: "/users".to_string(),
+                    limit: 100,
+                    period: Duration::from_secs(3600),
+                    key: RateLimitKey::User,
+                },
+            ]
+        );
+        assert!(rules[1].matches("/users/1"));
+        assert!(!rules[1].matches("/users_admin"));
+
+        assert!(RateLimitRule::from_env(None).is_ok());
+        assert!(RateLimitRule::from_env(Some("/users=0/60")).is_err());
+        assert!(RateLimitRule::from_env(Some("/users=10/60:session")).is_err());
+        assert!(RateLimitRule::from_env(Some("users=10")).is_err());
+    }
+
+    #[test]
+    fn adds_retry_after_when_limited() {
+        let rule: RateLimitRule = "/auth_login=5/60".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        let limited = RateLimit {
+            allowed: false,
+            limit: 5,
+            remaining: 0,
+            reset_after: Duration::from_millis(59_500),
+            retry_after: Duration::from_millis(11_200),
+        };
+        add_rate_limit_headers(&mut headers, &rule, &limited);
+        assert_eq!(headers[RATE_LIMIT_REMAINING], "0");
+        assert_eq!(headers[RATE_LIMIT_RESET], "60");
+        assert_eq!(headers[RATE_LIMIT_POLICY], "5;w=60");
+        assert_eq!(headers[RETRY_AFTER], "12");
+    }
+}
@@ -0,0 +1,216 @@
+use axum::{
+    handler::Handler,
+    routing::{MethodFilter, MethodRouter},
+    Router,
+};
+use model::State as ModelState;
+use std::sync::Arc;
+use utoipa::{
+    __dev::SchemaReferences,
+    openapi::{
+        path::HttpMethod,
+        security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+        OpenApi,
+    },
+    Modify, Path,
+};
+
+/// Declares how requests authenticate, operations opt in with `security(("bearer" = []))`
+pub struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some("Base64 encoded token returned by `/auth_callback`"))
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("x-api-key"))),
+        );
+    }
+}
+
+fn method_filter(method: &HttpMethod) -> MethodFilter {
+    match method {
+        HttpMethod::Get => MethodFilter::GET,
+        HttpMethod::Put => MethodFilter::PUT,
+        HttpMethod::Post => MethodFilter::POST,
+        HttpMethod::Delete => MethodFilter::DELETE,
+        HttpMethod::Options => MethodFilter::OPTIONS,
+        HttpMethod::Head => MethodFilter::HEAD,
+        HttpMethod::Patch => MethodFilter::PATCH,
+        HttpMethod::Trace => MethodFilter::TRACE,
+    }
+}
+
+/// Axum captures `{name}` path params as `:name`
+fn axum_path(path: &str) -> String {
+    path.split('/')
+        .map(
+            |segment| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(param) => format!(":{param}"),
+                None => segment.to_owned(),
+            },
+        )
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// A `Router` that keeps the spec in step with it, handlers routed with `document!` are
+/// served at the path and methods of their `#[utoipa::path]` and add its operation and
+/// schemas to the spec
+pub(crate) struct ApiRouter {
+    pub router: Router<Arc<ModelState>>,
+    pub openapi: OpenApi,
+    /// Paths routed with `route`, left out of the spec
+    pub undocumented: Vec<String>,
+}
+
+impl ApiRouter {
+    pub fn new(openapi: OpenApi) -> Self {
+        Self {
+            router: Router::new(),
+            openapi,
+            undocumented: Vec::new(),
+        }
+    }
+
+    pub fn document<P, H, T>(mut self, handler: H) -> Self
+    where
+        P: Path + SchemaReferences,
+        H: Handler<T, Arc<ModelState>>,
+        T: 'static,
+    {
+        let path = P::path();
+        let methods = P::methods();
+        let method_router = methods
+            .iter()
+            .fold(MethodRouter::new(), |method_router, method| {
+                method_router.on(method_filter(method), handler.clone())
+            });
+        self.router = self.router.route(&axum_path(&path), method_router);
+
+        let mut schemas = Vec::new();
+        P::schemas(&mut schemas);
+        self.openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .schemas
+            .extend(schemas);
+        self.openapi
+            .paths
+            .add_path_operation(path, methods, P::operation());
+        self
+    }
+
+    /// Route without documenting, only for routes that are not part of the api such as pages
+    pub fn route(mut self, path: &str, method_router: MethodRouter<Arc<ModelState>>) -> Self {
+        self.router = self.router.route(path, method_router);
+        self.undocumented.push(path.to_owned());
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Routes served by the api that are not part of it and so left out of the spec
+    const UNDOCUMENTED_ROUTES: &[&str] = &["/"];
+
+    #[test]
+    fn documents_every_route() {
+        let ApiRouter {
+            openapi,
+            undocumented,
+            ..
+        } = crate::api_router();
+        assert_eq!(
+            undocumented, UNDOCUMENTED_ROUTES,
+            "routes must be added with `document!` so they are in the spec"
+        );
+
+        let components = openapi.components.expect("components");
+        assert!(components.security_schemes.contains_key("bearer"));
+        assert!(components.security_schemes.contains_key("api_key"));
+        assert!(components
+            .schemas
+            .contains_key("PaginatedResult_UserReadModel"));
+
+        for (path, item) in openapi.paths.paths {
+            assert!(!path.contains(':'), "{path} should use {{param}} captures");
+            let operations = [
+                item.get,
+                item.put,
+                item.post,
+                item.delete,
+                item.patch,
+                item.head,
+            ];
+            for operation in operations.into_iter().flatten() {
+                if path != "/healthcheck" {
+                    assert!(
+                        operation.responses.responses.contains_key("500"),
+                        "{path} does not document its errors"
+                    );
+                }
+                if path.starts_with("/auth_users") || path.starts_with("/admin") {
+                    assert!(operation.security.is_some(), "{path} is missing security");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn documents_wrapped_errors() {
+        let ApiRouter { openapi, .. } = crate::api_router();
+        let user = openapi.paths.paths["/users/{id}"]
+            .get
+            .clone()
+            .expect("get /users/{id}");
+        for status in ["404", "409", "422"] {
+            assert!(
+                user.responses.responses.contains_key(status),
+                "missing {status}"
+            );
+        }
+    }
+
+    #[test]
+    fn expands_query_params() {
+        let ApiRouter { openapi, .. } = crate::api_router();
+        let users = openapi.paths.paths["/users"]
+            .get
+            .clone()
+            .expect("get /users");
+        let names: Vec<_> = users
+            .parameters
+            .unwrap_or_default()
+            .into_iter()
+            .map(|param| param.name)
+            .collect();
+        for name in ["id", "email", "sortBy", "direction", "page", "limit"] {
+            assert!(
+                names.iter().any(|n| n == name),
+                "missing {name} in {names:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn converts_path_params() {
+        assert_eq!(axum_path("/users/{id}"), "/users/:id");
+        assert_eq!(
+            axum_path("/admin/broker/topics/{topic}/groups/{group}/consumers"),
+            "/admin/broker/topics/:topic/groups/:group/consumers"
+        );
+        assert_eq!(axum_path("/healthcheck"), "/healthcheck");
+    }
+}
@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+/// A topic and the number of messages currently retained for it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema, Default)]
+pub struct TopicInfo {
+    pub name: String,
+    pub length: u64,
+}
+
+/// State of a consumer group on a topic
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema, Default)]
+pub struct GroupInfo {
+    pub name: String,
+    pub consumers: u64,
+    /// Messages delivered to a consumer but not yet acknowledged
+    pub pending: u64,
+    /// Messages not yet delivered to the group, `None` if the backend cannot tell
+    pub lag: Option<u64>,
+    /// Milliseconds since the oldest pending message was published
+    pub oldest_pending_ms: Option<u64>,
+}
+
+/// A member of a consumer group
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema, Default)]
+pub struct ConsumerInfo {
+    pub name: String,
+    pub pending: u64,
+    /// Milliseconds since the consumer last read from the group
+    pub idle_ms: u64,
+}
+
+/// Milliseconds between a unix timestamp in milliseconds and now
+pub(crate) fn age_ms(published_ms: u64) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    now.saturating_sub(published_ms)
+}
@@ -0,0 +1,156 @@
+use deadpool_redis::redis::cmd;
+use sqlx::{Postgres, Transaction};
+use std::time::Duration;
+use util::{
+    env::Env,
+    error::UtilError,
+    macros::redis_op,
+    store::{CacheLayer, ConnectionPool, Redis, RWDB},
+};
+
+/// Value stored in Redis while a message is being handled
+const PROCESSING: &str = "processing";
+/// Value stored in Redis once a message has been handled
+const DONE: &str = "done";
+
+/// Where a subscriber records the ids of messages it has handled so repeats are skipped.
+/// Only messages published with an id are deduplicated, which is every message published
+/// through `BrokerLayer::publish` or the outbox relay
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Dedup {
+    /// Handle every delivery, the handler must be idempotent
+    #[default]
+    None,
+    /// Record ids in Redis, forgotten after `ttl` seconds
+    Redis { ttl: u64 },
+    /// Record ids in the `processed_messages` table. The row is inserted in a transaction
+    /// that `Subscriber::handle_message_tx` writes through and that is only committed if
+    /// handling succeeds, a concurrent delivery of the same id waits on the row lock
+    Postgres,
+}
+
+/// Backend for a subscriber's `Dedup` setting, created once per consumer task
+pub enum DedupStore {
+    Redis {
+        client: Redis,
+        ttl: u64,
+        processing_ttl: u64,
+    },
+    Postgres(RWDB),
+}
+
+/// Outcome of claiming a message id
+pub enum Claim {
+    /// First time the id is seen, the guard must be completed or released
+    New(DedupGuard),
+    /// Already handled, the delivery can be acknowledged without handling it
+    Duplicate,
+    /// Another consumer is handling the id right now, leave the delivery pending
+    InFlight,
+}
+
+/// Claim on a message id held while the message is handled
+pub enum DedupGuard {
+    Redis {
+        client: Redis,
+        key: String,
+        ttl: u64,
+    },
+    Postgres(Box<Transaction<'static, Postgres>>),
+}
+
+impl DedupStore {
+    /// `processing_after` bounds how long a claim survives a consumer that dies while
+    /// handling the message, normally the broker's retry delay
+    pub async fn new(
+        dedup: &Dedup,
+        env: &Env,
+        db: &RWDB,
+        processing_after: Duration,
+    ) -> Result<Option<Self>, UtilError> {
+        Ok(match dedup {
+            Dedup::None => None,
+            Dedup::Redis { ttl } => Some(Self::Redis {
+                client: Redis::new(env).await?,
+                ttl: *ttl,
+                processing_ttl: processing_after.as_secs().max(1),
+            }),
+            Dedup::Postgres => Some(Self::Postgres(db.clone())),
+        })
+    }
+
+    pub async fn claim(&self, group: &str, message_id: &str) -> Result<Claim, UtilError> {
+        match self {
+            Self::Redis {
+                client,
+                ttl,
+                processing_ttl,
+            } => {
+                let key = format!("dedup:{group}:{message_id}");
+                let claimed = redis_op!(
+                    client,
+                    cmd("SET")
+                        .arg(&key)
+                        .arg(PROCESSING)
+                        .arg("NX")
+                        .arg("EX")
+                        .arg(processing_ttl),
+                    Option<String>
+                )?;
+                if claimed.is_some() {
+                    return Ok(Claim::New(DedupGuard::Redis {
+                        client: client.clone(),
+                        key,
+                        ttl: *ttl,
+                    }));
+                }
+                let state = redis_op!(client, cmd("GET").arg(&key), Option<String>)?;
+                Ok(match state.as_deref() {
+                    Some(DONE) => Claim::Duplicate,
+                    _ => Claim::InFlight,
+                })
+            }
+            Self::Postgres(db) => {
+                let mut tx = db.begin().await?;
+                let inserted = sqlx::query(
+                    "INSERT INTO processed_messages (group_name, message_id) VALUES ($1, $2)
+                    ON CONFLICT DO NOTHING",
+                )
+                .bind(group)
+                .bind(message_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+                if inserted == 0 {
+                    tx.rollback().await?;
+                    return Ok(Claim::Duplicate);
+                }
+                Ok(Claim::New(DedupGuard::Postgres(Box::new(tx))))
+            }
+        }
+    }
+}
+
+impl DedupGuard {
+    /// Record the id as handled
+    pub async fn complete(self) -> Result<(), UtilError> {
+        match self {
+            Self::Redis { client, key, ttl } => redis_op!(
+                client,
+                cmd("SET").arg(&key).arg(DONE).arg("EX").arg(ttl),
+                ()
+            ),
+            Self::Postgres(tx) => Ok(tx.commit().await?),
+        }
+    }
+
+    /// Give up the claim so a redelivery is handled again
+    pub async fn release(self) -> Result<(), UtilError> {
+        match self {
+            Self::Redis { client, key, .. } => {
+                redis_op!(client, cmd("DEL").arg(&key), ())
+            }
+            Self::Postgres(tx) => Ok(tx.rollback().await?),
+        }
+    }
+}
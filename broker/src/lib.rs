@@ -1,6 +1,24 @@
-use deadpool_redis::redis::{cmd, Value};
-use std::collections::HashSet;
+pub mod admin;
+pub mod dedup;
+pub mod memory;
+pub mod metrics;
+pub mod outbox;
+pub mod postgres;
+pub mod read_model;
+pub mod replay;
+pub mod rpc;
+pub mod scheduler;
+
+use admin::{age_ms, ConsumerInfo, GroupInfo, TopicInfo};
+use chrono::{DateTime, Utc};
+use deadpool_redis::redis::{cmd, pipe, Value};
+use dedup::{Claim, DedupGuard, DedupStore};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::task;
 use tokio::task::JoinHandle;
 use util::{
@@ -10,57 +28,210 @@ use util::{
     store::{CacheLayer, ConnectionPool, Redis},
     AppState,
 };
-use util::{FromParams, ToParams};
+use util::{FromParams, RawParams, ToParams};
 use uuid::Uuid;
 
+pub use dedup::Dedup;
+pub use memory::MemoryBroker;
+pub use outbox::OutboxRelay;
+pub use postgres::PostgresQueue;
+pub use read_model::{ReadModel, ReadModelTable};
+pub use rpc::{Responder, ResponderSubscriber, RpcClient};
+
+/// Sorted set of scheduled message ids scored by delivery time in milliseconds, the hash
+/// tag keeps it in the same cluster slot as the payloads
+const SCHEDULED_QUEUE: &str = "{broker_scheduled}:queue";
+/// Hash of scheduled message id to `ScheduledMessage` json
+const SCHEDULED_MESSAGES: &str = "{broker_scheduled}:messages";
+
+/// Set of every topic added or subscribed to, used to list topics without scanning keys
+const TOPICS: &str = "broker:topics";
+
+/// Stream field appended after the message params to carry the published message id
+const MESSAGE_ID_FIELD: &str = "_message_id";
+
+/// Most scheduled messages moved at once
+const SCHEDULED_BATCH: usize = 100;
+
+/// How long a mover has to publish the scheduled messages it claimed before they fall due
+/// again for any mover
+const SCHEDULED_LEASE_MS: i64 = 30_000;
+
+/// Claim due scheduled messages by pushing their delivery time back to the end of the
+/// lease, returning the id and payload of each. Ids without a payload were cancelled
+const CLAIM_DUE: &str = r#"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[3])
+local claimed = {}
+for _, id in ipairs(due) do
+    local scheduled = redis.call('HGET', KEYS[2], id)
+    if scheduled then
+        redis.call('ZADD', KEYS[1], ARGV[2], id)
+        table.insert(claimed, {id, scheduled})
+    else
+        redis.call('ZREM', KEYS[1], id)
+    end
+end
+return claimed
+"#;
+
+/// Remove a published scheduled message unless it was scheduled again since it was claimed
+const COMPLETE_DUE: &str = r#"
+if tonumber(redis.call('ZSCORE', KEYS[1], ARGV[1])) == tonumber(ARGV[2]) then
+    redis.call('ZREM', KEYS[1], ARGV[1])
+    redis.call('HDEL', KEYS[2], ARGV[1])
+end
+return 0
+"#;
+
+/// How long a blocking read waits for new messages before checking for stale pending ones
+const READ_BLOCK_MS: u64 = 5000;
+
+/// A message read by a consumer group member, independent of the backend that stored it
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Delivery {
+    pub id: String,
+    /// Id given when publishing, unlike `id` it is the same for every copy of a message
+    /// published more than once
+    pub message_id: Option<String>,
+    pub params: Vec<String>,
+    pub attempts: u64,
+}
+
+/// Where to start or stop reading a topic. Ids are those of the backend, the stream entry id
+/// for Redis, the `broker_messages` id for Postgres and the sequence number for memory
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamPosition {
+    /// Before the first retained message
+    Beginning,
+    /// Just after the message with this id
+    Id(String),
+    /// Just before the first message published at or after this time
+    Time(DateTime<Utc>),
+}
+
+/// A message waiting in the schedule until it is due
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduledMessage {
+    pub topic: String,
+    pub params: Vec<String>,
+}
+
+/// Controls when unacknowledged messages are handed out again and when they are given up on
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub retry_after: Duration,
+    pub max_attempts: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retry_after: Duration::from_secs(30),
+            max_attempts: 5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_env(env: &Env) -> Self {
+        let default = Self::default();
+        let Some(broker) = &env.broker else {
+            return default;
+        };
+        Self {
+            retry_after: broker
+                .retry_after
+                .as_ref()
+                .and_then(|s| s.parse::<u64>().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(default.retry_after),
+            max_attempts: broker
+                .max_attempts
+                .as_ref()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(default.max_attempts),
+        }
+    }
+}
+
 #[allow(async_fn_in_trait)]
 pub trait Subscriber: Send + Sync {
-    type MessageType: FromParams + ToParams;
+    type MessageType: FromParams + ToParams + Send;
 
     fn handle_message(
         &self,
         message: Self::MessageType,
         app_state: Arc<impl AppState>,
     ) -> impl std::future::Future<Output = Result<(), UtilError>> + Send;
+    /// Handle the message in the transaction `Dedup::Postgres` records its id in, writes
+    /// made through `conn` commit together with the record or not at all. Defaults to
+    /// `handle_message`, whose writes are not part of the transaction
+    fn handle_message_tx(
+        &self,
+        message: Self::MessageType,
+        conn: &mut PgConnection,
+        app_state: Arc<impl AppState>,
+    ) -> impl std::future::Future<Output = Result<(), UtilError>> + Send {
+        let _ = conn;
+        self.handle_message(message, app_state)
+    }
     fn topic(&self) -> String;
     fn group_name(&self) -> String;
-    fn parse_message(&self, message: &Value) -> Result<Self::MessageType, UtilError> {
-        if let Some(params) = message
-            .as_sequence()
-            .and_then(|v| v.first())
-            .and_then(|v| v.as_sequence())
-            .and_then(|v| v.get(1))
-            .and_then(|v| v.as_sequence())
-            .and_then(|v| v.first())
-            .and_then(|v| v.as_sequence())
-            .and_then(|v| v.get(1))
-            .and_then(|v| v.as_sequence())
-            .map(|v| {
-                v.iter()
-                    .filter_map(|v| {
-                        if let Value::BulkString(data) = v {
-                            String::from_utf8(data.clone()).ok()
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<String>>()
-            })
-        {
-            return Ok(Self::MessageType::from_params(params));
-        }
-        Err(UtilError::RedisStreamParams)
+    fn parse_params(&self, params: Vec<String>) -> Result<Self::MessageType, UtilError> {
+        Ok(Self::MessageType::from_params(params))
+    }
+    /// Opt in to skipping messages this group has already handled, for handlers that are
+    /// not idempotent
+    fn dedup(&self) -> Dedup {
+        Dedup::None
     }
 }
 
 #[allow(async_fn_in_trait)]
-pub trait BrokerLayer: Sized {
+pub trait BrokerLayer: Sized + Clone + Send + Sync + 'static {
     async fn new(env: &Env) -> Result<Self, UtilError>;
     async fn publish(
         &self,
         topic: &str,
         message: &(impl FromParams + ToParams),
+    ) -> Result<(), UtilError> {
+        self.publish_with_id(topic, &Uuid::now_v7().to_string(), message)
+            .await
+    }
+    /// Publish with a caller supplied message id, subscribers using `Dedup` only handle
+    /// the first message they see with a given id
+    async fn publish_with_id(
+        &self,
+        topic: &str,
+        message_id: &str,
+        message: &(impl FromParams + ToParams),
+    ) -> Result<(), UtilError>;
+    /// Hold the message until `at`, publishing again with the same id replaces the
+    /// pending message and its delivery time
+    async fn publish_at(
+        &self,
+        topic: &str,
+        message_id: &str,
+        at: DateTime<Utc>,
+        message: &(impl FromParams + ToParams),
     ) -> Result<(), UtilError>;
+    async fn publish_after(
+        &self,
+        topic: &str,
+        message_id: &str,
+        delay: Duration,
+        message: &(impl FromParams + ToParams),
+    ) -> Result<(), UtilError> {
+        let delay = chrono::Duration::from_std(delay)
+            .map_err(|e| UtilError::Other(format!("Invalid delay {e}")))?;
+        self.publish_at(topic, message_id, Utc::now() + delay, message)
+            .await
+    }
+    /// Cancel a scheduled message that has not been delivered yet, returns false if no
+    /// message with the id is waiting
+    async fn cancel_scheduled(&self, message_id: &str) -> Result<bool, UtilError>;
+    /// Publish scheduled messages that are due, returns how many were published
+    async fn move_due(&self) -> Result<usize, UtilError>;
     async fn subscribe(
         &self,
         subscriber: impl Subscriber + 'static,
@@ -71,18 +242,495 @@ pub trait BrokerLayer: Sized {
         all_subscribers: Vec<impl Subscriber + 'static>,
         env: &Env,
         app_state: Arc<impl AppState + Clone + std::marker::Sync + std::marker::Send + 'static>,
-    ) -> Result<Vec<JoinHandle<Result<(), UtilError>>>, UtilError>;
-    async fn topic_exists(&self, topic: &str) -> Result<bool, UtilError>;
-    async fn add_topic(&self, _topic: &str) -> Result<(), UtilError> {
-        Ok(())
+    ) -> Result<Vec<JoinHandle<Result<(), UtilError>>>, UtilError> {
+        let watch_topics = env.watch_topics.clone().unwrap_or_default();
+        let topic_names = watch_topics.split(",").collect::<HashSet<_>>();
+        let mut subscribers = vec![];
+        for subscriber in all_subscribers
+            .into_iter()
+            .filter(|s| topic_names.contains(s.topic().as_str()))
+        {
+            subscribers.push(self.subscribe(subscriber, app_state.clone()).await?)
+        }
+        Ok(subscribers)
     }
+    /// Create the consumer group if it does not exist, only messages published after
+    /// the group is created are delivered to it
+    async fn create_group(&self, topic: &str, group: &str) -> Result<(), UtilError>;
+    /// Claim the next message for a group member, stale unacknowledged messages are
+    /// redelivered before new ones. Returns `None` if nothing arrived while waiting
+    async fn read_group(
+        &self,
+        topic: &str,
+        group: &str,
+        consumer: &str,
+    ) -> Result<Option<Delivery>, UtilError>;
+    async fn ack(&self, topic: &str, group: &str, id: &str) -> Result<(), UtilError>;
+    fn retry_policy(&self) -> &RetryPolicy;
+    async fn topic_exists(&self, topic: &str) -> Result<bool, UtilError>;
+    /// Create the topic without any messages or groups, doing nothing if it exists
+    async fn add_topic(&self, topic: &str) -> Result<(), UtilError>;
     async fn remove_queue(&self, topic: &str) -> Result<(), UtilError>;
+    /// Move the group so the next read returns the first message after `position`, pending
+    /// messages are left to be redelivered or acknowledged as usual
+    async fn reset_group(
+        &self,
+        topic: &str,
+        group: &str,
+        position: &StreamPosition,
+    ) -> Result<(), UtilError>;
+    /// Read up to `count` retained messages after `from`, and up to and including `to`
+    /// if given, without touching any consumer group
+    async fn read_range(
+        &self,
+        topic: &str,
+        from: &StreamPosition,
+        to: Option<&StreamPosition>,
+        count: usize,
+    ) -> Result<Vec<Delivery>, UtilError>;
+    async fn list_topics(&self) -> Result<Vec<TopicInfo>, UtilError>;
+    async fn list_groups(&self, topic: &str) -> Result<Vec<GroupInfo>, UtilError>;
+    async fn list_consumers(
+        &self,
+        topic: &str,
+        group: &str,
+    ) -> Result<Vec<ConsumerInfo>, UtilError>;
+}
+
+/// Read, handle and acknowledge messages for a single subscriber until the task is aborted.
+/// Messages that fail are left unacknowledged so the backend hands them out again once
+/// `RetryPolicy::retry_after` has passed, after `RetryPolicy::max_attempts` they are dropped.
+/// Subscribers with a `Dedup` store acknowledge repeats of a handled message without
+/// handling them again
+pub async fn consume<B, S, A>(broker: B, subscriber: S, app_state: Arc<A>) -> Result<(), UtilError>
+where
+    B: BrokerLayer,
+    S: Subscriber,
+    A: AppState + Clone + Sync + Send + 'static,
+{
+    let topic = subscriber.topic();
+    let group = subscriber.group_name();
+    let consumer = Uuid::new_v4().to_string();
+    let counters = metrics::counters(&topic, &group);
+    let dedup = DedupStore::new(
+        &subscriber.dedup(),
+        app_state.get_env(),
+        app_state.get_rw_store(),
+        broker.retry_policy().retry_after,
+    )
+    .await
+    .inspect_err(|e| log::error!("Failed to create dedup store for {} {:?}", group, e))?;
+
+    if let Err(e) = broker.create_group(&topic, &group).await {
+        log::error!("Failed to create group {} on {} {:?}", group, topic, e);
+    }
+
+    loop {
+        let delivery = match broker.read_group(&topic, &group, &consumer).await {
+            Ok(Some(delivery)) => delivery,
+            Ok(None) => continue,
+            Err(e) => {
+                log::error!("Broker read error on {} {:?}", topic, e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        if delivery.attempts > broker.retry_policy().max_attempts {
+            log::error!(
+                "Dropping message {} on {} after {} failed attempts",
+                delivery.id,
+                topic,
+                delivery.attempts - 1
+            );
+            counters.dropped.fetch_add(1, Ordering::Relaxed);
+            if let Err(e) = broker.ack(&topic, &group, &delivery.id).await {
+                log::error!("Broker ack error {:?} on {} id {}", e, topic, delivery.id);
+            }
+            continue;
+        }
+
+        let mut guard = match (&dedup, &delivery.message_id) {
+            (Some(store), Some(message_id)) => match store.claim(&group, message_id).await {
+                Ok(Claim::New(guard)) => Some(guard),
+                Ok(Claim::Duplicate) => {
+                    log::info!("Skipping duplicate message {} on {}", message_id, topic);
+                    counters.duplicates.fetch_add(1, Ordering::Relaxed);
+                    if let Err(e) = broker.ack(&topic, &group, &delivery.id).await {
+                        log::error!("Broker ack error {:?} on {} id {}", e, topic, delivery.id);
+                    }
+                    continue;
+                }
+                Ok(Claim::InFlight) => continue,
+                Err(e) => {
+                    log::error!("Dedup claim failed for {} on {} {:?}", message_id, topic, e);
+                    continue;
+                }
+            },
+            _ => None,
+        };
+
+        let handled = match subscriber.parse_params(delivery.params) {
+            Ok(message) => match &mut guard {
+                Some(DedupGuard::Postgres(tx)) => {
+                    subscriber
+                        .handle_message_tx(message, tx, app_state.clone())
+                        .await
+                }
+                _ => subscriber.handle_message(message, app_state.clone()).await,
+            }
+            .inspect_err(|e| log::error!("Subscriber failed to handle message due to {:?}", e)),
+            Err(e) => {
+                log::error!("Failed to parse message {:?}", e);
+                Err(e)
+            }
+        };
+        if handled.is_err() {
+            counters.failed.fetch_add(1, Ordering::Relaxed);
+            release(guard).await;
+            continue;
+        }
+
+        // with `Dedup::Postgres` a failed commit also rolls back what the handler wrote, so
+        // the message is left pending to be handled again
+        if let Some(guard) = guard {
+            if let Err(e) = guard.complete().await {
+                log::error!(
+                    "Failed to record message {} as handled {:?}",
+                    delivery.id,
+                    e
+                );
+                counters.failed.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        }
+        counters.processed.fetch_add(1, Ordering::Relaxed);
+
+        if let Err(e) = broker.ack(&topic, &group, &delivery.id).await {
+            log::error!("Broker ack error {:?} on {} id {}", e, topic, delivery.id);
+        }
+    }
+}
+
+async fn release(guard: Option<DedupGuard>) {
+    if let Some(Err(e)) = match guard {
+        Some(guard) => Some(guard.release().await),
+        None => None,
+    } {
+        log::error!("Failed to release dedup claim {:?}", e);
+    }
+}
+
+/// Broker backend selected by the `broker_backend` env var, defaults to Redis streams
+#[derive(Clone)]
+pub enum Broker {
+    Redis(RedisStream),
+    Postgres(PostgresQueue),
+    Memory(MemoryBroker),
+}
+
+impl BrokerLayer for Broker {
+    async fn new(env: &Env) -> Result<Self, UtilError> {
+        let backend = env.broker.as_ref().and_then(|b| b.backend.as_deref());
+        match backend {
+            Some("postgres") => Ok(Self::Postgres(PostgresQueue::new(env).await?)),
+            Some("memory") => Ok(Self::Memory(MemoryBroker::new(env).await?)),
+            Some("redis") | None => Ok(Self::Redis(RedisStream::new(env).await?)),
+            Some(other) => Err(UtilError::Other(format!(
+                "Unknown broker backend {other}, expected redis, postgres or memory"
+            ))),
+        }
+    }
+
+    async fn publish_with_id(
+        &self,
+        topic: &str,
+        message_id: &str,
+        message: &(impl FromParams + ToParams),
+    ) -> Result<(), UtilError> {
+        match self {
+            Self::Redis(broker) => broker.publish_with_id(topic, message_id, message).await,
+            Self::Postgres(broker) => broker.publish_with_id(topic, message_id, message).await,
+            Self::Memory(broker) => broker.publish_with_id(topic, message_id, message).await,
+        }
+    }
+
+    async fn publish_at(
+        &self,
+        topic: &str,
+        message_id: &str,
+        at: DateTime<Utc>,
+        message: &(impl FromParams + ToParams),
+    ) -> Result<(), UtilError> {
+        match self {
+            Self::Redis(broker) => broker.publish_at(topic, message_id, at, message).await,
+            Self::Postgres(broker) => broker.publish_at(topic, message_id, at, message).await,
+            Self::Memory(broker) => broker.publish_at(topic, message_id, at, message).await,
+        }
+    }
+
+    async fn cancel_scheduled(&self, message_id: &str) -> Result<bool, UtilError> {
+        match self {
+            Self::Redis(broker) => broker.cancel_scheduled(message_id).await,
+            Self::Postgres(broker) => broker.cancel_scheduled(message_id).await,
+            Self::Memory(broker) => broker.cancel_scheduled(message_id).await,
+        }
+    }
+
+    async fn move_due(&self) -> Result<usize, UtilError> {
+        match self {
+            Self::Redis(broker) => broker.move_due().await,
+            Self::Postgres(broker) => broker.move_due().await,
+            Self::Memory(broker) => broker.move_due().await,
+        }
+    }
+
+    async fn subscribe(
+        &self,
+        subscriber: impl Subscriber + 'static,
+        app_state: Arc<impl AppState + Clone + std::marker::Sync + std::marker::Send + 'static>,
+    ) -> Result<JoinHandle<Result<(), UtilError>>, UtilError> {
+        match self {
+            Self::Redis(broker) => broker.subscribe(subscriber, app_state).await,
+            Self::Postgres(broker) => broker.subscribe(subscriber, app_state).await,
+            Self::Memory(broker) => broker.subscribe(subscriber, app_state).await,
+        }
+    }
+
+    async fn create_group(&self, topic: &str, group: &str) -> Result<(), UtilError> {
+        match self {
+            Self::Redis(broker) => broker.create_group(topic, group).await,
+            Self::Postgres(broker) => broker.create_group(topic, group).await,
+            Self::Memory(broker) => broker.create_group(topic, group).await,
+        }
+    }
+
+    async fn read_group(
+        &self,
+        topic: &str,
+        group: &str,
+        consumer: &str,
+    ) -> Result<Option<Delivery>, UtilError> {
+        match self {
+            Self::Redis(broker) => broker.read_group(topic, group, consumer).await,
+            Self::Postgres(broker) => broker.read_group(topic, group, consumer).await,
+            Self::Memory(broker) => broker.read_group(topic, group, consumer).await,
+        }
+    }
+
+    async fn ack(&self, topic: &str, group: &str, id: &str) -> Result<(), UtilError> {
+        match self {
+            Self::Redis(broker) => broker.ack(topic, group, id).await,
+            Self::Postgres(broker) => broker.ack(topic, group, id).await,
+            Self::Memory(broker) => broker.ack(topic, group, id).await,
+        }
+    }
+
+    fn retry_policy(&self) -> &RetryPolicy {
+        match self {
+            Self::Redis(broker) => broker.retry_policy(),
+            Self::Postgres(broker) => broker.retry_policy(),
+            Self::Memory(broker) => broker.retry_policy(),
+        }
+    }
+
+    async fn topic_exists(&self, topic: &str) -> Result<bool, UtilError> {
+        match self {
+            Self::Redis(broker) => broker.topic_exists(topic).await,
+            Self::Postgres(broker) => broker.topic_exists(topic).await,
+            Self::Memory(broker) => broker.topic_exists(topic).await,
+        }
+    }
+
+    async fn add_topic(&self, topic: &str) -> Result<(), UtilError> {
+        match self {
+            Self::Redis(broker) => broker.add_topic(topic).await,
+            Self::Postgres(broker) => broker.add_topic(topic).await,
+            Self::Memory(broker) => broker.add_topic(topic).await,
+        }
+    }
+
+    async fn remove_queue(&self, topic: &str) -> Result<(), UtilError> {
+        match self {
+            Self::Redis(broker) => broker.remove_queue(topic).await,
+            Self::Postgres(broker) => broker.remove_queue(topic).await,
+            Self::Memory(broker) => broker.remove_queue(topic).await,
+        }
+    }
+
+    async fn reset_group(
+        &self,
+        topic: &str,
+        group: &str,
+        position: &StreamPosition,
+    ) -> Result<(), UtilError> {
+        match self {
+            Self::Redis(broker) => broker.reset_group(topic, group, position).await,
+            Self::Postgres(broker) => broker.reset_group(topic, group, position).await,
+            Self::Memory(broker) => broker.reset_group(topic, group, position).await,
+        }
+    }
+
+    async fn read_range(
+        &self,
+        topic: &str,
+        from: &StreamPosition,
+        to: Option<&StreamPosition>,
+        count: usize,
+    ) -> Result<Vec<Delivery>, UtilError> {
+        match self {
+            Self::Redis(broker) => broker.read_range(topic, from, to, count).await,
+            Self::Postgres(broker) => broker.read_range(topic, from, to, count).await,
+            Self::Memory(broker) => broker.read_range(topic, from, to, count).await,
+        }
+    }
+
+    async fn list_topics(&self) -> Result<Vec<TopicInfo>, UtilError> {
+        match self {
+            Self::Redis(broker) => broker.list_topics().await,
+            Self::Postgres(broker) => broker.list_topics().await,
+            Self::Memory(broker) => broker.list_topics().await,
+        }
+    }
+
+    async fn list_groups(&self, topic: &str) -> Result<Vec<GroupInfo>, UtilError> {
+        match self {
+            Self::Redis(broker) => broker.list_groups(topic).await,
+            Self::Postgres(broker) => broker.list_groups(topic).await,
+            Self::Memory(broker) => broker.list_groups(topic).await,
+        }
+    }
+
+    async fn list_consumers(
+        &self,
+        topic: &str,
+        group: &str,
+    ) -> Result<Vec<ConsumerInfo>, UtilError> {
+        match self {
+            Self::Redis(broker) => broker.list_consumers(topic, group).await,
+            Self::Postgres(broker) => broker.list_consumers(topic, group).await,
+            Self::Memory(broker) => broker.list_consumers(topic, group).await,
+        }
+    }
+}
+
+impl Broker {
+    /// Publish scheduled messages as they fall due until the task is aborted. Safe to run
+    /// on every replica, each due message is claimed by a single mover
+    pub fn start_mover(self, interval: Duration) -> JoinHandle<Result<(), UtilError>> {
+        task::spawn(async move {
+            loop {
+                match self.move_due().await {
+                    Ok(moved) if moved >= SCHEDULED_BATCH => continue,
+                    Ok(_) => {}
+                    Err(e) => log::error!("Failed to move scheduled messages {:?}", e),
+                }
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
 }
 
 #[derive(Clone)]
 pub struct RedisStream {
     pub client: Redis,
     pub max_len: i64,
+    pub retry: RetryPolicy,
+}
+
+impl RedisStream {
+    /// Parse a single `[id, [field, value, ...]]` stream entry
+    fn parse_entry(entry: &Value) -> Option<Delivery> {
+        let entry = entry.as_sequence()?;
+        let id = match entry.first()? {
+            Value::BulkString(id) => String::from_utf8(id.clone()).ok()?,
+            _ => return None,
+        };
+        let mut params = entry
+            .get(1)?
+            .as_sequence()?
+            .iter()
+            .filter_map(|v| {
+                if let Value::BulkString(data) = v {
+                    String::from_utf8(data.clone()).ok()
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<String>>();
+        let message_id = match params.as_slice() {
+            [.., field, message_id] if field == MESSAGE_ID_FIELD => Some(message_id.clone()),
+            _ => None,
+        };
+        if message_id.is_some() {
+            params.truncate(params.len() - 2);
+        }
+        Some(Delivery {
+            id,
+            message_id,
+            params,
+            attempts: 1,
+        })
+    }
+
+    /// Claim the oldest pending message that has been idle longer than the retry delay
+    async fn claim_stale(
+        &self,
+        topic: &str,
+        group: &str,
+        consumer: &str,
+    ) -> Result<Option<Delivery>, UtilError> {
+        let client = &self.client;
+        let idle_ms = self.retry.retry_after.as_millis() as u64;
+        let pending = redis_op!(
+            client,
+            cmd("XPENDING")
+                .arg(topic)
+                .arg(group)
+                .arg("IDLE")
+                .arg(idle_ms)
+                .arg("-")
+                .arg("+")
+                .arg(1),
+            Value
+        )?;
+
+        let Some((id, deliveries)) = pending
+            .as_sequence()
+            .and_then(|v| v.first())
+            .and_then(|v| v.as_sequence())
+            .and_then(|v| match (v.first(), v.get(3)) {
+                (Some(Value::BulkString(id)), Some(Value::Int(deliveries))) => {
+                    String::from_utf8(id.clone())
+                        .ok()
+                        .map(|id| (id, *deliveries))
+                }
+                _ => None,
+            })
+        else {
+            return Ok(None);
+        };
+
+        let claimed = redis_op!(
+            client,
+            cmd("XCLAIM")
+                .arg(topic)
+                .arg(group)
+                .arg(consumer)
+                .arg(idle_ms)
+                .arg(&id),
+            Value
+        )?;
+
+        Ok(claimed
+            .as_sequence()
+            .and_then(|v| v.first())
+            .and_then(Self::parse_entry)
+            .map(|delivery| Delivery {
+                attempts: deliveries as u64 + 1,
+                ..delivery
+            }))
+    }
 }
 
 #[allow(async_fn_in_trait)]
@@ -96,15 +744,17 @@ impl BrokerLayer for RedisStream {
                     .as_ref()
                     .and_then(|l| l.parse::<i64>().ok())
                     .unwrap_or(1000),
+                retry: RetryPolicy::from_env(env),
             });
         }
 
         Err(UtilError::RedisNotConfigured)
     }
 
-    async fn publish(
+    async fn publish_with_id(
         &self,
         topic: &str,
+        message_id: &str,
         message: &(impl FromParams + ToParams),
     ) -> Result<(), UtilError> {
         let params = message.to_params();
@@ -117,146 +767,439 @@ impl BrokerLayer for RedisStream {
                 .arg("~")
                 .arg(self.max_len)
                 .arg("*")
-                .arg(&params),
+                .arg(&params)
+                .arg(MESSAGE_ID_FIELD)
+                .arg(message_id),
             String
         )?;
         Ok(())
     }
 
+    async fn publish_at(
+        &self,
+        topic: &str,
+        message_id: &str,
+        at: DateTime<Utc>,
+        message: &(impl FromParams + ToParams),
+    ) -> Result<(), UtilError> {
+        let scheduled = serde_json::to_string(&ScheduledMessage {
+            topic: topic.to_owned(),
+            params: message.to_params(),
+        })?;
+        let client = &self.client;
+        redis_op!(
+            client,
+            pipe()
+                .atomic()
+                .cmd("HSET")
+                .arg(SCHEDULED_MESSAGES)
+                .arg(message_id)
+                .arg(scheduled)
+                .cmd("ZADD")
+                .arg(SCHEDULED_QUEUE)
+                .arg(at.timestamp_millis())
+                .arg(message_id),
+            ()
+        )
+    }
+
+    async fn cancel_scheduled(&self, message_id: &str) -> Result<bool, UtilError> {
+        let client = &self.client;
+        let (removed, _) = redis_op!(
+            client,
+            pipe()
+                .atomic()
+                .cmd("ZREM")
+                .arg(SCHEDULED_QUEUE)
+                .arg(message_id)
+                .cmd("HDEL")
+                .arg(SCHEDULED_MESSAGES)
+                .arg(message_id),
+            (i64, i64)
+        )?;
+        Ok(removed == 1)
+    }
+
+    async fn move_due(&self) -> Result<usize, UtilError> {
+        let client = &self.client;
+        let lease_until = Utc::now().timestamp_millis() + SCHEDULED_LEASE_MS;
+        let claimed = redis_op!(
+            client,
+            cmd("EVAL")
+                .arg(CLAIM_DUE)
+                .arg(2)
+                .arg(SCHEDULED_QUEUE)
+                .arg(SCHEDULED_MESSAGES)
+                .arg(Utc::now().timestamp_millis())
+                .arg(lease_until)
+                .arg(SCHEDULED_BATCH),
+            Vec<(String, String)>
+        )?;
+        let mut moved = 0;
+        for (message_id, scheduled) in claimed {
+            let published = match serde_json::from_str::<ScheduledMessage>(&scheduled) {
+                Ok(scheduled) => {
+                    self.publish_with_id(
+                        &scheduled.topic,
+                        &message_id,
+                        &RawParams(scheduled.params),
+                    )
+                    .await
+                }
+                Err(e) => Err(e.into()),
+            };
+            // left in the queue the message is claimed again once the lease is over
+            if let Err(e) = published {
+                log::error!("Failed to publish scheduled message {} {:?}", message_id, e);
+                continue;
+            }
+            redis_op!(
+                client,
+                cmd("EVAL")
+                    .arg(COMPLETE_DUE)
+                    .arg(2)
+                    .arg(SCHEDULED_QUEUE)
+                    .arg(SCHEDULED_MESSAGES)
+                    .arg(&message_id)
+                    .arg(lease_until),
+                ()
+            )?;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
     async fn subscribe(
         &self,
         subscriber: impl Subscriber + 'static,
         app_state: Arc<impl AppState + Clone + std::marker::Sync + std::marker::Send + 'static>,
     ) -> Result<JoinHandle<Result<(), UtilError>>, UtilError> {
-        let client = self.client.clone();
-        let state = app_state.clone();
-        let capture_topic = subscriber.topic();
-        let join_handle: task::JoinHandle<_> = task::spawn(async move {
-            let mkgroup = redis_op!(
-                client,
-                cmd("XGROUP")
-                    .arg("CREATE")
-                    .arg(capture_topic.clone())
-                    .arg(subscriber.group_name())
-                    .arg("$")
-                    .arg("MKSTREAM"),
-                String
-            );
+        Ok(task::spawn(consume(self.clone(), subscriber, app_state)))
+    }
 
-            if let Err(e) = &mkgroup {
-                if !e.to_string().contains("Consumer Group name already exists") {
-                    log::error!("Failed to create Redis group {:?}", mkgroup);
-                }
-            }
+    async fn create_group(&self, topic: &str, group: &str) -> Result<(), UtilError> {
+        let client = &self.client;
+        let mkgroup = redis_op!(
+            client,
+            cmd("XGROUP")
+                .arg("CREATE")
+                .arg(topic)
+                .arg(group)
+                .arg("$")
+                .arg("MKSTREAM"),
+            String
+        );
 
-            loop {
-                let mut message_processed = false;
-                let message;
-
-                let message_result = redis_op!(
-                    client,
-                    cmd("XREADGROUP")
-                        .arg("GROUP")
-                        .arg(subscriber.group_name())
-                        .arg(Uuid::new_v4().to_string())
-                        .arg("BLOCK")
-                        .arg("0")
-                        .arg("COUNT")
-                        .arg("1")
-                        .arg("STREAMS")
-                        .arg(capture_topic.clone())
-                        .arg(">")
-                );
+        match mkgroup {
+            Err(e) if !e.to_string().contains("Consumer Group name already exists") => Err(e),
+            _ => redis_op!(client, cmd("SADD").arg(TOPICS).arg(topic), ()),
+        }
+    }
 
-                if let Err(e) = message_result {
-                    log::error!("Redis XREADGROUP error {:?}", e);
-                    continue;
-                } else {
-                    message = message_result.unwrap();
-                }
+    async fn read_group(
+        &self,
+        topic: &str,
+        group: &str,
+        consumer: &str,
+    ) -> Result<Option<Delivery>, UtilError> {
+        if let Some(delivery) = self.claim_stale(topic, group, consumer).await? {
+            return Ok(Some(delivery));
+        }
 
-                let parsed_message_result = subscriber.parse_message(&message);
-                if let Err(ref e) = parsed_message_result {
-                    log::error!("Failed to parse message {:?}", e);
-                    continue;
-                }
+        let client = &self.client;
+        let message = redis_op!(
+            client,
+            cmd("XREADGROUP")
+                .arg("GROUP")
+                .arg(group)
+                .arg(consumer)
+                .arg("BLOCK")
+                .arg(READ_BLOCK_MS)
+                .arg("COUNT")
+                .arg("1")
+                .arg("STREAMS")
+                .arg(topic)
+                .arg(">"),
+            Value
+        )?;
 
-                let parsed_message = parsed_message_result.unwrap();
+        if message == Value::Nil {
+            return Ok(None);
+        }
 
-                if let Err(e) = subscriber
-                    .handle_message(parsed_message, state.clone())
-                    .await
-                {
-                    log::error!("Redis subscriber failed to handle message due to {:?}", e);
-                } else {
-                    message_processed = true
-                }
+        message
+            .as_sequence()
+            .and_then(|v| v.first())
+            .and_then(|v| v.as_sequence())
+            .and_then(|v| v.get(1))
+            .and_then(|v| v.as_sequence())
+            .and_then(|v| v.first())
+            .and_then(Self::parse_entry)
+            .map(Some)
+            .ok_or(UtilError::RedisStreamParams)
+    }
 
-                if message_processed {
-                    if let Some(Value::BulkString(id_vec)) = message
-                        .as_sequence()
-                        .and_then(|v| v.first())
-                        .and_then(|v| v.as_sequence())
-                        .and_then(|v| v.get(1))
-                        .and_then(|v| v.as_sequence())
-                        .and_then(|v| v.first())
-                        .and_then(|v| v.as_sequence())
-                        .and_then(|v| v.first())
-                    {
-                        let id = String::from_utf8(id_vec.clone()).unwrap_or_default();
-
-                        let ack_result: Result<Value, UtilError> = redis_op!(
-                            client,
-                            cmd("XACK")
-                                .arg(capture_topic.clone())
-                                .arg(subscriber.group_name())
-                                .arg(id.clone())
-                        );
-                        if let Err(e) = ack_result {
-                            log::error!(
-                                "Redis xack error {:?} on stream {:?} id {:?}",
-                                e,
-                                capture_topic,
-                                id
-                            )
-                        }
-                    }
-                }
-            }
-        });
-        Ok(join_handle)
+    async fn ack(&self, topic: &str, group: &str, id: &str) -> Result<(), UtilError> {
+        let client = &self.client;
+        redis_op!(client, cmd("XACK").arg(topic).arg(group).arg(id), Value)?;
+        Ok(())
     }
 
-    async fn start_subscriptions(
+    fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    async fn topic_exists(&self, topic: &str) -> Result<bool, UtilError> {
+        let client = &self.client;
+        let key_type = redis_op!(client, cmd("TYPE").arg(topic), String)?;
+        Ok(key_type == "stream")
+    }
+
+    /// Create an empty stream, adding an entry and trimming to zero is the only way to
+    /// create a stream without a consumer group
+    async fn add_topic(&self, topic: &str) -> Result<(), UtilError> {
+        let client = &self.client;
+        if !self.topic_exists(topic).await? {
+            redis_op!(
+                client,
+                cmd("XADD")
+                    .arg(topic)
+                    .arg("MAXLEN")
+                    .arg(0)
+                    .arg("*")
+                    .arg(MESSAGE_ID_FIELD)
+                    .arg(""),
+                String
+            )?;
+        }
+        redis_op!(client, cmd("SADD").arg(TOPICS).arg(topic), ())
+    }
+
+    /// Delete the stream along with its consumer groups and pending messages
+    async fn remove_queue(&self, topic: &str) -> Result<(), UtilError> {
+        let client = &self.client;
+        redis_op!(client, cmd("DEL").arg(topic), ())?;
+        redis_op!(client, cmd("SREM").arg(TOPICS).arg(topic), ())
+    }
+
+    async fn reset_group(
         &self,
-        all_subscribers: Vec<impl Subscriber + 'static>,
-        env: &Env,
-        app_state: Arc<impl AppState + Clone + std::marker::Sync + std::marker::Send + 'static>,
-    ) -> Result<Vec<JoinHandle<Result<(), UtilError>>>, UtilError> {
-        let watch_topics = env.watch_topics.clone().unwrap_or_default();
-        let topic_names = watch_topics.split(",").collect::<HashSet<_>>();
-        let mut subscribers = vec![];
-        for subscriber in all_subscribers
+        topic: &str,
+        group: &str,
+        position: &StreamPosition,
+    ) -> Result<(), UtilError> {
+        let id = match position {
+            StreamPosition::Beginning => "0".to_string(),
+            StreamPosition::Id(id) => id.clone(),
+            // the last possible id of the previous millisecond
+            StreamPosition::Time(at) => format!("{}-{}", at.timestamp_millis() - 1, u64::MAX),
+        };
+        let client = &self.client;
+        redis_op!(
+            client,
+            cmd("XGROUP").arg("SETID").arg(topic).arg(group).arg(id),
+            ()
+        )
+    }
+
+    async fn read_range(
+        &self,
+        topic: &str,
+        from: &StreamPosition,
+        to: Option<&StreamPosition>,
+        count: usize,
+    ) -> Result<Vec<Delivery>, UtilError> {
+        let start = match from {
+            StreamPosition::Beginning => "-".to_string(),
+            StreamPosition::Id(id) => format!("({id}"),
+            StreamPosition::Time(at) => at.timestamp_millis().to_string(),
+        };
+        let end = match to {
+            None => "+".to_string(),
+            Some(StreamPosition::Beginning) => return Ok(vec![]),
+            Some(StreamPosition::Id(id)) => id.clone(),
+            Some(StreamPosition::Time(at)) => format!("({}", at.timestamp_millis()),
+        };
+        let client = &self.client;
+        let entries = redis_op!(
+            client,
+            cmd("XRANGE")
+                .arg(topic)
+                .arg(start)
+                .arg(end)
+                .arg("COUNT")
+                .arg(count),
+            Value
+        )?;
+        entries
+            .as_sequence()
             .into_iter()
-            .filter(|s| topic_names.contains(s.topic().as_str()))
-        {
-            subscribers.push(self.subscribe(subscriber, app_state.clone()).await?)
+            .flatten()
+            .map(|entry| Self::parse_entry(entry).ok_or(UtilError::RedisStreamParams))
+            .collect()
+    }
+
+    /// Topics added or subscribed to through the broker, streams written to directly
+    /// are not listed
+    async fn list_topics(&self) -> Result<Vec<TopicInfo>, UtilError> {
+        let client = &self.client;
+        let mut names = redis_op!(client, cmd("SMEMBERS").arg(TOPICS), Vec<String>)?;
+        names.sort();
+        let mut topics = vec![];
+        for name in names {
+            let length = redis_op!(client, cmd("XLEN").arg(&name), u64)?;
+            topics.push(TopicInfo { name, length });
         }
-        Ok(subscribers)
+        Ok(topics)
     }
 
-    ///Not required for redis streams but valuable for other message brokers
-    async fn topic_exists(&self, _topic: &str) -> Result<bool, UtilError> {
-        Ok(true)
+    async fn list_groups(&self, topic: &str) -> Result<Vec<GroupInfo>, UtilError> {
+        let client = &self.client;
+        let groups = redis_op!(client, cmd("XINFO").arg("GROUPS").arg(topic), Value)?;
+        let mut infos = vec![];
+        for group in groups.as_sequence().into_iter().flatten() {
+            let fields = value_fields(group);
+            let name = fields
+                .get("name")
+                .and_then(value_string)
+                .unwrap_or_default();
+            let pending = fields
+                .get("pending")
+                .and_then(value_u64)
+                .unwrap_or_default();
+            let oldest_pending_ms = if pending > 0 {
+                // summary form returns [count, smallest id, greatest id, consumers]
+                let summary = redis_op!(client, cmd("XPENDING").arg(topic).arg(&name), Value)?;
+                summary
+                    .as_sequence()
+                    .and_then(|s| s.get(1))
+                    .and_then(value_string)
+                    .and_then(|id| id.split('-').next()?.parse::<u64>().ok())
+                    .map(age_ms)
+            } else {
+                None
+            };
+            infos.push(GroupInfo {
+                consumers: fields
+                    .get("consumers")
+                    .and_then(value_u64)
+                    .unwrap_or_default(),
+                pending,
+                lag: fields.get("lag").and_then(value_u64),
+                oldest_pending_ms,
+                name,
+            });
+        }
+        Ok(infos)
     }
 
-    ///Not required for redis streams but valuable for other message brokers
-    async fn add_topic(&self, _topic: &str) -> Result<(), UtilError> {
-        Ok(())
+    async fn list_consumers(
+        &self,
+        topic: &str,
+        group: &str,
+    ) -> Result<Vec<ConsumerInfo>, UtilError> {
+        let client = &self.client;
+        let consumers = redis_op!(
+            client,
+            cmd("XINFO").arg("CONSUMERS").arg(topic).arg(group),
+            Value
+        )?;
+        Ok(consumers
+            .as_sequence()
+            .into_iter()
+            .flatten()
+            .map(|consumer| {
+                let fields = value_fields(consumer);
+                ConsumerInfo {
+                    name: fields
+                        .get("name")
+                        .and_then(value_string)
+                        .unwrap_or_default(),
+                    pending: fields
+                        .get("pending")
+                        .and_then(value_u64)
+                        .unwrap_or_default(),
+                    idle_ms: fields.get("idle").and_then(value_u64).unwrap_or_default(),
+                }
+            })
+            .collect())
     }
+}
 
-    ///Not required for redis streams but valuable for other message brokers
-    async fn remove_queue(&self, _topic: &str) -> Result<(), UtilError> {
-        Ok(())
+/// Fields of an `XINFO` reply entry, a flat list of name value pairs or a map under RESP3
+fn value_fields(value: &Value) -> HashMap<String, Value> {
+    match value {
+        Value::Map(pairs) => pairs
+            .iter()
+            .filter_map(|(k, v)| Some((value_string(k)?, v.clone())))
+            .collect(),
+        _ => value
+            .as_sequence()
+            .map(|fields| {
+                fields
+                    .chunks(2)
+                    .filter_map(|pair| Some((value_string(pair.first()?)?, pair.get(1)?.clone())))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+fn value_string(value: &Value) -> Option<String> {
+    match value {
+        Value::BulkString(data) => String::from_utf8(data.clone()).ok(),
+        Value::SimpleString(data) => Some(data.clone()),
+        _ => None,
+    }
+}
+
+fn value_u64(value: &Value) -> Option<u64> {
+    match value {
+        Value::Int(i) => u64::try_from(*i).ok(),
+        _ => value_string(value)?.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use to_params::{FromParams, ToParams};
+    use util::tests::get_test_env;
+
+    #[derive(Debug, Default, PartialEq, ToParams, FromParams)]
+    struct TestMessage {
+        id: String,
+    }
+
+    #[tokio::test]
+    async fn moves_due_scheduled_messages_once() {
+        let broker = RedisStream::new(&get_test_env()).await.unwrap();
+        let topic = format!("scheduled-{}", Uuid::new_v4());
+        let due = Uuid::new_v4().to_string();
+        let later = Uuid::new_v4().to_string();
+        broker.create_group(&topic, "group").await.unwrap();
+        let message = TestMessage {
+            id: "due".to_string(),
+        };
+        broker
+            .publish_after(&topic, &due, Duration::ZERO, &message)
+            .await
+            .unwrap();
+        broker
+            .publish_after(&topic, &later, Duration::from_secs(60), &message)
+            .await
+            .unwrap();
+
+        assert!(broker.move_due().await.unwrap() >= 1);
+        assert!(!broker.cancel_scheduled(&due).await.unwrap());
+        let delivery = broker
+            .read_group(&topic, "group", "consumer")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(delivery.message_id.as_deref(), Some(due.as_str()));
+        assert_eq!(TestMessage::from_params(delivery.params), message);
+        assert!(broker.cancel_scheduled(&later).await.unwrap());
+        broker.remove_queue(&topic).await.unwrap();
     }
 }
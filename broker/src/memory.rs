@@ -0,0 +1,907 @@
+use crate::{
+    admin::{age_ms, ConsumerInfo, GroupInfo, TopicInfo},
+    consume, BrokerLayer, Delivery, RetryPolicy, ScheduledMessage, StreamPosition, Subscriber,
+    READ_BLOCK_MS, SCHEDULED_BATCH,
+};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::{
+    sync::Notify,
+    task::{self, JoinHandle},
+};
+use util::{env::Env, error::UtilError, AppState, FromParams, RawParams, ToParams};
+
+#[derive(Debug, Clone)]
+struct PendingEntry {
+    consumer: String,
+    delivered_at: Instant,
+    attempts: u64,
+}
+
+#[derive(Debug, Default)]
+struct Group {
+    last_delivered: u64,
+    pending: BTreeMap<u64, PendingEntry>,
+    /// When each consumer last read from the group
+    consumers: HashMap<String, Instant>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    message_id: String,
+    params: Vec<String>,
+    published_ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct Stream {
+    last_id: u64,
+    entries: BTreeMap<u64, Entry>,
+    groups: HashMap<String, Group>,
+}
+
+/// Scheduled messages keyed by message id with their delivery time
+type Schedule = HashMap<String, (DateTime<Utc>, ScheduledMessage)>;
+
+/// In process broker with the same consumer group semantics as Redis streams.
+/// Message ids are sequential per topic and always delivered in order, which makes
+/// it suitable for tests and for running the api and subscribers in a single binary.
+/// Clones share the same streams
+#[derive(Clone)]
+pub struct MemoryBroker {
+    pub max_len: usize,
+    pub retry: RetryPolicy,
+    streams: Arc<Mutex<HashMap<String, Stream>>>,
+    scheduled: Arc<Mutex<Schedule>>,
+    notify: Arc<Notify>,
+}
+
+impl MemoryBroker {
+    pub fn with_retry_policy(retry: RetryPolicy) -> Self {
+        Self {
+            max_len: 1000,
+            retry,
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            scheduled: Arc::new(Mutex::new(HashMap::new())),
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    fn streams(&self) -> MutexGuard<'_, HashMap<String, Stream>> {
+        self.streams
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn scheduled(&self) -> MutexGuard<'_, Schedule> {
+        self.scheduled
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Ids of messages delivered to the group but not yet acknowledged, oldest first
+    pub fn pending(&self, topic: &str, group: &str) -> Vec<String> {
+        self.streams()
+            .get(topic)
+            .and_then(|s| s.groups.get(group))
+            .map(|g| g.pending.keys().map(|id| id.to_string()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Number of messages currently retained for the topic
+    pub fn len(&self, topic: &str) -> usize {
+        self.streams()
+            .get(topic)
+            .map(|s| s.entries.len())
+            .unwrap_or_default()
+    }
+
+    pub fn is_empty(&self, topic: &str) -> bool {
+        self.len(topic) == 0
+    }
+
+    /// Sequence number the position falls after
+    fn position_id(stream: &Stream, position: &StreamPosition) -> Result<u64, UtilError> {
+        Ok(match position {
+            StreamPosition::Beginning => 0,
+            StreamPosition::Id(id) => id
+                .parse::<u64>()
+                .map_err(|_| UtilError::Other(format!("Invalid message id {id}")))?,
+            StreamPosition::Time(at) => {
+                let at_ms = at.timestamp_millis().max(0) as u64;
+                stream
+                    .entries
+                    .iter()
+                    .find(|(_, entry)| entry.published_ms >= at_ms)
+                    .map(|(id, _)| id - 1)
+                    .unwrap_or(stream.last_id)
+            }
+        })
+    }
+
+    fn try_read(
+        &self,
+        topic: &str,
+        group: &str,
+        consumer: &str,
+    ) -> Result<Option<Delivery>, UtilError> {
+        let mut streams = self.streams();
+        let stream = streams
+            .get_mut(topic)
+            .ok_or_else(|| UtilError::Other(format!("No such topic {topic}")))?;
+        let entries = &stream.entries;
+        let group_state = stream
+            .groups
+            .get_mut(group)
+            .ok_or_else(|| UtilError::Other(format!("No group {group} on topic {topic}")))?;
+
+        // messages trimmed from the stream can no longer be redelivered
+        group_state.pending.retain(|id, _| entries.contains_key(id));
+
+        let now = Instant::now();
+        group_state.consumers.insert(consumer.to_owned(), now);
+        if let Some((id, pending)) = group_state
+            .pending
+            .iter_mut()
+            .find(|(_, p)| now.duration_since(p.delivered_at) >= self.retry.retry_after)
+        {
+            pending.consumer = consumer.to_owned();
+            pending.delivered_at = now;
+            pending.attempts += 1;
+            let entry = entries[id].clone();
+            return Ok(Some(Delivery {
+                id: id.to_string(),
+                message_id: Some(entry.message_id),
+                params: entry.params,
+                attempts: pending.attempts,
+            }));
+        }
+
+        let Some((id, entry)) = entries
+            .range(group_state.last_delivered + 1..)
+            .next()
+            .map(|(id, entry)| (*id, entry.clone()))
+        else {
+            return Ok(None);
+        };
+
+        group_state.last_delivered = id;
+        group_state.pending.insert(
+            id,
+            PendingEntry {
+                consumer: consumer.to_owned(),
+                delivered_at: now,
+                attempts: 1,
+            },
+        );
+        Ok(Some(Delivery {
+            id: id.to_string(),
+            message_id: Some(entry.message_id),
+            params: entry.params,
+            attempts: 1,
+        }))
+    }
+}
+
+impl BrokerLayer for MemoryBroker {
+    async fn new(env: &Env) -> Result<Self, UtilError> {
+        let mut broker = Self::with_retry_policy(RetryPolicy::from_env(env));
+        if let Some(max_len) = env
+            .broker
+            .as_ref()
+            .and_then(|b| b.stream_len.as_ref())
+            .and_then(|l| l.parse::<usize>().ok())
+        {
+            broker.max_len = max_len;
+        }
+        Ok(broker)
+    }
+
+    async fn publish_with_id(
+        &self,
+        topic: &str,
+        message_id: &str,
+        message: &(impl FromParams + ToParams),
+    ) -> Result<(), UtilError> {
+        {
+            let mut streams = self.streams();
+            let stream = streams.entry(topic.to_owned()).or_default();
+            stream.last_id += 1;
+            stream.entries.insert(
+                stream.last_id,
+                Entry {
+                    message_id: message_id.to_owned(),
+                    params: message.to_params(),
+                    published_ms: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_millis() as u64)
+                        .unwrap_or_default(),
+                },
+            );
+            while stream.entries.len() > self.max_len {
+                stream.entries.pop_first();
+            }
+        }
+        self.notify.notify_waiters();
+        Ok(())
+    }
+
+    async fn publish_at(
+        &self,
+        topic: &str,
+        message_id: &str,
+        at: DateTime<Utc>,
+        message: &(impl FromParams + ToParams),
+    ) -> Result<(), UtilError> {
+        self.scheduled().insert(
+            message_id.to_owned(),
+            (
+                at,
+                ScheduledMessage {
+                    topic: topic.to_owned(),
+                    params: message.to_params(),
+                },
+            ),
+        );
+        Ok(())
+    }
+
+    async fn cancel_scheduled(&self, message_id: &str) -> Result<bool, UtilError> {
+        Ok(self.scheduled().remove(message_id).is_some())
+    }
+
+    async fn move_due(&self) -> Result<usize, UtilError> {
+        let due = {
+            let mut scheduled = self.scheduled();
+            let now = Utc::now();
+            let mut due = scheduled
+                .iter()
+                .filter(|(_, (at, _))| *at <= now)
+                .map(|(id, (at, _))| (*at, id.clone()))
+                .collect::<Vec<_>>();
+            due.sort();
+            due.truncate(SCHEDULED_BATCH);
+            due.into_iter()
+                .filter_map(|(_, id)| scheduled.remove(&id).map(|(_, message)| (id, message)))
+                .collect::<Vec<_>>()
+        };
+        let moved = due.len();
+        for (message_id, message) in due {
+            self.publish_with_id(&message.topic, &message_id, &RawParams(message.params))
+                .await?;
+        }
+        Ok(moved)
+    }
+
+    async fn subscribe(
+        &self,
+        subscriber: impl Subscriber + 'static,
+        app_state: Arc<impl AppState + Clone + std::marker::Sync + std::marker::Send + 'static>,
+    ) -> Result<JoinHandle<Result<(), UtilError>>, UtilError> {
+        Ok(task::spawn(consume(self.clone(), subscriber, app_state)))
+    }
+
+    async fn create_group(&self, topic: &str, group: &str) -> Result<(), UtilError> {
+        let mut streams = self.streams();
+        let stream = streams.entry(topic.to_owned()).or_default();
+        let last_id = stream.last_id;
+        stream
+            .groups
+            .entry(group.to_owned())
+            .or_insert_with(|| Group {
+                last_delivered: last_id,
+                ..Group::default()
+            });
+        Ok(())
+    }
+
+    async fn read_group(
+        &self,
+        topic: &str,
+        group: &str,
+        consumer: &str,
+    ) -> Result<Option<Delivery>, UtilError> {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        if let Some(delivery) = self.try_read(topic, group, consumer)? {
+            return Ok(Some(delivery));
+        }
+
+        let wait = self
+            .retry
+            .retry_after
+            .min(Duration::from_millis(READ_BLOCK_MS));
+        let _ = tokio::time::timeout(wait, notified).await;
+        self.try_read(topic, group, consumer)
+    }
+
+    async fn ack(&self, topic: &str, group: &str, id: &str) -> Result<(), UtilError> {
+        let id = id
+            .parse::<u64>()
+            .map_err(|_| UtilError::Other(format!("Invalid message id {id}")))?;
+        if let Some(group_state) = self
+            .streams()
+            .get_mut(topic)
+            .and_then(|s| s.groups.get_mut(group))
+        {
+            group_state.pending.remove(&id);
+        }
+        Ok(())
+    }
+
+    fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    async fn topic_exists(&self, topic: &str) -> Result<bool, UtilError> {
+        Ok(self.streams().contains_key(topic))
+    }
+
+    async fn add_topic(&self, topic: &str) -> Result<(), UtilError> {
+        self.streams().entry(topic.to_owned()).or_default();
+        Ok(())
+    }
+
+    async fn remove_queue(&self, topic: &str) -> Result<(), UtilError> {
+        self.streams().remove(topic);
+        Ok(())
+    }
+
+    async fn reset_group(
+        &self,
+        topic: &str,
+        group: &str,
+        position: &StreamPosition,
+    ) -> Result<(), UtilError> {
+        let mut streams = self.streams();
+        let stream = streams
+            .get_mut(topic)
+            .ok_or_else(|| UtilError::Other(format!("No such topic {topic}")))?;
+        let last_delivered = Self::position_id(stream, position)?;
+        let group_state = stream
+            .groups
+            .get_mut(group)
+            .ok_or_else(|| UtilError::Other(format!("No group {group} on topic {topic}")))?;
+        group_state.last_delivered = last_delivered;
+        drop(streams);
+        self.notify.notify_waiters();
+        Ok(())
+    }
+
+    async fn read_range(
+        &self,
+        topic: &str,
+        from: &StreamPosition,
+        to: Option<&StreamPosition>,
+        count: usize,
+    ) -> Result<Vec<Delivery>, UtilError> {
+        let streams = self.streams();
+        let Some(stream) = streams.get(topic) else {
+            return Ok(vec![]);
+        };
+        let after = Self::position_id(stream, from)?;
+        let until = match to {
+            Some(to) => Self::position_id(stream, to)?,
+            None => stream.last_id,
+        };
+        Ok(stream
+            .entries
+            .range(after + 1..)
+            .take_while(|(id, _)| **id <= until)
+            .take(count)
+            .map(|(id, entry)| Delivery {
+                id: id.to_string(),
+                message_id: Some(entry.message_id.clone()),
+                params: entry.params.clone(),
+                attempts: 1,
+            })
+            .collect())
+    }
+
+    async fn list_topics(&self) -> Result<Vec<TopicInfo>, UtilError> {
+        let mut topics = self
+            .streams()
+            .iter()
+            .map(|(name, stream)| TopicInfo {
+                name: name.clone(),
+                length: stream.entries.len() as u64,
+            })
+            .collect::<Vec<_>>();
+        topics.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(topics)
+    }
+
+    async fn list_groups(&self, topic: &str) -> Result<Vec<GroupInfo>, UtilError> {
+        let streams = self.streams();
+        let Some(stream) = streams.get(topic) else {
+            return Ok(vec![]);
+        };
+        let mut groups = stream
+            .groups
+            .iter()
+            .map(|(name, group)| GroupInfo {
+                name: name.clone(),
+                consumers: group.consumers.len() as u64,
+                pending: group.pending.len() as u64,
+                lag: Some(stream.entries.range(group.last_delivered + 1..).count() as u64),
+                oldest_pending_ms: group
+                    .pending
+                    .keys()
+                    .next()
+                    .and_then(|id| stream.entries.get(id))
+                    .map(|entry| age_ms(entry.published_ms)),
+            })
+            .collect::<Vec<_>>();
+        groups.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(groups)
+    }
+
+    async fn list_consumers(
+        &self,
+        topic: &str,
+        group: &str,
+    ) -> Result<Vec<ConsumerInfo>, UtilError> {
+        let streams = self.streams();
+        let Some(group) = streams.get(topic).and_then(|s| s.groups.get(group)) else {
+            return Ok(vec![]);
+        };
+        let mut consumers = group
+            .consumers
+            .iter()
+            .map(|(name, last_seen)| ConsumerInfo {
+                name: name.clone(),
+                pending: group
+                    .pending
+                    .values()
+                    .filter(|p| &p.consumer == name)
+                    .count() as u64,
+                idle_ms: last_seen.elapsed().as_millis() as u64,
+            })
+            .collect::<Vec<_>>();
+        consumers.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(consumers)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{metrics, Dedup};
+    use sqlx::PgConnection;
+    use to_params::{FromParams, ToParams};
+    use tokio::sync::mpsc;
+    use util::tests::MemoryApiState;
+    use uuid::Uuid;
+
+    #[derive(Debug, Default, PartialEq, ToParams, FromParams)]
+    struct TestMessage {
+        id: String,
+    }
+
+    fn message(id: &str) -> TestMessage {
+        TestMessage { id: id.to_owned() }
+    }
+
+    #[tokio::test]
+    async fn delivers_in_order_to_each_group() {
+        let broker = MemoryBroker::with_retry_policy(RetryPolicy::default());
+        broker.publish("topic", &message("before")).await.unwrap();
+        broker.create_group("topic", "a").await.unwrap();
+        broker.create_group("topic", "b").await.unwrap();
+        broker.publish("topic", &message("first")).await.unwrap();
+        broker.publish("topic", &message("second")).await.unwrap();
+
+        for group in ["a", "b"] {
+            for expected in ["first", "second"] {
+                let delivery = broker
+                    .read_group("topic", group, "consumer")
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(TestMessage::from_params(delivery.params), message(expected));
+            }
+        }
+        assert_eq!(broker.pending("topic", "a"), vec!["2", "3"]);
+    }
+
+    #[tokio::test]
+    async fn redelivers_unacked_messages() {
+        let broker = MemoryBroker::with_retry_policy(RetryPolicy {
+            retry_after: Duration::ZERO,
+            max_attempts: 5,
+        });
+        broker.create_group("topic", "group").await.unwrap();
+        broker.publish("topic", &message("retried")).await.unwrap();
+
+        let first = broker
+            .read_group("topic", "group", "consumer")
+            .await
+            .unwrap()
+            .unwrap();
+        let second = broker
+            .read_group("topic", "group", "consumer")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.id, second.id);
+        assert_eq!(second.attempts, 2);
+
+        broker.ack("topic", "group", &second.id).await.unwrap();
+        assert!(broker.pending("topic", "group").is_empty());
+        assert_eq!(
+            broker
+                .read_group("topic", "group", "consumer")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_published_message_id() {
+        let broker = MemoryBroker::with_retry_policy(RetryPolicy::default());
+        broker.create_group("topic", "group").await.unwrap();
+        broker
+            .publish_with_id("topic", "outbox-1", &message("first"))
+            .await
+            .unwrap();
+        broker
+            .publish_with_id("topic", "outbox-1", &message("first"))
+            .await
+            .unwrap();
+
+        let first = broker
+            .read_group("topic", "group", "consumer")
+            .await
+            .unwrap()
+            .unwrap();
+        let second = broker
+            .read_group("topic", "group", "consumer")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(first.id, second.id);
+        assert_eq!(first.message_id.as_deref(), Some("outbox-1"));
+        assert_eq!(first.message_id, second.message_id);
+    }
+
+    #[tokio::test]
+    async fn moves_due_scheduled_messages() {
+        let broker = MemoryBroker::with_retry_policy(RetryPolicy::default());
+        broker.create_group("topic", "group").await.unwrap();
+        broker
+            .publish_after("topic", "due", Duration::ZERO, &message("due"))
+            .await
+            .unwrap();
+        broker
+            .publish_after("topic", "later", Duration::from_secs(60), &message("later"))
+            .await
+            .unwrap();
+        broker
+            .publish_after("topic", "cancelled", Duration::ZERO, &message("cancelled"))
+            .await
+            .unwrap();
+
+        assert!(broker.cancel_scheduled("cancelled").await.unwrap());
+        assert!(!broker.cancel_scheduled("cancelled").await.unwrap());
+        assert_eq!(broker.move_due().await.unwrap(), 1);
+        assert_eq!(broker.len("topic"), 1);
+
+        let delivery = broker
+            .read_group("topic", "group", "consumer")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(delivery.message_id.as_deref(), Some("due"));
+        assert_eq!(TestMessage::from_params(delivery.params), message("due"));
+        assert!(broker.cancel_scheduled("later").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn reports_group_state() {
+        let broker = MemoryBroker::with_retry_policy(RetryPolicy::default());
+        broker.create_group("topic", "group").await.unwrap();
+        broker.publish("topic", &message("first")).await.unwrap();
+        broker.publish("topic", &message("second")).await.unwrap();
+        broker
+            .read_group("topic", "group", "consumer")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            broker.list_topics().await.unwrap(),
+            vec![TopicInfo {
+                name: "topic".to_string(),
+                length: 2
+            }]
+        );
+        let groups = broker.list_groups("topic").await.unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].consumers, 1);
+        assert_eq!(groups[0].pending, 1);
+        assert_eq!(groups[0].lag, Some(1));
+        assert!(groups[0].oldest_pending_ms.is_some());
+        let consumers = broker.list_consumers("topic", "group").await.unwrap();
+        assert_eq!(consumers[0].name, "consumer");
+        assert_eq!(consumers[0].pending, 1);
+    }
+
+    #[tokio::test]
+    async fn resets_group_and_reads_range() {
+        let broker = MemoryBroker::with_retry_policy(RetryPolicy::default());
+        broker.create_group("topic", "group").await.unwrap();
+        for id in ["first", "second", "third"] {
+            broker.publish("topic", &message(id)).await.unwrap();
+        }
+
+        let range = broker
+            .read_range(
+                "topic",
+                &StreamPosition::Id("1".to_string()),
+                Some(&StreamPosition::Id("2".to_string())),
+                10,
+            )
+            .await
+            .unwrap();
+        assert_eq!(range.len(), 1);
+        assert_eq!(
+            TestMessage::from_params(range[0].params.clone()),
+            message("second")
+        );
+
+        for _ in 0..3 {
+            let delivery = broker
+                .read_group("topic", "group", "consumer")
+                .await
+                .unwrap()
+                .unwrap();
+            broker.ack("topic", "group", &delivery.id).await.unwrap();
+        }
+        broker
+            .reset_group("topic", "group", &StreamPosition::Beginning)
+            .await
+            .unwrap();
+        let delivery = broker
+            .read_group("topic", "group", "consumer")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(TestMessage::from_params(delivery.params), message("first"));
+    }
+
+    /// Sends the ids it handles, failing the first attempt at ids starting with `fail`
+    struct Recorder {
+        group: String,
+        dedup: Dedup,
+        handled: mpsc::UnboundedSender<String>,
+        failed: Mutex<Vec<String>>,
+    }
+
+    impl Recorder {
+        fn new(dedup: Dedup) -> (Self, mpsc::UnboundedReceiver<String>) {
+            let (handled, received) = mpsc::unbounded_channel();
+            let recorder = Self {
+                group: format!("recorder-{}", Uuid::new_v4()),
+                dedup,
+                handled,
+                failed: Mutex::default(),
+            };
+            (recorder, received)
+        }
+    }
+
+    impl Subscriber for Recorder {
+        type MessageType = TestMessage;
+
+        fn handle_message(
+            &self,
+            message: TestMessage,
+            _app_state: Arc<impl AppState>,
+        ) -> impl std::future::Future<Output = Result<(), UtilError>> + Send {
+            let mut failed = self.failed.lock().unwrap();
+            let result = if message.id.starts_with("fail") && !failed.contains(&message.id) {
+                failed.push(message.id);
+                Err(UtilError::Other("first attempt".to_string()))
+            } else {
+                self.handled
+                    .send(message.id)
+                    .map_err(|e| UtilError::Other(e.to_string()))
+            };
+            async move { result }
+        }
+
+        /// Fails unless the dedup record of the group is visible through `conn`. Ids starting
+        /// with `uncommittable` write a row that violates a deferred constraint, so the
+        /// transaction fails to commit
+        fn handle_message_tx(
+            &self,
+            message: TestMessage,
+            conn: &mut PgConnection,
+            app_state: Arc<impl AppState>,
+        ) -> impl std::future::Future<Output = Result<(), UtilError>> + Send {
+            let group = self.group.clone();
+            let id = message.id.clone();
+            let handled = self.handle_message(message, app_state);
+            async move {
+                let (records,): (i64,) =
+                    sqlx::query_as("SELECT count(*) FROM processed_messages WHERE group_name = $1")
+                        .bind(group)
+                        .fetch_one(&mut *conn)
+                        .await?;
+                if records == 0 {
+                    return Err(UtilError::Other("no dedup record".to_string()));
+                }
+                if id.starts_with("uncommittable") {
+                    sqlx::query(
+                        "CREATE TEMP TABLE uncommittable (
+                            id int UNIQUE DEFERRABLE INITIALLY DEFERRED
+                        ) ON COMMIT DROP",
+                    )
+                    .execute(&mut *conn)
+                    .await?;
+                    sqlx::query("INSERT INTO uncommittable VALUES (1), (1)")
+                        .execute(&mut *conn)
+                        .await?;
+                }
+                handled.await
+            }
+        }
+
+        fn topic(&self) -> String {
+            "topic".to_string()
+        }
+
+        fn group_name(&self) -> String {
+            self.group.clone()
+        }
+
+        fn dedup(&self) -> Dedup {
+            self.dedup.clone()
+        }
+    }
+
+    fn retrying_broker() -> MemoryBroker {
+        MemoryBroker::with_retry_policy(RetryPolicy {
+            retry_after: Duration::ZERO,
+            max_attempts: 3,
+        })
+    }
+
+    /// The next `count` ids the recorder handles, sorted
+    async fn handled_ids(
+        received: &mut mpsc::UnboundedReceiver<String>,
+        count: usize,
+    ) -> Vec<String> {
+        let mut ids = Vec::new();
+        while ids.len() < count {
+            let id = tokio::time::timeout(Duration::from_secs(5), received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            ids.push(id);
+        }
+        ids.sort();
+        ids
+    }
+
+    /// Publishes a message twice with one id, the first attempt at it fails, and checks
+    /// it is handled once and the repeat counted as a duplicate
+    async fn skips_duplicates(dedup: Dedup) {
+        let broker = retrying_broker();
+        let (subscriber, mut received) = Recorder::new(dedup);
+        let group = subscriber.group_name();
+        let state = Arc::new(MemoryApiState::from_test_env().await.unwrap());
+        broker.create_group("topic", &group).await.unwrap();
+        let consumer = task::spawn(consume(broker.clone(), subscriber, state));
+        broker
+            .publish_with_id("topic", "repeated", &message("fail-repeated"))
+            .await
+            .unwrap();
+        broker
+            .publish_with_id("topic", "repeated", &message("fail-repeated"))
+            .await
+            .unwrap();
+        broker.publish("topic", &message("last")).await.unwrap();
+
+        assert_eq!(
+            handled_ids(&mut received, 2).await,
+            vec!["fail-repeated", "last"]
+        );
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !broker.pending("topic", &group).is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        consumer.abort();
+        assert!(received.try_recv().is_err());
+        let counters = metrics::snapshot()
+            .into_iter()
+            .find(|c| c.group == group)
+            .unwrap();
+        assert_eq!(counters.duplicates, 1);
+        assert_eq!(counters.failed, 1);
+    }
+
+    #[tokio::test]
+    async fn skips_duplicates_with_redis_dedup() {
+        skips_duplicates(Dedup::Redis { ttl: 60 }).await;
+    }
+
+    #[tokio::test]
+    async fn skips_duplicates_with_postgres_dedup() {
+        skips_duplicates(Dedup::Postgres).await;
+    }
+
+    #[tokio::test]
+    async fn keeps_message_when_commit_fails() {
+        let broker = MemoryBroker::with_retry_policy(RetryPolicy {
+            retry_after: Duration::from_secs(60),
+            max_attempts: 3,
+        });
+        let (subscriber, mut received) = Recorder::new(Dedup::Postgres);
+        let group = subscriber.group_name();
+        let state = Arc::new(MemoryApiState::from_test_env().await.unwrap());
+        broker.create_group("topic", &group).await.unwrap();
+        let consumer = task::spawn(consume(broker.clone(), subscriber, state.clone()));
+        broker
+            .publish_with_id("topic", "uncommittable", &message("uncommittable"))
+            .await
+            .unwrap();
+
+        assert_eq!(handled_ids(&mut received, 1).await, vec!["uncommittable"]);
+        let counters = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let counters = metrics::snapshot().into_iter().find(|c| c.group == group);
+                match counters {
+                    Some(counters) if counters.failed == 1 => return counters,
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        consumer.abort();
+        assert_eq!(counters.processed, 0);
+        assert_eq!(broker.pending("topic", &group).len(), 1);
+        let (records,): (i64,) =
+            sqlx::query_as("SELECT count(*) FROM processed_messages WHERE group_name = $1")
+                .bind(&group)
+                .fetch_one(state.rw_db.get_conn())
+                .await
+                .unwrap();
+        assert_eq!(records, 0);
+    }
+
+    #[tokio::test]
+    async fn subscriber_consumes_and_retries() {
+        let broker = retrying_broker();
+        let (subscriber, mut received) = Recorder::new(Dedup::None);
+        let group = subscriber.group_name();
+        let state = Arc::new(MemoryApiState::from_test_env().await.unwrap());
+        broker.create_group("topic", &group).await.unwrap();
+        let consumer = task::spawn(consume(broker.clone(), subscriber, state));
+        for id in ["first", "failing", "last"] {
+            broker.publish("topic", &message(id)).await.unwrap();
+        }
+
+        assert_eq!(
+            handled_ids(&mut received, 3).await,
+            vec!["failing", "first", "last"]
+        );
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !broker.pending("topic", &group).is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        consumer.abort();
+    }
+}
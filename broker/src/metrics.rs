@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tokio::task::{self, JoinHandle};
+use util::error::UtilError;
+
+/// Counters keyed by topic and group
+type CounterMap = HashMap<(String, String), Arc<Counters>>;
+
+static COUNTERS: LazyLock<Mutex<CounterMap>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Running totals for a single topic and consumer group
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub processed: AtomicU64,
+    pub failed: AtomicU64,
+    pub duplicates: AtomicU64,
+    pub dropped: AtomicU64,
+}
+
+/// Point in time copy of `Counters`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CounterSnapshot {
+    pub topic: String,
+    pub group: String,
+    pub processed: u64,
+    pub failed: u64,
+    pub duplicates: u64,
+    pub dropped: u64,
+}
+
+/// Counters for the topic and group, created on first use
+pub fn counters(topic: &str, group: &str) -> Arc<Counters> {
+    COUNTERS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .entry((topic.to_owned(), group.to_owned()))
+        .or_default()
+        .clone()
+}
+
+/// Counters of every subscriber running in this process, ordered by topic then group
+pub fn snapshot() -> Vec<CounterSnapshot> {
+    let mut snapshot = COUNTERS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .iter()
+        .map(|((topic, group), c)| CounterSnapshot {
+            topic: topic.clone(),
+            group: group.clone(),
+            processed: c.processed.load(Ordering::Relaxed),
+            failed: c.failed.load(Ordering::Relaxed),
+            duplicates: c.duplicates.load(Ordering::Relaxed),
+            dropped: c.dropped.load(Ordering::Relaxed),
+        })
+        .collect::<Vec<_>>();
+    snapshot.sort_by(|a, b| (&a.topic, &a.group).cmp(&(&b.topic, &b.group)));
+    snapshot
+}
+
+/// Log the counters of every subscriber in this process each `interval` until the task is
+/// aborted
+pub fn start_logging(interval: Duration) -> JoinHandle<Result<(), UtilError>> {
+    task::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            for c in snapshot() {
+                log::info!(
+                    "Subscriber {} on {}: {} processed, {} failed, {} duplicates, {} dropped",
+                    c.group,
+                    c.topic,
+                    c.processed,
+                    c.failed,
+                    c.duplicates,
+                    c.dropped
+                );
+            }
+        }
+    })
+}
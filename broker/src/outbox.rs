@@ -0,0 +1,91 @@
+use crate::{Broker, BrokerLayer};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::task::{self, JoinHandle};
+use util::{error::UtilError, outbox::OutboxEntry, store::RWDB, RawParams};
+
+/// Publishes rows written to the `outbox` table through the broker and marks them sent.
+/// Delivery is at least once, a crash after publishing but before the batch commits
+/// publishes the batch again with the same message ids, so subscribers using `Dedup`
+/// skip the repeats. Only one relay works at a time across all replicas and an
+/// aggregate's entries are skipped for the rest of a batch once one of them fails, which
+/// keeps messages for the same aggregate in the order they were written
+#[derive(Clone)]
+pub struct OutboxRelay {
+    pub broker: Broker,
+    pub db: RWDB,
+    pub batch_size: i64,
+    pub poll_interval: Duration,
+}
+
+impl OutboxRelay {
+    pub fn new(broker: Broker, db: RWDB) -> Self {
+        Self {
+            broker,
+            db,
+            batch_size: 100,
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// Publish a single batch, returning how many entries were sent
+    pub async fn relay_batch(&self) -> Result<usize, UtilError> {
+        let mut tx = self.db.begin().await?;
+        let (locked,) = sqlx::query_as::<_, (bool,)>(
+            "SELECT pg_try_advisory_xact_lock(hashtext('outbox_relay'))",
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if !locked {
+            return Ok(0);
+        }
+
+        let entries = OutboxEntry::unsent(&mut tx, self.batch_size).await?;
+        let mut failed_aggregates = HashSet::new();
+        let mut sent = vec![];
+        for entry in entries {
+            let aggregate = (entry.aggregate_type, entry.aggregate_id);
+            if failed_aggregates.contains(&aggregate) {
+                continue;
+            }
+            match self
+                .broker
+                .publish_with_id(
+                    &entry.topic,
+                    &format!("outbox-{}", entry.id),
+                    &RawParams(entry.params.0),
+                )
+                .await
+            {
+                Ok(()) => sent.push(entry.id),
+                Err(e) => {
+                    log::error!(
+                        "Failed to relay outbox entry {} to {} {:?}",
+                        entry.id,
+                        entry.topic,
+                        e
+                    );
+                    failed_aggregates.insert(aggregate);
+                }
+            }
+        }
+
+        OutboxEntry::mark_sent(&mut tx, &sent).await?;
+        tx.commit().await?;
+        Ok(sent.len())
+    }
+
+    /// Relay continuously, polling again straight away while there is a backlog
+    pub fn start(self) -> JoinHandle<Result<(), UtilError>> {
+        task::spawn(async move {
+            loop {
+                match self.relay_batch().await {
+                    Ok(sent) if sent as i64 >= self.batch_size => continue,
+                    Ok(_) => {}
+                    Err(e) => log::error!("Outbox relay failed {:?}", e),
+                }
+                tokio::time::sleep(self.poll_interval).await;
+            }
+        })
+    }
+}
@@ -0,0 +1,588 @@
+use crate::{
+    admin::{ConsumerInfo, GroupInfo, TopicInfo},
+    consume, BrokerLayer, Delivery, RetryPolicy, StreamPosition, Subscriber, READ_BLOCK_MS,
+    SCHEDULED_BATCH,
+};
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgListener, types::Json, PgConnection};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{
+    sync::{Notify, OnceCell},
+    task::{self, JoinHandle},
+};
+use util::{env::Env, error::UtilError, store::RWDB, AppState, FromParams, ToParams};
+
+/// Channel used to wake up consumers when a message is published, the payload is the topic
+const NOTIFY_CHANNEL: &str = "broker_jobs";
+
+/// Broker backed by Postgres tables for deployments that do not want to run Redis.
+/// Every published message is fanned out as a job row per consumer group, consumers
+/// claim jobs with `FOR UPDATE SKIP LOCKED` and are woken up through `LISTEN/NOTIFY`
+#[derive(Clone)]
+pub struct PostgresQueue {
+    pub db: RWDB,
+    pub max_len: i64,
+    pub retry: RetryPolicy,
+    notify: Arc<Notify>,
+    listener: Arc<OnceCell<()>>,
+}
+
+impl PostgresQueue {
+    /// Start a single background `LISTEN` connection shared by every consumer of this queue
+    async fn listen(&self) {
+        self.listener
+            .get_or_init(|| async {
+                let db = self.db.clone();
+                let notify = self.notify.clone();
+                task::spawn(async move {
+                    loop {
+                        match PgListener::connect_with(db.get_conn()).await {
+                            Ok(mut listener) => {
+                                if let Err(e) = listener.listen(NOTIFY_CHANNEL).await {
+                                    log::error!("Postgres broker failed to listen {:?}", e);
+                                } else {
+                                    while listener.recv().await.is_ok() {
+                                        notify.notify_waiters();
+                                    }
+                                    log::error!("Postgres broker listener disconnected");
+                                }
+                            }
+                            Err(e) => log::error!("Postgres broker failed to connect {:?}", e),
+                        }
+                        // fall back to polling until the listener is back
+                        notify.notify_waiters();
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                });
+            })
+            .await;
+    }
+
+    async fn claim(
+        &self,
+        topic: &str,
+        group: &str,
+        consumer: &str,
+    ) -> Result<Option<Delivery>, UtilError> {
+        let claimed = sqlx::query_as::<_, (i64, Option<String>, Json<Vec<String>>, i32)>(
+            "UPDATE broker_jobs j SET attempts = j.attempts + 1, claimed_by = $3, claimed_at = now()
+            FROM broker_messages m
+            WHERE m.id = j.message_id AND j.id = (
+                SELECT id FROM broker_jobs
+                WHERE topic = $1 AND group_name = $2
+                AND (claimed_at IS NULL OR claimed_at < now() - make_interval(secs => $4))
+                ORDER BY id
+                FOR UPDATE SKIP LOCKED
+                LIMIT 1
+            )
+            RETURNING j.id, m.message_id, m.params, j.attempts",
+        )
+        .bind(topic)
+        .bind(group)
+        .bind(consumer)
+        .bind(self.retry.retry_after.as_secs_f64())
+        .fetch_optional(self.db.get_conn())
+        .await?;
+
+        Ok(claimed.map(|(id, message_id, params, attempts)| Delivery {
+            id: id.to_string(),
+            message_id,
+            params: params.0,
+            attempts: attempts as u64,
+        }))
+    }
+
+    /// Message id and time bounds of a position for use as nullable query params
+    fn bounds(
+        position: Option<&StreamPosition>,
+    ) -> Result<(Option<i64>, Option<DateTime<Utc>>), UtilError> {
+        Ok(match position {
+            None | Some(StreamPosition::Beginning) => (None, None),
+            Some(StreamPosition::Id(id)) => (
+                Some(id.parse::<i64>().map_err(|_| {
+                    UtilError::Other(format!("Invalid Postgres broker message id {id}"))
+                })?),
+                None,
+            ),
+            Some(StreamPosition::Time(at)) => (None, Some(*at)),
+        })
+    }
+
+    /// Store the message and fan it out to every group of the topic
+    async fn insert_message(
+        &self,
+        conn: &mut PgConnection,
+        topic: &str,
+        message_id: &str,
+        params: Vec<String>,
+    ) -> Result<(), UtilError> {
+        let (id,) = sqlx::query_as::<_, (i64,)>(
+            "INSERT INTO broker_messages (topic, message_id, params) VALUES ($1, $2, $3) RETURNING id",
+        )
+        .bind(topic)
+        .bind(message_id)
+        .bind(Json(params))
+        .fetch_one(&mut *conn)
+        .await?;
+
+        sqlx::query(
+            "INSERT INTO broker_jobs (topic, group_name, message_id)
+            SELECT topic, group_name, $2 FROM broker_groups WHERE topic = $1",
+        )
+        .bind(topic)
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+
+        // only messages every group is done with are trimmed, jobs are deleted when acked
+        sqlx::query(
+            "DELETE FROM broker_messages m WHERE m.topic = $1 AND m.id <= (
+                SELECT id FROM broker_messages WHERE topic = $1 ORDER BY id DESC OFFSET $2 LIMIT 1
+            )
+            AND NOT EXISTS (SELECT 1 FROM broker_jobs j WHERE j.message_id = m.id)",
+        )
+        .bind(topic)
+        .bind(self.max_len)
+        .execute(&mut *conn)
+        .await?;
+
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(NOTIFY_CHANNEL)
+            .bind(topic)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+}
+
+impl BrokerLayer for PostgresQueue {
+    async fn new(env: &Env) -> Result<Self, UtilError> {
+        Ok(Self {
+            db: RWDB::connect(env).await?,
+            max_len: env
+                .broker
+                .as_ref()
+                .and_then(|b| b.stream_len.as_ref())
+                .and_then(|l| l.parse::<i64>().ok())
+                .unwrap_or(1000),
+            retry: RetryPolicy::from_env(env),
+            notify: Arc::new(Notify::new()),
+            listener: Arc::new(OnceCell::new()),
+        })
+    }
+
+    async fn publish_with_id(
+        &self,
+        topic: &str,
+        message_id: &str,
+        message: &(impl FromParams + ToParams),
+    ) -> Result<(), UtilError> {
+        let mut tx = self.db.get_conn().begin().await?;
+        self.insert_message(&mut tx, topic, message_id, message.to_params())
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn publish_at(
+        &self,
+        topic: &str,
+        message_id: &str,
+        at: DateTime<Utc>,
+        message: &(impl FromParams + ToParams),
+    ) -> Result<(), UtilError> {
+        sqlx::query(
+            "INSERT INTO broker_scheduled (message_id, topic, params, deliver_at) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (message_id) DO UPDATE
+            SET topic = EXCLUDED.topic, params = EXCLUDED.params, deliver_at = EXCLUDED.deliver_at",
+        )
+        .bind(message_id)
+        .bind(topic)
+        .bind(Json(message.to_params()))
+        .bind(at)
+        .execute(self.db.get_conn())
+        .await?;
+        Ok(())
+    }
+
+    async fn cancel_scheduled(&self, message_id: &str) -> Result<bool, UtilError> {
+        let removed = sqlx::query("DELETE FROM broker_scheduled WHERE message_id = $1")
+            .bind(message_id)
+            .execute(self.db.get_conn())
+            .await?
+            .rows_affected();
+        Ok(removed > 0)
+    }
+
+    async fn move_due(&self) -> Result<usize, UtilError> {
+        let mut tx = self.db.get_conn().begin().await?;
+        let due = sqlx::query_as::<_, (String, String, Json<Vec<String>>)>(
+            "DELETE FROM broker_scheduled WHERE message_id IN (
+                SELECT message_id FROM broker_scheduled
+                WHERE deliver_at <= now()
+                ORDER BY deliver_at
+                FOR UPDATE SKIP LOCKED
+                LIMIT $1
+            )
+            RETURNING message_id, topic, params",
+        )
+        .bind(SCHEDULED_BATCH as i64)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let moved = due.len();
+        for (message_id, topic, params) in due {
+            self.insert_message(&mut tx, &topic, &message_id, params.0)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(moved)
+    }
+
+    async fn subscribe(
+        &self,
+        subscriber: impl Subscriber + 'static,
+        app_state: Arc<impl AppState + Clone + std::marker::Sync + std::marker::Send + 'static>,
+    ) -> Result<JoinHandle<Result<(), UtilError>>, UtilError> {
+        Ok(task::spawn(consume(self.clone(), subscriber, app_state)))
+    }
+
+    async fn create_group(&self, topic: &str, group: &str) -> Result<(), UtilError> {
+        sqlx::query(
+            "INSERT INTO broker_groups (topic, group_name) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(topic)
+        .bind(group)
+        .execute(self.db.get_conn())
+        .await?;
+        Ok(())
+    }
+
+    async fn read_group(
+        &self,
+        topic: &str,
+        group: &str,
+        consumer: &str,
+    ) -> Result<Option<Delivery>, UtilError> {
+        self.listen().await;
+        // register interest before claiming so a notification sent in between is not missed
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        if let Some(delivery) = self.claim(topic, group, consumer).await? {
+            return Ok(Some(delivery));
+        }
+
+        let _ = tokio::time::timeout(Duration::from_millis(READ_BLOCK_MS), notified).await;
+        self.claim(topic, group, consumer).await
+    }
+
+    async fn ack(&self, _topic: &str, _group: &str, id: &str) -> Result<(), UtilError> {
+        let id = id
+            .parse::<i64>()
+            .map_err(|_| UtilError::Other(format!("Invalid Postgres broker job id {id}")))?;
+        sqlx::query("DELETE FROM broker_jobs WHERE id = $1")
+            .bind(id)
+            .execute(self.db.get_conn())
+            .await?;
+        Ok(())
+    }
+
+    fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    async fn topic_exists(&self, topic: &str) -> Result<bool, UtilError> {
+        let (exists,) = sqlx::query_as::<_, (bool,)>(
+            "SELECT EXISTS(SELECT 1 FROM broker_topics WHERE topic = $1)
+            OR EXISTS(SELECT 1 FROM broker_groups WHERE topic = $1)
+            OR EXISTS(SELECT 1 FROM broker_messages WHERE topic = $1)",
+        )
+        .bind(topic)
+        .fetch_one(self.db.get_conn())
+        .await?;
+        Ok(exists)
+    }
+
+    /// Topics without groups or messages are only kept in `broker_topics`
+    async fn add_topic(&self, topic: &str) -> Result<(), UtilError> {
+        sqlx::query("INSERT INTO broker_topics (topic) VALUES ($1) ON CONFLICT DO NOTHING")
+            .bind(topic)
+            .execute(self.db.get_conn())
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_queue(&self, topic: &str) -> Result<(), UtilError> {
+        let mut tx = self.db.get_conn().begin().await?;
+        sqlx::query("DELETE FROM broker_groups WHERE topic = $1")
+            .bind(topic)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM broker_messages WHERE topic = $1")
+            .bind(topic)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM broker_topics WHERE topic = $1")
+            .bind(topic)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Replaces every job of the group, including claimed ones, with a job per retained
+    /// message after the position
+    async fn reset_group(
+        &self,
+        topic: &str,
+        group: &str,
+        position: &StreamPosition,
+    ) -> Result<(), UtilError> {
+        let (after_id, since) = Self::bounds(Some(position))?;
+        let mut tx = self.db.get_conn().begin().await?;
+        sqlx::query("DELETE FROM broker_jobs WHERE topic = $1 AND group_name = $2")
+            .bind(topic)
+            .bind(group)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO broker_jobs (topic, group_name, message_id)
+            SELECT topic, $2, id FROM broker_messages
+            WHERE topic = $1
+            AND ($3::bigint IS NULL OR id > $3)
+            AND ($4::timestamptz IS NULL OR created_at >= $4)
+            ORDER BY id",
+        )
+        .bind(topic)
+        .bind(group)
+        .bind(after_id)
+        .bind(since)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(NOTIFY_CHANNEL)
+            .bind(topic)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn read_range(
+        &self,
+        topic: &str,
+        from: &StreamPosition,
+        to: Option<&StreamPosition>,
+        count: usize,
+    ) -> Result<Vec<Delivery>, UtilError> {
+        let (after_id, since) = Self::bounds(Some(from))?;
+        let (until_id, before) = match to {
+            Some(StreamPosition::Beginning) => return Ok(vec![]),
+            to => Self::bounds(to)?,
+        };
+        let messages = sqlx::query_as::<_, (i64, Option<String>, Json<Vec<String>>)>(
+            "SELECT id, message_id, params FROM broker_messages
+            WHERE topic = $1
+            AND ($2::bigint IS NULL OR id > $2)
+            AND ($3::timestamptz IS NULL OR created_at >= $3)
+            AND ($4::bigint IS NULL OR id <= $4)
+            AND ($5::timestamptz IS NULL OR created_at < $5)
+            ORDER BY id LIMIT $6",
+        )
+        .bind(topic)
+        .bind(after_id)
+        .bind(since)
+        .bind(until_id)
+        .bind(before)
+        .bind(count as i64)
+        .fetch_all(self.db.get_conn())
+        .await?;
+        Ok(messages
+            .into_iter()
+            .map(|(id, message_id, params)| Delivery {
+                id: id.to_string(),
+                message_id,
+                params: params.0,
+                attempts: 1,
+            })
+            .collect())
+    }
+
+    async fn list_topics(&self) -> Result<Vec<TopicInfo>, UtilError> {
+        let topics = sqlx::query_as::<_, (String, i64)>(
+            "SELECT t.topic, count(m.id) FROM (
+                SELECT topic FROM broker_topics
+                UNION SELECT topic FROM broker_groups
+                UNION SELECT topic FROM broker_messages
+            ) t
+            LEFT JOIN broker_messages m ON m.topic = t.topic
+            GROUP BY t.topic ORDER BY t.topic",
+        )
+        .fetch_all(self.db.get_conn())
+        .await?;
+        Ok(topics
+            .into_iter()
+            .map(|(name, length)| TopicInfo {
+                name,
+                length: length as u64,
+            })
+            .collect())
+    }
+
+    /// Consumers are only known while they hold a claimed job, acknowledged jobs are deleted
+    async fn list_groups(&self, topic: &str) -> Result<Vec<GroupInfo>, UtilError> {
+        let groups = sqlx::query_as::<_, (String, i64, i64, i64, Option<i64>)>(
+            "SELECT g.group_name,
+                count(DISTINCT j.claimed_by),
+                count(j.id) FILTER (WHERE j.claimed_at IS NOT NULL),
+                count(j.id) FILTER (WHERE j.claimed_at IS NULL),
+                (extract(epoch FROM now() - min(m.created_at) FILTER (WHERE j.claimed_at IS NOT NULL)) * 1000)::bigint
+            FROM broker_groups g
+            LEFT JOIN broker_jobs j ON j.topic = g.topic AND j.group_name = g.group_name
+            LEFT JOIN broker_messages m ON m.id = j.message_id
+            WHERE g.topic = $1
+            GROUP BY g.group_name ORDER BY g.group_name",
+        )
+        .bind(topic)
+        .fetch_all(self.db.get_conn())
+        .await?;
+        Ok(groups
+            .into_iter()
+            .map(
+                |(name, consumers, pending, lag, oldest_pending_ms)| GroupInfo {
+                    name,
+                    consumers: consumers as u64,
+                    pending: pending as u64,
+                    lag: Some(lag as u64),
+                    oldest_pending_ms: oldest_pending_ms.map(|ms| ms as u64),
+                },
+            )
+            .collect())
+    }
+
+    async fn list_consumers(
+        &self,
+        topic: &str,
+        group: &str,
+    ) -> Result<Vec<ConsumerInfo>, UtilError> {
+        let consumers = sqlx::query_as::<_, (String, i64, i64)>(
+            "SELECT claimed_by, count(*),
+                (extract(epoch FROM now() - max(claimed_at)) * 1000)::bigint
+            FROM broker_jobs
+            WHERE topic = $1 AND group_name = $2 AND claimed_by IS NOT NULL
+            GROUP BY claimed_by ORDER BY claimed_by",
+        )
+        .bind(topic)
+        .bind(group)
+        .fetch_all(self.db.get_conn())
+        .await?;
+        Ok(consumers
+            .into_iter()
+            .map(|(name, pending, idle_ms)| ConsumerInfo {
+                name,
+                pending: pending as u64,
+                idle_ms: idle_ms.max(0) as u64,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use to_params::{FromParams, ToParams};
+    use util::tests::TestApiState;
+
+    #[derive(Debug, Default, PartialEq, ToParams, FromParams)]
+    struct TestMessage {
+        id: String,
+    }
+
+    #[tokio::test]
+    async fn publish_and_claim_job() {
+        let state = TestApiState::from_test_env().await.unwrap();
+        let queue = PostgresQueue::new(&state.env).await.unwrap();
+        let topic = format!("test_topic_{}", uuid::Uuid::new_v4());
+        queue.create_group(&topic, "test_group").await.unwrap();
+
+        let message = TestMessage {
+            id: "some id".to_string(),
+        };
+        queue
+            .publish_with_id(&topic, "message id", &message)
+            .await
+            .unwrap();
+
+        let delivery = queue
+            .read_group(&topic, "test_group", "test_consumer")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.message_id.as_deref(), Some("message id"));
+        assert_eq!(TestMessage::from_params(delivery.params), message);
+
+        queue.ack(&topic, "test_group", &delivery.id).await.unwrap();
+        queue.remove_queue(&topic).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn adds_empty_topic() {
+        let state = TestApiState::from_test_env().await.unwrap();
+        let queue = PostgresQueue::new(&state.env).await.unwrap();
+        let topic = format!("test_topic_{}", uuid::Uuid::new_v4());
+        queue.add_topic(&topic).await.unwrap();
+        queue.add_topic(&topic).await.unwrap();
+
+        assert!(queue.topic_exists(&topic).await.unwrap());
+        let topics = queue.list_topics().await.unwrap();
+        let added = topics.iter().find(|t| t.name == topic).unwrap();
+        assert_eq!(added.length, 0);
+        queue.remove_queue(&topic).await.unwrap();
+        assert!(!queue.topic_exists(&topic).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn trim_keeps_unconsumed_jobs() {
+        let state = TestApiState::from_test_env().await.unwrap();
+        let mut queue = PostgresQueue::new(&state.env).await.unwrap();
+        queue.max_len = 1;
+        let topic = format!("test_topic_{}", uuid::Uuid::new_v4());
+        queue.create_group(&topic, "test_group").await.unwrap();
+
+        for id in ["1", "2", "3"] {
+            let message = TestMessage { id: id.to_string() };
+            queue.publish_with_id(&topic, id, &message).await.unwrap();
+        }
+        for id in ["1", "2", "3"] {
+            let delivery = queue
+                .read_group(&topic, "test_group", "test_consumer")
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(delivery.message_id.as_deref(), Some(id));
+            queue.ack(&topic, "test_group", &delivery.id).await.unwrap();
+        }
+
+        // acked messages are trimmed by the next publish
+        let message = TestMessage {
+            id: "4".to_string(),
+        };
+        queue.publish_with_id(&topic, "4", &message).await.unwrap();
+        assert_eq!(
+            queue
+                .list_topics()
+                .await
+                .unwrap()
+                .iter()
+                .find(|t| t.name == topic)
+                .unwrap()
+                .length,
+            1
+        );
+        queue.remove_queue(&topic).await.unwrap();
+    }
+}
@@ -0,0 +1,366 @@
+use sqlx::{postgres::PgRow, Postgres, QueryBuilder, Row};
+use std::str::FromStr;
+use std::time::Duration;
+use util::{
+    cache::AppCache,
+    error::UtilError,
+    outbox::OutboxEntry,
+    store::{
+        Model, NewModel, Pagination, QueryCache, ToSqlQuery, ToSqlSort, UpdateModel, RODB, RWDB,
+    },
+    FromParams, RawParams, ToParams,
+};
+
+/// How long a materialize lock outlives a replica that stopped renewing it
+const MATERIALIZE_LOCK_TTL: Duration = Duration::from_secs(30);
+/// How long a materialize waits for another replica materializing the same rows
+const MATERIALIZE_LOCK_WAIT: Duration = Duration::from_secs(10);
+
+/// A table kept in step with a source view. Derive it with `#[derive(ReadModel)]` which also
+/// implements `Subscriber` so materialize messages published to `topic` refresh the rows
+/// matching the message's query
+#[allow(async_fn_in_trait)]
+pub trait ReadModel:
+    Model + NewModel + UpdateModel + for<'r> sqlx::FromRow<'r, PgRow> + Unpin + Clone + Sync
+{
+    /// Selects the rows to materialize, published as the message params
+    type Query: ToSqlQuery + Pagination + ToSqlSort + FromParams + ToParams + Send + Sync;
+    /// Type of the key column, parsed from its text form to repair rows by key
+    type Key: FromStr;
+
+    /// View the rows are selected from, it must have the same columns as the table
+    fn source_view() -> String;
+    /// Column uniquely identifying a row in both the view and the table
+    fn key_column() -> String;
+    fn topic() -> String;
+    /// Query selecting only the row with the given key, the message that refreshes it
+    fn key_query(key: Self::Key) -> Self::Query;
+
+    fn source_select() -> String {
+        format!(
+            "SELECT {} FROM {}",
+            Self::select_fields_str(),
+            Self::source_view()
+        )
+    }
+
+    /// Copy the rows matching the query from the view into the table and remove the rows
+    /// matching it that are no longer in the view, in one transaction. Fails with
+    /// `RowCantMaterialize` if the query has no filter or matches nothing in either, the view
+    /// may be read from a replica that has not caught up yet so the message is worth retrying.
+    /// When `rw_db` caches in Redis replicas materializing the same query take turns under a lock
+    async fn materialize(query: Self::Query, ro_db: &RODB, rw_db: &RWDB) -> Result<(), UtilError> {
+        let Some(cache) = rw_db.cache().and_then(AppCache::redis) else {
+            return Self::copy_rows(query, ro_db, rw_db).await;
+        };
+        let lock = format!("materialize:{}:{}", Self::table_name(), query.cache_key());
+        let guard = cache
+            .lock(&lock, MATERIALIZE_LOCK_TTL, MATERIALIZE_LOCK_WAIT)
+            .await?;
+        let result = Self::copy_rows(query, ro_db, rw_db).await;
+        if let Err(e) = guard.release().await {
+            log::warn!("Failed to release {} {:?}", lock, e);
+        }
+        result
+    }
+
+    /// `materialize` without the lock
+    async fn copy_rows(query: Self::Query, ro_db: &RODB, rw_db: &RWDB) -> Result<(), UtilError> {
+        let mut filter = QueryBuilder::<Postgres>::new("");
+        query.add_where(&mut filter);
+        if filter.sql().is_empty() {
+            return Err(UtilError::RowCantMaterialize);
+        }
+
+        // the key is also selected as text so the rows that left the view can be told apart
+        let mut qb = QueryBuilder::new(format!(
+            "SELECT {}, {}::text AS materialized_key FROM {}",
+            Self::select_fields_str(),
+            Self::key_column(),
+            Self::source_view()
+        ));
+        query.add_where(&mut qb);
+        log::trace!("Materialize SQL generated {:?}", qb.sql());
+        let rows = qb.build().fetch_all(ro_db.get_conn()).await?;
+
+        let mut tx = rw_db.begin().await?;
+        let mut keys = Vec::with_capacity(rows.len());
+        for row in &rows {
+            keys.push(row.try_get::<String, _>("materialized_key")?);
+            let _ = Self::upsert_tx(Self::from_row(row)?, &mut tx).await?;
+        }
+        let mut qb = QueryBuilder::new(format!("DELETE FROM {}", Self::table_name()));
+        query.add_where(&mut qb);
+        qb.push(format!(" AND NOT ({}::text = ANY(", Self::key_column()))
+            .push_bind(keys)
+            .push("))");
+        let deleted = qb.build().execute(&mut *tx).await?.rows_affected();
+        if rows.is_empty() && deleted == 0 {
+            return Err(UtilError::RowCantMaterialize);
+        }
+        tx.commit().await?;
+        QueryCache::invalidate(rw_db, &Self::table_name()).await;
+        Ok(())
+    }
+}
+
+/// The tables and columns of a read model, lets read models be rebuilt and checked by name
+/// without knowing their types
+#[derive(Debug, Clone)]
+pub struct ReadModelTable {
+    pub table: String,
+    pub source_view: String,
+    pub key_column: String,
+    pub columns: Vec<String>,
+    pub topic: String,
+    /// Params of the materialize message for the row with a key in its text form
+    pub key_params: fn(&str) -> Result<Vec<String>, UtilError>,
+}
+
+/// Rows of a read model that differ from its source view, by key in text form
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ConsistencyReport {
+    /// In the view but not the table
+    pub missing: Vec<String>,
+    /// In both but with different content
+    pub stale: Vec<String>,
+    /// In the table but no longer in the view
+    pub orphaned: Vec<String>,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.stale.is_empty() && self.orphaned.is_empty()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.missing
+            .iter()
+            .chain(self.stale.iter())
+            .chain(self.orphaned.iter())
+    }
+}
+
+/// Row count and content hash of a table or view, equal for the same rows in any order
+#[derive(Debug, Clone, PartialEq, Default, sqlx::FromRow)]
+pub struct TableChecksum {
+    pub rows: i64,
+    pub checksum: String,
+}
+
+impl ReadModelTable {
+    pub fn of<R: ReadModel>() -> Self {
+        Self {
+            table: R::table_name(),
+            source_view: R::source_view(),
+            key_column: R::key_column(),
+            columns: R::fields(),
+            topic: R::topic(),
+            key_params: |key| {
+                key.parse::<R::Key>()
+                    .map(|key| R::key_query(key).to_params())
+                    .map_err(|_| UtilError::Other(format!("Invalid read model key {key}")))
+            },
+        }
+    }
+
+    pub fn shadow_table(&self) -> String {
+        format!("{}_shadow", self.table)
+    }
+
+    fn columns_str(&self) -> String {
+        self.columns.join(",")
+    }
+
+    /// Copies the next batch of rows from the view into the shadow table, rows are copied in
+    /// key order so each batch carries on after the largest key already copied
+    fn copy_batch_sql(&self) -> String {
+        let shadow = self.shadow_table();
+        format!(
+            "INSERT INTO {shadow} ({columns}) SELECT {columns} FROM {view}
+            WHERE NOT EXISTS (SELECT 1 FROM {shadow})
+            OR {key} > (SELECT {key} FROM {shadow} ORDER BY {key} DESC LIMIT 1)
+            ORDER BY {key} LIMIT $1",
+            columns = self.columns_str(),
+            view = self.source_view,
+            key = self.key_column,
+        )
+    }
+
+    fn checksum_sql(&self, relation: &str) -> String {
+        format!(
+            "SELECT count(*) AS rows,
+            coalesce(md5(string_agg(md5(to_jsonb(t)::text), '' ORDER BY t.{key})), '') AS checksum
+            FROM (SELECT {columns} FROM {relation}) t",
+            key = self.key_column,
+            columns = self.columns_str(),
+        )
+    }
+
+    /// Compares every row of the table with the view by key and a hash of its content.
+    /// Both are read in a single query so they are compared at the same point in time, rows
+    /// are hashed as jsonb so json columns written by `upsert` match the view's formatting
+    fn check_sql(&self) -> String {
+        format!(
+            "SELECT coalesce(s.key, t.key) AS key,
+            CASE WHEN t.key IS NULL THEN 'missing' WHEN s.key IS NULL THEN 'orphaned'
+            ELSE 'stale' END AS status
+            FROM (SELECT x.{key}::text AS key, md5(to_jsonb(x)::text) AS hash
+                FROM (SELECT {columns} FROM {view}) x) s
+            FULL OUTER JOIN (SELECT x.{key}::text AS key, md5(to_jsonb(x)::text) AS hash
+                FROM (SELECT {columns} FROM {table}) x) t
+            ON s.key = t.key
+            WHERE s.key IS NULL OR t.key IS NULL OR s.hash <> t.hash
+            ORDER BY 1",
+            key = self.key_column,
+            columns = self.columns_str(),
+            view = self.source_view,
+            table = self.table,
+        )
+    }
+
+    /// Find the rows of the table that are missing, stale or orphaned compared to the view
+    pub async fn check(&self, db: &RODB) -> Result<ConsistencyReport, UtilError> {
+        let rows = sqlx::query_as::<_, (String, String)>(&self.check_sql())
+            .fetch_all(db.get_conn())
+            .await?;
+        let mut report = ConsistencyReport::default();
+        for (key, status) in rows {
+            match status.as_str() {
+                "missing" => report.missing.push(key),
+                "orphaned" => report.orphaned.push(key),
+                _ => report.stale.push(key),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Enqueue a materialize message for every row in the report, the subscriber copies
+    /// missing and stale rows from the view and deletes orphaned ones. Returns the number
+    /// of messages enqueued
+    pub async fn repair(&self, report: &ConsistencyReport, db: &RWDB) -> Result<usize, UtilError> {
+        let mut tx = db.begin().await?;
+        let mut enqueued = 0;
+        for key in report.keys() {
+            let params = RawParams((self.key_params)(key)?);
+            OutboxEntry::enqueue(&mut tx, &self.table, key, &self.topic, &params).await?;
+            enqueued += 1;
+        }
+        tx.commit().await?;
+        Ok(enqueued)
+    }
+
+    /// Rebuild the table from the view without blocking readers. Rows are copied in batches
+    /// into a fresh shadow table which is swapped in once its count and checksum match the
+    /// view. Writes to the source during the copy make the check fail and leave the table
+    /// as it was, run the rebuild again. `progress` is called with the rows copied so far
+    /// after every batch
+    pub async fn rebuild(
+        &self,
+        db: &RWDB,
+        batch_size: i64,
+        mut progress: impl FnMut(u64),
+    ) -> Result<TableChecksum, UtilError> {
+        let shadow = self.shadow_table();
+        sqlx::query(&format!("DROP TABLE IF EXISTS {shadow}"))
+            .execute(db.get_conn())
+            .await?;
+        sqlx::query(&format!(
+            "CREATE TABLE {shadow} (LIKE {} INCLUDING ALL)",
+            self.table
+        ))
+        .execute(db.get_conn())
+        .await?;
+
+        let copy_batch = self.copy_batch_sql();
+        let mut copied = 0;
+        loop {
+            let inserted = sqlx::query(&copy_batch)
+                .bind(batch_size)
+                .execute(db.get_conn())
+                .await?
+                .rows_affected();
+            copied += inserted;
+            progress(copied);
+            if inserted < batch_size as u64 {
+                break;
+            }
+        }
+
+        let mut tx = db.begin().await?;
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+            .execute(&mut *tx)
+            .await?;
+        let copy = sqlx::query_as::<_, TableChecksum>(&self.checksum_sql(&shadow))
+            .fetch_one(&mut *tx)
+            .await?;
+        let source = sqlx::query_as::<_, TableChecksum>(&self.checksum_sql(&self.source_view))
+            .fetch_one(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        if copy != source {
+            return Err(UtilError::Other(format!(
+                "Rebuild of {} does not match {}, {} rows copied of {}",
+                self.table, self.source_view, copy.rows, source.rows
+            )));
+        }
+
+        let old = format!("{}_old", self.table);
+        let mut tx = db.begin().await?;
+        for statement in [
+            format!("LOCK TABLE {} IN ACCESS EXCLUSIVE MODE", self.table),
+            format!("ALTER TABLE {} RENAME TO {old}", self.table),
+            format!("ALTER TABLE {shadow} RENAME TO {}", self.table),
+            format!("DROP TABLE {old}"),
+        ] {
+            sqlx::query(&statement).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        QueryCache::invalidate(db, &self.table).await;
+        Ok(copy)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn table() -> ReadModelTable {
+        ReadModelTable {
+            table: "things".to_string(),
+            source_view: "things_v".to_string(),
+            key_column: "id".to_string(),
+            columns: vec!["id".to_string(), "name".to_string()],
+            topic: "MaterializeThing".to_string(),
+            key_params: |key| Ok(vec![key.to_string()]),
+        }
+    }
+
+    #[test]
+    fn builds_rebuild_sql() {
+        let sql = table()
+            .copy_batch_sql()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(
+            sql,
+            "INSERT INTO things_shadow (id,name) SELECT id,name FROM things_v \
+            WHERE NOT EXISTS (SELECT 1 FROM things_shadow) \
+            OR id > (SELECT id FROM things_shadow ORDER BY id DESC LIMIT 1) \
+            ORDER BY id LIMIT $1"
+        );
+    }
+
+    #[test]
+    fn report_lists_every_key() {
+        let report = ConsistencyReport {
+            missing: vec!["1".to_string()],
+            stale: vec!["2".to_string()],
+            orphaned: vec!["3".to_string()],
+        };
+        assert!(!report.is_consistent());
+        assert_eq!(report.keys().collect::<Vec<_>>(), vec!["1", "2", "3"]);
+        assert!(ConsistencyReport::default().is_consistent());
+    }
+}
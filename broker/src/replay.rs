@@ -0,0 +1,60 @@
+use crate::{BrokerLayer, StreamPosition, Subscriber};
+use std::sync::Arc;
+use util::{error::UtilError, AppState};
+
+/// Messages read per `read_range` call while replaying
+const REPLAY_BATCH: usize = 100;
+
+/// Outcome of a replay
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ReplaySummary {
+    pub handled: u64,
+    pub failed: u64,
+    /// Id of the last message replayed, a position to resume from
+    pub last_id: Option<String>,
+}
+
+/// Hand every retained message of the subscriber's topic after `from`, and up to `to` if
+/// given, to the subscriber in order. Runs outside of any consumer group so live
+/// subscribers are unaffected. A message that fails is logged and counted and the replay
+/// carries on with the next one
+pub async fn replay<B, S, A>(
+    broker: &B,
+    subscriber: &S,
+    app_state: Arc<A>,
+    from: StreamPosition,
+    to: Option<StreamPosition>,
+) -> Result<ReplaySummary, UtilError>
+where
+    B: BrokerLayer,
+    S: Subscriber,
+    A: AppState,
+{
+    let topic = subscriber.topic();
+    let mut summary = ReplaySummary::default();
+    let mut position = from;
+    loop {
+        let batch = broker
+            .read_range(&topic, &position, to.as_ref(), REPLAY_BATCH)
+            .await?;
+        let batch_len = batch.len();
+        for delivery in batch {
+            let handled = match subscriber.parse_params(delivery.params) {
+                Ok(message) => subscriber.handle_message(message, app_state.clone()).await,
+                Err(e) => Err(e),
+            };
+            match handled {
+                Ok(()) => summary.handled += 1,
+                Err(e) => {
+                    log::error!("Replay of {} on {} failed {:?}", delivery.id, topic, e);
+                    summary.failed += 1;
+                }
+            }
+            position = StreamPosition::Id(delivery.id.clone());
+            summary.last_id = Some(delivery.id);
+        }
+        if batch_len < REPLAY_BATCH {
+            return Ok(summary);
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use crate::{Broker, BrokerLayer, Subscriber};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::{self, JoinHandle};
+use util::{error::UtilError, AppState, FromParams, ToParams};
+use uuid::Uuid;
+
+/// Group every client uses on its own reply topic
+const REPLY_GROUP: &str = "rpc_reply";
+const REPLY_OK: &str = "ok";
+const REPLY_ERR: &str = "err";
+/// Names the message of an error reply, params are field and value pairs
+const REPLY_ERROR_FIELD: &str = "error";
+
+type PendingReplies = HashMap<String, oneshot::Sender<Result<Vec<String>, String>>>;
+
+/// Request envelope, the reply topic and correlation id come before the request params
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RpcRequest<T> {
+    pub reply_to: String,
+    pub correlation_id: String,
+    pub request: T,
+}
+
+impl<T: ToParams> ToParams for RpcRequest<T> {
+    fn to_params(&self) -> Vec<String> {
+        let mut params = vec![self.reply_to.clone(), self.correlation_id.clone()];
+        params.append(&mut self.request.to_params());
+        params
+    }
+}
+
+impl<T: FromParams> FromParams for RpcRequest<T> {
+    fn from_params(mut params: Vec<String>) -> Self {
+        if params.len() < 2 {
+            return Self::default();
+        }
+        let request = params.split_off(2);
+        let mut params = params.into_iter();
+        Self {
+            reply_to: params.next().unwrap_or_default(),
+            correlation_id: params.next().unwrap_or_default(),
+            request: T::from_params(request),
+        }
+    }
+}
+
+/// Reply envelope, `error` is set instead of `params` when the responder failed
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RpcReply {
+    pub correlation_id: String,
+    pub error: Option<String>,
+    pub params: Vec<String>,
+}
+
+impl ToParams for RpcReply {
+    fn to_params(&self) -> Vec<String> {
+        let mut params = vec![self.correlation_id.clone()];
+        match &self.error {
+            Some(error) => params.extend([
+                REPLY_ERR.to_string(),
+                REPLY_ERROR_FIELD.to_string(),
+                error.clone(),
+            ]),
+            None => {
+                params.push(REPLY_OK.to_string());
+                params.extend(self.params.iter().cloned());
+            }
+        }
+        params
+    }
+}
+
+impl FromParams for RpcReply {
+    fn from_params(params: Vec<String>) -> Self {
+        let mut params = params.into_iter();
+        let correlation_id = params.next().unwrap_or_default();
+        match params.next().as_deref() {
+            Some(REPLY_OK) => Self {
+                correlation_id,
+                error: None,
+                params: params.collect(),
+            },
+            _ => Self {
+                correlation_id,
+                error: Some(params.nth(1).unwrap_or_default()),
+                params: vec![],
+            },
+        }
+    }
+}
+
+/// Subscriber whose handler returns a value that is sent back to the caller of
+/// `RpcClient::request`. Run one by subscribing a `ResponderSubscriber`
+#[allow(async_fn_in_trait)]
+pub trait Responder: Send + Sync {
+    type Request: FromParams + ToParams + Send;
+    type Response: FromParams + ToParams + Send + Sync;
+
+    fn respond(
+        &self,
+        request: Self::Request,
+        app_state: Arc<impl AppState>,
+    ) -> impl std::future::Future<Output = Result<Self::Response, UtilError>> + Send;
+    fn topic(&self) -> String;
+    fn group_name(&self) -> String;
+}
+
+/// Adapts a `Responder` to a `Subscriber` publishing its result to the request's reply topic.
+/// A failed response is sent back as an error reply rather than retried, only failing to
+/// publish the reply leaves the request to be delivered again
+pub struct ResponderSubscriber<R> {
+    pub responder: R,
+    pub broker: Broker,
+}
+
+impl<R: Responder> ResponderSubscriber<R> {
+    pub fn new(responder: R, broker: Broker) -> Self {
+        Self { responder, broker }
+    }
+}
+
+impl<R: Responder> Subscriber for ResponderSubscriber<R> {
+    type MessageType = RpcRequest<R::Request>;
+
+    fn handle_message(
+        &self,
+        message: Self::MessageType,
+        app_state: Arc<impl AppState>,
+    ) -> impl std::future::Future<Output = Result<(), UtilError>> + Send {
+        let response = self.responder.respond(message.request, app_state);
+        let broker = self.broker.clone();
+        async move {
+            let reply = match response.await {
+                Ok(response) => RpcReply {
+                    correlation_id: message.correlation_id,
+                    error: None,
+                    params: response.to_params(),
+                },
+                Err(e) => RpcReply {
+                    correlation_id: message.correlation_id,
+                    error: Some(e.to_string()),
+                    params: vec![],
+                },
+            };
+            broker.publish(&message.reply_to, &reply).await
+        }
+    }
+
+    fn topic(&self) -> String {
+        self.responder.topic()
+    }
+
+    fn group_name(&self) -> String {
+        self.responder.group_name()
+    }
+}
+
+/// Sends requests to a `Responder` and waits for the reply. Each client reads replies from
+/// its own topic so create one per process and share it
+#[derive(Clone)]
+pub struct RpcClient {
+    pub broker: Broker,
+    pub reply_topic: String,
+    pending: Arc<Mutex<PendingReplies>>,
+    listener: Arc<JoinHandle<()>>,
+}
+
+impl RpcClient {
+    pub async fn new(broker: Broker) -> Result<Self, UtilError> {
+        let reply_topic = format!("rpc_reply_{}", Uuid::new_v4());
+        // the group must exist before the first request or early replies are missed
+        broker.create_group(&reply_topic, REPLY_GROUP).await?;
+        let pending = Arc::new(Mutex::new(PendingReplies::new()));
+        let listener = task::spawn(listen(broker.clone(), reply_topic.clone(), pending.clone()));
+        Ok(Self {
+            broker,
+            reply_topic,
+            pending,
+            listener: Arc::new(listener),
+        })
+    }
+
+    fn pending(&self) -> MutexGuard<'_, PendingReplies> {
+        lock(&self.pending)
+    }
+
+    /// Publish the request and wait up to `timeout` for the responder's reply
+    pub async fn request<Req, Resp>(
+        &self,
+        topic: &str,
+        request: Req,
+        timeout: Duration,
+    ) -> Result<Resp, UtilError>
+    where
+        Req: FromParams + ToParams,
+        Resp: FromParams,
+    {
+        let correlation_id = Uuid::now_v7().to_string();
+        let (sender, receiver) = oneshot::channel();
+        self.pending().insert(correlation_id.clone(), sender);
+
+        let envelope = RpcRequest {
+            reply_to: self.reply_topic.clone(),
+            correlation_id: correlation_id.clone(),
+            request,
+        };
+        if let Err(e) = self.broker.publish(topic, &envelope).await {
+            self.pending().remove(&correlation_id);
+            return Err(e);
+        }
+
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(Ok(params))) => Ok(Resp::from_params(params)),
+            Ok(Ok(Err(error))) => Err(UtilError::RpcFailed(error)),
+            Ok(Err(_)) => Err(UtilError::Other("RPC reply listener stopped".to_string())),
+            Err(_) => {
+                self.pending().remove(&correlation_id);
+                Err(UtilError::RpcTimeout(topic.to_string()))
+            }
+        }
+    }
+}
+
+impl Drop for RpcClient {
+    /// The last clone stops the listener and deletes the reply topic with its group
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.listener) > 1 {
+            return;
+        }
+        self.listener.abort();
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            log::warn!(
+                "RPC reply topic {} left without a runtime",
+                self.reply_topic
+            );
+            return;
+        };
+        let broker = self.broker.clone();
+        let reply_topic = self.reply_topic.clone();
+        runtime.spawn(async move {
+            if let Err(e) = broker.remove_queue(&reply_topic).await {
+                log::warn!("Failed to remove RPC reply topic {} {:?}", reply_topic, e);
+            }
+        });
+    }
+}
+
+fn lock(pending: &Mutex<PendingReplies>) -> MutexGuard<'_, PendingReplies> {
+    pending
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+async fn listen(broker: Broker, reply_topic: String, pending: Arc<Mutex<PendingReplies>>) {
+    let consumer = Uuid::new_v4().to_string();
+    loop {
+        let delivery = match broker
+            .read_group(&reply_topic, REPLY_GROUP, &consumer)
+            .await
+        {
+            Ok(Some(delivery)) => delivery,
+            Ok(None) => continue,
+            Err(e) => {
+                log::error!("RPC reply read error on {} {:?}", reply_topic, e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let reply = RpcReply::from_params(delivery.params);
+        // a missing sender means the request already timed out
+        if let Some(sender) = lock(&pending).remove(&reply.correlation_id) {
+            let _ = sender.send(match reply.error {
+                Some(error) => Err(error),
+                None => Ok(reply.params),
+            });
+        }
+        if let Err(e) = broker.ack(&reply_topic, REPLY_GROUP, &delivery.id).await {
+            log::error!("RPC reply ack error {:?} on {}", e, reply_topic);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MemoryBroker, RetryPolicy};
+    use to_params::{FromParams, ToParams};
+    use util::tests::{get_test_env, MemoryApiState};
+
+    #[derive(Debug, Default, PartialEq, ToParams, FromParams)]
+    struct Sum {
+        total: i64,
+    }
+
+    /// Doubles the total, refusing negative ones
+    struct Doubler;
+
+    impl Responder for Doubler {
+        type Request = Sum;
+        type Response = Sum;
+
+        fn respond(
+            &self,
+            request: Sum,
+            _app_state: Arc<impl AppState>,
+        ) -> impl std::future::Future<Output = Result<Sum, UtilError>> + Send {
+            std::future::ready(match request.total {
+                total if total < 0 => Err(UtilError::Other("negative total".to_string())),
+                total => Ok(Sum { total: total * 2 }),
+            })
+        }
+
+        fn topic(&self) -> String {
+            "sum".to_string()
+        }
+
+        fn group_name(&self) -> String {
+            "sum".to_string()
+        }
+    }
+
+    /// Answers requests with a `Doubler` until the returned task is aborted
+    async fn respond(broker: &Broker) -> JoinHandle<Result<(), UtilError>> {
+        broker.create_group("sum", "sum").await.unwrap();
+        let state = Arc::new(MemoryApiState::from_test_env().await.unwrap());
+        let subscriber = ResponderSubscriber::new(Doubler, broker.clone());
+        broker.subscribe(subscriber, state).await.unwrap()
+    }
+
+    async fn assert_removed(broker: &Broker, topic: &str) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while broker
+                .list_topics()
+                .await
+                .unwrap()
+                .iter()
+                .any(|t| t.name == topic)
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn replies_to_request() {
+        let broker = Broker::Memory(MemoryBroker::with_retry_policy(RetryPolicy::default()));
+        let responder = respond(&broker).await;
+        let client = RpcClient::new(broker.clone()).await.unwrap();
+
+        let response: Sum = client
+            .request("sum", Sum { total: 21 }, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(response, Sum { total: 42 });
+        let failed = client
+            .request::<_, Sum>("sum", Sum { total: -1 }, Duration::from_secs(5))
+            .await;
+        assert!(matches!(failed, Err(UtilError::RpcFailed(e)) if e == "negative total"));
+
+        let reply_topic = client.reply_topic.clone();
+        drop(client);
+        assert_removed(&broker, &reply_topic).await;
+        responder.abort();
+    }
+
+    #[tokio::test]
+    async fn replies_with_error_over_redis() {
+        let broker = Broker::new(&get_test_env()).await.unwrap();
+        let responder = respond(&broker).await;
+        let client = RpcClient::new(broker.clone()).await.unwrap();
+
+        let failed = client
+            .request::<_, Sum>("sum", Sum { total: -1 }, Duration::from_secs(5))
+            .await;
+        assert!(matches!(failed, Err(UtilError::RpcFailed(e)) if e == "negative total"));
+
+        let reply_topic = client.reply_topic.clone();
+        drop(client);
+        assert_removed(&broker, &reply_topic).await;
+        responder.abort();
+    }
+
+    #[tokio::test]
+    async fn times_out_without_responder() {
+        let broker = Broker::Memory(MemoryBroker::with_retry_policy(RetryPolicy::default()));
+        let client = RpcClient::new(broker).await.unwrap();
+        let response = client
+            .request::<_, Sum>("nobody", Sum { total: 1 }, Duration::from_millis(50))
+            .await;
+        assert!(matches!(response, Err(UtilError::RpcTimeout(_))));
+        assert!(client.pending().is_empty());
+    }
+
+    #[test]
+    fn error_reply_round_trips() {
+        let reply = RpcReply {
+            correlation_id: "id".to_string(),
+            error: Some("failed".to_string()),
+            params: vec![],
+        };
+        assert_eq!(reply.to_params().len() % 2, 0);
+        assert_eq!(RpcReply::from_params(reply.to_params()), reply);
+    }
+}
@@ -0,0 +1,346 @@
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::task::{self, JoinHandle};
+use util::{
+    cache::AppCache,
+    env::Env,
+    error::UtilError,
+    store::{CacheLayer, RODB, RWDB},
+    AppState,
+};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+#[allow(async_fn_in_trait)]
+pub trait Job: Send + Sync {
+    fn name(&self) -> String;
+    /// Cron expression including seconds, e.g. `0 0 3 * * *` for 03:00 UTC every day
+    fn schedule(&self) -> String;
+    fn run(
+        &self,
+        app_state: Arc<impl AppState>,
+    ) -> impl std::future::Future<Output = Result<(), UtilError>> + Send;
+}
+
+/// A single run of a scheduled job as recorded in `scheduled_job_runs`
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow, Serialize, Deserialize, ToSchema)]
+pub struct JobRun {
+    pub id: i64,
+    pub job_name: String,
+    pub instance: String,
+    pub scheduled_for: DateTime<Utc>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub duration_ms: Option<i64>,
+    pub succeeded: Option<bool>,
+    pub error: Option<String>,
+}
+
+impl JobRun {
+    /// The most recent run of every job that has run at least once
+    pub async fn latest(db: &RODB) -> Result<Vec<Self>, UtilError> {
+        Ok(sqlx::query_as::<_, Self>(
+            "SELECT DISTINCT ON (job_name) * FROM scheduled_job_runs
+            ORDER BY job_name, started_at DESC",
+        )
+        .fetch_all(db.get_conn())
+        .await?)
+    }
+
+    /// Runs of a job, newest first
+    pub async fn history(db: &RODB, job_name: &str, limit: i64) -> Result<Vec<Self>, UtilError> {
+        Ok(sqlx::query_as::<_, Self>(
+            "SELECT * FROM scheduled_job_runs WHERE job_name = $1
+            ORDER BY started_at DESC LIMIT $2",
+        )
+        .bind(job_name)
+        .bind(limit)
+        .fetch_all(db.get_conn())
+        .await?)
+    }
+
+    /// Delete runs started more than `days` ago, returns how many were deleted
+    pub async fn purge(db: &RWDB, days: i32) -> Result<u64, UtilError> {
+        Ok(sqlx::query(
+            "DELETE FROM scheduled_job_runs WHERE started_at < now() - make_interval(days => $1)",
+        )
+        .bind(days)
+        .execute(db.get_conn())
+        .await?
+        .rows_affected())
+    }
+
+    /// Record the start of the run of a tick, `None` if the tick already has a run
+    async fn start(
+        db: &RWDB,
+        job_name: &str,
+        instance: &str,
+        scheduled_for: DateTime<Utc>,
+    ) -> Result<Option<i64>, UtilError> {
+        let id = sqlx::query_as::<_, (i64,)>(
+            "INSERT INTO scheduled_job_runs (job_name, instance, scheduled_for)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (job_name, scheduled_for) DO NOTHING
+            RETURNING id",
+        )
+        .bind(job_name)
+        .bind(instance)
+        .bind(scheduled_for)
+        .fetch_optional(db.get_conn())
+        .await?;
+        Ok(id.map(|(id,)| id))
+    }
+
+    async fn finish(
+        db: &RWDB,
+        id: i64,
+        duration_ms: i64,
+        result: &Result<(), UtilError>,
+    ) -> Result<(), UtilError> {
+        sqlx::query(
+            "UPDATE scheduled_job_runs
+            SET finished_at = now(), duration_ms = $2, succeeded = $3, error = $4
+            WHERE id = $1",
+        )
+        .bind(id)
+        .bind(duration_ms)
+        .bind(result.is_ok())
+        .bind(result.as_ref().err().map(|e| e.to_string()))
+        .execute(db.get_conn())
+        .await?;
+        Ok(())
+    }
+}
+
+/// Runs jobs on their cron schedule. Every replica may run a scheduler, for each tick the
+/// replicas race for a lock in Redis and only the winner runs the job. Without Redis they
+/// race to record the run of the tick, which is unique per job and tick
+#[derive(Clone)]
+pub struct Scheduler {
+    pub cache: AppCache,
+    pub db: RWDB,
+    pub instance: String,
+}
+
+impl Scheduler {
+    pub async fn new(env: &Env) -> Result<Self, UtilError> {
+        Ok(Self {
+            cache: AppCache::new(env).await?,
+            db: RWDB::connect(env).await?,
+            instance: Uuid::new_v4().to_string(),
+        })
+    }
+
+    /// Spawn a task per job, fails without starting anything if a schedule does not parse
+    pub fn start<A>(
+        &self,
+        jobs: Vec<impl Job + 'static>,
+        app_state: Arc<A>,
+    ) -> Result<Vec<JoinHandle<Result<(), UtilError>>>, UtilError>
+    where
+        A: AppState + Clone + Sync + Send + 'static,
+    {
+        let scheduled = jobs
+            .into_iter()
+            .map(|job| Ok((parse_schedule(&job)?, job)))
+            .collect::<Result<Vec<_>, UtilError>>()?;
+        Ok(scheduled
+            .into_iter()
+            .map(|(schedule, job)| {
+                task::spawn(run_job(self.clone(), job, schedule, app_state.clone()))
+            })
+            .collect())
+    }
+
+    /// Take the Redis lock for a tick, held until the following tick so a replica with a
+    /// slow clock cannot run the same tick again. Without Redis every replica goes on to
+    /// `JobRun::start`, which only one of them wins
+    async fn acquire(
+        &self,
+        job_name: &str,
+        tick: DateTime<Utc>,
+        next: Option<DateTime<Utc>>,
+    ) -> Result<bool, UtilError> {
+        let Some(redis) = self.cache.redis() else {
+            return Ok(true);
+        };
+        let expires = next
+            .map(|next| (next - tick).num_seconds())
+            .unwrap_or(60)
+            .max(60) as u64;
+        redis
+            .set_value_if_absent(
+                &format!("scheduler:{job_name}:{}", tick.timestamp()),
+                &self.instance,
+                Some(expires),
+            )
+            .await
+    }
+}
+
+pub fn parse_schedule(job: &impl Job) -> Result<Schedule, UtilError> {
+    Schedule::from_str(&job.schedule())
+        .map_err(|e| UtilError::Other(format!("Invalid schedule for job {} {e}", job.name())))
+}
+
+async fn run_job<J, A>(
+    scheduler: Scheduler,
+    job: J,
+    schedule: Schedule,
+    app_state: Arc<A>,
+) -> Result<(), UtilError>
+where
+    J: Job,
+    A: AppState + Clone + Sync + Send + 'static,
+{
+    let name = job.name();
+    loop {
+        let mut upcoming = schedule.upcoming(Utc);
+        let Some(tick) = upcoming.next() else {
+            log::info!("Job {} has no upcoming runs", name);
+            return Ok(());
+        };
+        if let Ok(wait) = (tick - Utc::now()).to_std() {
+            tokio::time::sleep(wait).await;
+        }
+
+        match scheduler.acquire(&name, tick, upcoming.next()).await {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                log::error!("Failed to take scheduler lock for {} {:?}", name, e);
+                continue;
+            }
+        }
+
+        let run_id = match JobRun::start(&scheduler.db, &name, &scheduler.instance, tick).await {
+            Ok(Some(run_id)) => Some(run_id),
+            Ok(None) => continue,
+            // the Redis lock still guards the tick, only its record is missing
+            Err(e) if scheduler.cache.redis().is_some() => {
+                log::error!("Failed to record run of {} {:?}", name, e);
+                None
+            }
+            Err(e) => {
+                log::error!("Failed to claim run of {} {:?}", name, e);
+                continue;
+            }
+        };
+        let started = Instant::now();
+        let result = job.run(app_state.clone()).await;
+        if let Err(e) = &result {
+            log::error!("Job {} failed {:?}", name, e);
+        }
+        if let Some(run_id) = run_id {
+            let duration_ms = started.elapsed().as_millis() as i64;
+            if let Err(e) = JobRun::finish(&scheduler.db, run_id, duration_ms, &result).await {
+                log::error!("Failed to record result of {} {:?}", name, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use util::{
+        cache::MemoryCache,
+        store::{CacheEncoding, Redis},
+        tests::get_test_env,
+    };
+
+    struct Nightly(&'static str);
+
+    impl Job for Nightly {
+        fn name(&self) -> String {
+            "nightly".to_string()
+        }
+
+        fn schedule(&self) -> String {
+            self.0.to_string()
+        }
+
+        fn run(
+            &self,
+            _app_state: Arc<impl AppState>,
+        ) -> impl std::future::Future<Output = Result<(), UtilError>> + Send {
+            std::future::ready(Ok(()))
+        }
+    }
+
+    fn replica(cache: &AppCache) -> Scheduler {
+        Scheduler {
+            cache: cache.clone(),
+            db: RWDB::connect_lazy(&get_test_env()).unwrap(),
+            instance: Uuid::new_v4().to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_schedules() {
+        assert!(parse_schedule(&Nightly("0 0 3 * * *")).is_ok());
+        assert!(parse_schedule(&Nightly("every night")).is_err());
+    }
+
+    #[tokio::test]
+    async fn one_replica_runs_each_tick() {
+        let cache = AppCache::Redis(Redis::new(&get_test_env()).await.unwrap());
+        let replicas = [replica(&cache), replica(&cache), replica(&cache)];
+        let tick = Utc::now();
+        let next = tick + chrono::Duration::minutes(5);
+
+        let mut winners = 0;
+        for replica in &replicas {
+            if replica.acquire("nightly", tick, Some(next)).await.unwrap() {
+                winners += 1;
+            }
+        }
+        assert_eq!(winners, 1);
+        assert!(replicas[1].acquire("nightly", next, None).await.unwrap());
+        assert!(!replicas[0].acquire("nightly", next, None).await.unwrap());
+        assert!(replicas[0].acquire("weekly", tick, None).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn leaves_ticks_to_the_database_without_redis() {
+        let cache = AppCache::Memory(MemoryCache::with_capacity(100, CacheEncoding::Json));
+        let replicas = [replica(&cache), replica(&cache)];
+        let tick = Utc::now();
+        for replica in &replicas {
+            assert!(replica.acquire("nightly", tick, None).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn records_and_purges_runs() {
+        let env = get_test_env();
+        let rw_db = RWDB::connect(&env).await.unwrap();
+        let ro_db = RODB::connect(&env).await.unwrap();
+        let job = format!("job-{}", Uuid::new_v4());
+        let tick = Utc::now();
+        let id = JobRun::start(&rw_db, &job, "instance", tick)
+            .await
+            .unwrap()
+            .unwrap();
+        // the tick is claimed, another replica without Redis does not run it
+        assert_eq!(
+            JobRun::start(&rw_db, &job, "other", tick).await.unwrap(),
+            None
+        );
+        let failed = Err(UtilError::Other("failed".to_string()));
+        JobRun::finish(&rw_db, id, 5, &failed).await.unwrap();
+
+        let runs = JobRun::history(&ro_db, &job, 10).await.unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].succeeded, Some(false));
+        assert_eq!(runs[0].error.as_deref(), Some("failed"));
+        assert!(JobRun::latest(&ro_db).await.unwrap().contains(&runs[0]));
+
+        assert!(JobRun::purge(&rw_db, 0).await.unwrap() >= 1);
+        assert!(JobRun::history(&ro_db, &job, 10).await.unwrap().is_empty());
+    }
+}
@@ -1,17 +1,25 @@
 use api::start_server;
-use broker::{BrokerLayer, RedisStream};
-use chrono::Utc;
+use broker::{
+    metrics, replay::replay, scheduler::Scheduler, BrokerLayer, OutboxRelay, StreamPosition,
+    Subscriber,
+};
+use chrono::{DateTime, Utc};
 use clap::Parser;
 use dotenv::dotenv;
 use futures::future::join_all;
-use model::{subscribers, State as ModelState};
+use model::{jobs, read_models, subscribers, State as ModelState};
 use std::error::Error;
 use std::fs::File;
 use std::sync::Arc;
-use util::store::RWDB;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use util::error::UtilError;
+use util::store::{RODB, RWDB};
 use util::{env::Env, AppState};
 
 const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// How often the broker logs the counters of its subscribers
+const METRICS_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(clap::Args, Clone, Debug)]
 pub struct AddSqlMigration {
@@ -20,6 +28,53 @@ pub struct AddSqlMigration {
     name: String,
 }
 
+#[derive(clap::Args, Clone, Debug)]
+pub struct Replay {
+    /// Topic to replay, a subscriber in `model::subscribers` must handle it
+    topic: String,
+    /// Reset this consumer group to the start position instead of replaying in this
+    /// process, running subscribers of the group then reprocess the messages
+    #[arg(long)]
+    group: Option<String>,
+    /// Start after the message with this broker id
+    #[arg(long, conflicts_with = "since")]
+    after_id: Option<String>,
+    /// Start from messages published at or after this RFC 3339 time
+    #[arg(long)]
+    since: Option<DateTime<Utc>>,
+    /// Stop before messages published at or after this RFC 3339 time, ignored with `--group`
+    #[arg(long)]
+    until: Option<DateTime<Utc>>,
+}
+
+impl Replay {
+    fn from(&self) -> StreamPosition {
+        match (&self.after_id, self.since) {
+            (Some(id), _) => StreamPosition::Id(id.clone()),
+            (None, Some(since)) => StreamPosition::Time(since),
+            (None, None) => StreamPosition::Beginning,
+        }
+    }
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct RebuildReadModel {
+    /// Table of the read model, one of those in `model::read_models`
+    table: String,
+    /// Rows copied from the source view per batch
+    #[arg(long, default_value_t = 1000)]
+    batch_size: i64,
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct CheckReadModel {
+    /// Table of the read model, every read model in `model::read_models` when not given
+    table: Option<String>,
+    /// Enqueue materialize messages for the rows that differ
+    #[arg(long)]
+    repair: bool,
+}
+
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub enum Command {
@@ -32,6 +87,41 @@ pub enum Command {
     /// Create a new sql migration file
     AddSqlMigration(AddSqlMigration),
     Broker,
+    /// Start the api and broker subscriptions in a single process, pairs with
+    /// `BROKER_BACKEND=memory` to run without Redis or a separate message broker
+    Standalone,
+    /// Run recurring jobs on their cron schedules, safe to run on several replicas
+    Scheduler,
+    /// Reprocess retained messages of a topic, e.g. to rebuild a read model after a bug fix
+    Replay(Replay),
+    /// Rebuild a read model from its source view into a shadow table and swap it in
+    RebuildReadModel(RebuildReadModel),
+    /// Compare read models with their source views and report missing, stale and orphaned rows
+    CheckReadModel(CheckReadModel),
+}
+
+/// Start the outbox relay, the subscriber metrics log and a subscription for every topic in
+/// `watch_topics`
+async fn start_broker(
+    env: &Env,
+    app_state: Arc<ModelState>,
+) -> Result<Vec<JoinHandle<Result<(), UtilError>>>, Box<dyn Error>> {
+    match app_state.broker.clone() {
+        Some(broker) => {
+            let mut handles = vec![
+                OutboxRelay::new(broker.clone(), app_state.rw_db.clone()).start(),
+                broker.clone().start_mover(Duration::from_secs(1)),
+                metrics::start_logging(METRICS_INTERVAL),
+            ];
+            handles.append(
+                &mut broker
+                    .start_subscriptions(subscribers(), env, app_state)
+                    .await?,
+            );
+            Ok(handles)
+        }
+        None => Ok(vec![]),
+    }
 }
 
 #[tokio::main]
@@ -64,14 +154,112 @@ async fn main() -> Result<(), Box<dyn Error>> {
         Command::Broker => {
             let env = Env::from_env()?;
             env_logger::init();
-            let broker = RedisStream::new(&env).await?;
             let app_state = Arc::new(ModelState::from_env(env.clone()).await?);
-            let subs = subscribers();
-            let subscriptions = broker.start_subscriptions(subs, &env, app_state).await?;
+            let subscriptions = start_broker(&env, app_state).await?;
             if !subscriptions.is_empty() {
                 join_all(subscriptions).await;
             }
         }
+        Command::Standalone => {
+            let env = Env::from_env()?;
+            env_logger::init();
+            let app_state = ModelState::from_env(env.clone()).await?;
+            let _subscriptions = start_broker(&env, Arc::new(app_state.clone())).await?;
+            let server_handle = start_server(app_state);
+            let _ = server_handle.await;
+        }
+        Command::Scheduler => {
+            let env = Env::from_env()?;
+            env_logger::init();
+            let app_state = Arc::new(ModelState::from_env(env.clone()).await?);
+            let scheduler = Scheduler::new(&env).await?;
+            join_all(scheduler.start(jobs(), app_state)?).await;
+        }
+        Command::Replay(details) => {
+            let env = Env::from_env()?;
+            env_logger::init();
+            let app_state = Arc::new(ModelState::from_env(env).await?);
+            let broker = app_state
+                .broker
+                .clone()
+                .ok_or_else(|| UtilError::Other("Broker is not configured".to_string()))?;
+            if let Some(group) = &details.group {
+                broker
+                    .reset_group(&details.topic, group, &details.from())
+                    .await?;
+                println!("reset group {group} on {}", details.topic);
+                return Ok(());
+            }
+            let subscriber = subscribers()
+                .into_iter()
+                .find(|s| s.topic() == details.topic)
+                .ok_or_else(|| UtilError::Other(format!("No subscriber for {}", details.topic)))?;
+            let summary = replay(
+                &broker,
+                &subscriber,
+                app_state,
+                details.from(),
+                details.until.map(StreamPosition::Time),
+            )
+            .await?;
+            println!(
+                "replayed {} messages on {}, {} failed, last id {}",
+                summary.handled,
+                details.topic,
+                summary.failed,
+                summary.last_id.unwrap_or_default()
+            );
+        }
+        Command::RebuildReadModel(details) => {
+            let env = Env::from_env()?;
+            env_logger::init();
+            let read_model = read_models()
+                .into_iter()
+                .find(|r| r.table == details.table)
+                .ok_or_else(|| UtilError::Other(format!("No read model {}", details.table)))?;
+            let app_state = ModelState::from_env(env).await?;
+            let rebuilt = read_model
+                .rebuild(&app_state.rw_db, details.batch_size, |copied| {
+                    println!("copied {copied} rows into {}", read_model.shadow_table())
+                })
+                .await?;
+            println!(
+                "rebuilt {} with {} rows, checksum {}",
+                read_model.table, rebuilt.rows, rebuilt.checksum
+            );
+        }
+        Command::CheckReadModel(details) => {
+            let env = Env::from_env()?;
+            env_logger::init();
+            let ro_db = RODB::connect(&env).await?;
+            let rw_db = RWDB::connect(&env).await?;
+            for read_model in read_models()
+                .into_iter()
+                .filter(|r| details.table.as_ref().is_none_or(|t| *t == r.table))
+            {
+                let report = read_model.check(&ro_db).await?;
+                println!(
+                    "{}: {} missing, {} stale, {} orphaned",
+                    read_model.table,
+                    report.missing.len(),
+                    report.stale.len(),
+                    report.orphaned.len()
+                );
+                for (status, keys) in [
+                    ("missing", &report.missing),
+                    ("stale", &report.stale),
+                    ("orphaned", &report.orphaned),
+                ] {
+                    for key in keys {
+                        println!("  {status} {key}");
+                    }
+                }
+                if details.repair && !report.is_consistent() {
+                    let enqueued = read_model.repair(&report, &rw_db).await?;
+                    println!("  enqueued {enqueued} materialize messages");
+                }
+            }
+        }
     }
     Ok(())
 }
@@ -0,0 +1,72 @@
+use crate::{ApiClient, ClientError};
+use broker::{
+    admin::{ConsumerInfo, GroupInfo, TopicInfo},
+    scheduler::JobRun,
+};
+use reqwest::Method;
+
+impl ApiClient {
+    /// `GET /admin/broker/topics`
+    pub async fn get_topics(&self) -> Result<Vec<TopicInfo>, ClientError> {
+        self.json(Method::GET, &["admin", "broker", "topics"], |request| {
+            request
+        })
+        .await
+    }
+
+    /// `POST /admin/broker/topics/{topic}`
+    pub async fn add_topic(&self, topic: &str) -> Result<(), ClientError> {
+        self.empty(Method::POST, &["admin", "broker", "topics", topic])
+            .await
+    }
+
+    /// `DELETE /admin/broker/topics/{topic}`
+    pub async fn remove_topic(&self, topic: &str) -> Result<(), ClientError> {
+        self.empty(Method::DELETE, &["admin", "broker", "topics", topic])
+            .await
+    }
+
+    /// `GET /admin/broker/topics/{topic}/groups`
+    pub async fn get_groups(&self, topic: &str) -> Result<Vec<GroupInfo>, ClientError> {
+        let segments = ["admin", "broker", "topics", topic, "groups"];
+        self.json(Method::GET, &segments, |request| request).await
+    }
+
+    /// `GET /admin/broker/topics/{topic}/groups/{group}/consumers`
+    pub async fn get_consumers(
+        &self,
+        topic: &str,
+        group: &str,
+    ) -> Result<Vec<ConsumerInfo>, ClientError> {
+        let segments = [
+            "admin",
+            "broker",
+            "topics",
+            topic,
+            "groups",
+            group,
+            "consumers",
+        ];
+        self.json(Method::GET, &segments, |request| request).await
+    }
+
+    /// `GET /admin/jobs`
+    pub async fn get_jobs(&self) -> Result<Vec<JobRun>, ClientError> {
+        self.json(Method::GET, &["admin", "jobs"], |request| request)
+            .await
+    }
+
+    /// `GET /admin/jobs/{job}/runs`
+    pub async fn get_job_runs(
+        &self,
+        job: &str,
+        limit: Option<i64>,
+    ) -> Result<Vec<JobRun>, ClientError> {
+        let segments = ["admin", "jobs", job, "runs"];
+        self.json(Method::GET, &segments, |request| match limit {
+            Some(limit) => request.query(&[("limit", limit)]),
+            None => request,
+        })
+        .await
+    }
+}
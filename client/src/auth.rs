@@ -0,0 +1,51 @@
+use crate::{ApiClient, ClientError};
+use casdoor_rust_sdk::CasdoorUser;
+use reqwest::Method;
+
+impl ApiClient {
+    /// `POST /auth_login`, the url to sign in at
+    pub async fn auth_login(&self) -> Result<String, ClientError> {
+        self.json(Method::POST, &["auth_login"], |request| request)
+            .await
+    }
+
+    /// `POST /auth_signup`, the url to sign up at
+    pub async fn auth_signup(&self) -> Result<String, ClientError> {
+        self.json(Method::POST, &["auth_signup"], |request| request)
+            .await
+    }
+
+    /// `GET /auth_callback`, the token to authenticate with as `Auth::Bearer`
+    pub async fn auth_callback(&self, code: &str, state: &str) -> Result<String, ClientError> {
+        self.json(Method::GET, &["auth_callback"], |request| {
+            request.query(&[("code", code), ("state", state)])
+        })
+        .await
+    }
+
+    /// `GET /auth_users/{name}`
+    pub async fn get_auth_user(&self, name: &str) -> Result<CasdoorUser, ClientError> {
+        self.json(Method::GET, &["auth_users", name], |request| request)
+            .await
+    }
+
+    /// `GET /auth_users`
+    pub async fn get_auth_users(&self) -> Result<Vec<CasdoorUser>, ClientError> {
+        self.json(Method::GET, &["auth_users"], |request| request)
+            .await
+    }
+
+    /// `POST /auth_users`, the status returned by the SSO service
+    pub async fn add_auth_user(&self, user: &CasdoorUser) -> Result<u16, ClientError> {
+        self.json(Method::POST, &["auth_users"], |request| request.json(user))
+            .await
+    }
+
+    /// `DELETE /auth_users`, the status returned by the SSO service
+    pub async fn delete_auth_user(&self, user: &CasdoorUser) -> Result<u16, ClientError> {
+        self.json(Method::DELETE, &["auth_users"], |request| {
+            request.json(user)
+        })
+        .await
+    }
+}
@@ -0,0 +1,78 @@
+use reqwest::StatusCode;
+use serde::Deserialize;
+use thiserror::Error as ThisError;
+use util::error::FieldError;
+
+/// Body of error responses, an RFC 7807 problem as written by `derive_axum_errors`
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    /// Stable code of the error, e.g. `not_found`
+    pub code: String,
+    /// Left out of 5xx responses
+    pub detail: Option<String>,
+    pub instance: Option<String>,
+    #[serde(default)]
+    pub errors: Vec<FieldError>,
+}
+
+impl Problem {
+    pub fn message(&self) -> &str {
+        self.detail.as_deref().unwrap_or(&self.title)
+    }
+}
+
+#[derive(ThisError, Debug)]
+pub enum ClientError {
+    #[error("Api responded {status}: {}", .problem.message())]
+    Api {
+        status: StatusCode,
+        problem: Box<Problem>,
+    },
+    #[error("Invalid api url {0}")]
+    InvalidUrl(String),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+impl ClientError {
+    /// The error of an unsuccessful response, falling back to the raw body when it was not
+    /// written by the api, e.g. by a proxy in front of it
+    pub fn from_response(status: StatusCode, body: &str) -> Self {
+        let problem = serde_json::from_str::<Problem>(body).unwrap_or_else(|_| Problem {
+            problem_type: "about:blank".to_owned(),
+            title: status.canonical_reason().unwrap_or_default().to_owned(),
+            status: status.as_u16(),
+            code: String::new(),
+            detail: Some(body.to_owned()),
+            instance: None,
+            errors: Vec::new(),
+        });
+        Self::Api {
+            status,
+            problem: Box::new(problem),
+        }
+    }
+
+    /// Status returned by the api, `None` if no response was received
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::Api { status, .. } => Some(*status),
+            Self::Http(e) => e.status(),
+            _ => None,
+        }
+    }
+
+    /// Stable code of an error returned by the api, `None` if it did not return a problem
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            Self::Api { problem, .. } if !problem.code.is_empty() => Some(&problem.code),
+            _ => None,
+        }
+    }
+}
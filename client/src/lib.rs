@@ -0,0 +1,245 @@
+pub mod admin;
+pub mod auth;
+pub mod error;
+pub mod users;
+
+pub use derive_log_and_parse::LogAndParse;
+pub use error::ClientError;
+pub use reqwest;
+
+use reqwest::{
+    header::{HeaderName, ACCEPT, RETRY_AFTER},
+    Client, Method, RequestBuilder, Response, StatusCode, Url,
+};
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+use uuid::Uuid;
+
+const API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Statuses worth retrying, the request did not reach a handler or was refused for now
+const RETRY_STATUSES: [StatusCode; 4] = [
+    StatusCode::TOO_MANY_REQUESTS,
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+
+/// Parses a successful response into `Self`, see `#[derive(LogAndParse)]`
+#[allow(async_fn_in_trait)]
+pub trait LogAndParse: Sized {
+    async fn log_and_parse(response: Response) -> Result<Self, ClientError>;
+}
+
+/// The `ClientError` of an unsuccessful response
+pub async fn error_for_status(response: Response) -> Result<Response, ClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await?;
+    Err(ClientError::from_response(status, &body))
+}
+
+/// Parses the json body of a successful response, logging the body when it does not
+/// match `T` as the serde error alone is not detailed enough
+pub async fn parse_response<T: DeserializeOwned>(
+    name: &str,
+    response: Response,
+) -> Result<T, ClientError> {
+    let body = error_for_status(response).await?.text().await?;
+    match serde_json::from_str(&body) {
+        Ok(parsed) => {
+            log::trace!("{} Parse original json {}", name, body);
+            Ok(parsed)
+        }
+        Err(e) => {
+            log::error!("{} Parse failed {:?} original json {}", name, e, body);
+            Err(e.into())
+        }
+    }
+}
+
+/// How the client authenticates, see the `bearer` and `api_key` security schemes
+#[derive(Debug, Clone, PartialEq)]
+pub enum Auth {
+    /// Token returned by `/auth_callback`
+    Bearer(String),
+    ApiKey(String),
+}
+
+/// Retries of requests that failed to connect, timed out or got a response in
+/// `RETRY_STATUSES`, waiting `Retry-After` when given or backing off exponentially
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    /// Longest wait between attempts, a longer `Retry-After` is returned as an error
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay)
+    }
+
+    fn retry_response(&self, response: &Response, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_retries || !RETRY_STATUSES.contains(&response.status()) {
+            return None;
+        }
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_secs);
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after),
+            None => Some(self.backoff(attempt)),
+        }
+    }
+
+    fn retry_error(&self, error: &reqwest::Error, attempt: u32) -> Option<Duration> {
+        (attempt < self.max_retries && (error.is_connect() || error.is_timeout()))
+            .then(|| self.backoff(attempt))
+    }
+}
+
+/// Typed client of the api, the endpoints are methods named after their handlers
+#[derive(Debug, Clone)]
+pub struct ApiClient {
+    http: Client,
+    base_url: Url,
+    auth: Option<Auth>,
+    retries: RetryPolicy,
+}
+
+impl ApiClient {
+    pub fn new(base_url: &str) -> Result<Self, ClientError> {
+        Self::with_client(Client::new(), base_url)
+    }
+
+    /// Client sending requests with `http`, e.g. one configured with timeouts or a proxy
+    pub fn with_client(http: Client, base_url: &str) -> Result<Self, ClientError> {
+        let url = Url::parse(base_url)
+            .map_err(|e| ClientError::InvalidUrl(format!("{} {}", base_url, e)))?;
+        if url.cannot_be_a_base() {
+            return Err(ClientError::InvalidUrl(base_url.to_owned()));
+        }
+        Ok(Self {
+            http,
+            base_url: url,
+            auth: None,
+            retries: RetryPolicy::default(),
+        })
+    }
+
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    pub fn with_retries(mut self, retries: RetryPolicy) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// `base_url` with the path segments appended, each is percent encoded
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base_url.clone();
+        if let Ok(mut path) = url.path_segments_mut() {
+            path.pop_if_empty().extend(segments);
+        }
+        url
+    }
+
+    /// Sends the request `build` returns, rebuilding it for each retry. Requests other
+    /// than GET carry one `Idempotency-Key` across retries so the api applies them once
+    async fn send(
+        &self,
+        method: Method,
+        segments: &[&str],
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<Response, ClientError> {
+        let url = self.url(segments);
+        let idempotency_key = (method != Method::GET).then(|| Uuid::new_v4().to_string());
+        let mut attempt = 0;
+        loop {
+            let mut request = self
+                .http
+                .request(method.clone(), url.clone())
+                .header(ACCEPT, "application/json");
+            request = match &self.auth {
+                Some(Auth::Bearer(token)) => request.bearer_auth(token),
+                Some(Auth::ApiKey(key)) => request.header(API_KEY, key),
+                None => request,
+            };
+            if let Some(key) = &idempotency_key {
+                request = request.header(IDEMPOTENCY_KEY, key);
+            }
+
+            let result = build(request).send().await;
+            let delay = match &result {
+                Ok(response) => self.retries.retry_response(response, attempt),
+                Err(e) => self.retries.retry_error(e, attempt),
+            };
+            let Some(delay) = delay else {
+                return Ok(result?);
+            };
+            attempt += 1;
+            log::warn!(
+                "Retrying {} {} in {:?}, attempt {}",
+                method,
+                url,
+                delay,
+                attempt
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn json<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        segments: &[&str],
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<T, ClientError> {
+        let response = self.send(method, segments, build).await?;
+        parse_response(std::any::type_name::<T>(), response).await
+    }
+
+    /// Requests of endpoints without a response body
+    async fn empty(&self, method: Method, segments: &[&str]) -> Result<(), ClientError> {
+        let response = self.send(method, segments, |request| request).await?;
+        error_for_status(response).await.map(|_| ())
+    }
+
+    /// `GET /healthcheck`
+    pub async fn healthcheck(&self) -> Result<String, ClientError> {
+        let response = self
+            .send(Method::GET, &["healthcheck"], |request| request)
+            .await?;
+        Ok(error_for_status(response).await?.text().await?)
+    }
+}
@@ -0,0 +1,36 @@
+use crate::{ApiClient, ClientError};
+use model::{
+    user_permission::{Query as UserPermissionQuery, UserPermission},
+    user_readmodel::{Query as ReadModelQuery, UserReadModel},
+};
+use reqwest::Method;
+use util::store::PaginatedResult;
+use uuid::Uuid;
+
+impl ApiClient {
+    /// `GET /users`
+    pub async fn get_users(
+        &self,
+        query: &ReadModelQuery,
+    ) -> Result<PaginatedResult<UserReadModel>, ClientError> {
+        self.json(Method::GET, &["users"], |request| request.query(query))
+            .await
+    }
+
+    /// `GET /users/{id}`
+    pub async fn get_user(&self, id: Uuid) -> Result<UserReadModel, ClientError> {
+        self.json(Method::GET, &["users", &id.to_string()], |request| request)
+            .await
+    }
+
+    /// `GET /users_permissions`
+    pub async fn get_user_permissions(
+        &self,
+        query: &UserPermissionQuery,
+    ) -> Result<PaginatedResult<UserPermission>, ClientError> {
+        self.json(Method::GET, &["users_permissions"], |request| {
+            request.query(query)
+        })
+        .await
+    }
+}
@@ -0,0 +1,163 @@
+use axum::{
+    extract::{Path, RawQuery, State},
+    http::{HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use broker::admin::TopicInfo;
+use client::{reqwest, ApiClient, Auth, ClientError, LogAndParse, RetryPolicy};
+use model::{user_readmodel::Query as ReadModelQuery, Paging};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use util::{store::PaginatedResult, JsonNum};
+
+/// What the test server received
+#[derive(Default)]
+struct Received {
+    headers: Vec<HeaderMap>,
+    query: Option<String>,
+}
+
+type Shared = Arc<Mutex<Received>>;
+
+async fn serve(router: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    format!("http://{}", address)
+}
+
+async fn server() -> (String, Shared) {
+    let received = Shared::default();
+    let router =
+        Router::new()
+            .route(
+                "/admin/broker/topics",
+                get(|| async {
+                    (
+                        StatusCode::FORBIDDEN,
+                        [("content-type", "application/problem+json")],
+                        json!({
+                            "type": "urn:problem-type:forbidden",
+                            "title": "Forbidden",
+                            "status": 403,
+                            "code": "forbidden",
+                            "detail": "Admin access is required",
+                            "instance": "/admin/broker/topics",
+                        })
+                        .to_string(),
+                    )
+                }),
+            )
+            .route(
+                "/admin/broker/topics/:topic",
+                post(
+                    |State(received): State<Shared>,
+                     Path(topic): Path<String>,
+                     headers: HeaderMap| async move {
+                        let mut received = received.lock().unwrap();
+                        received.headers.push(headers);
+                        match received.headers.len() {
+                            1 => StatusCode::SERVICE_UNAVAILABLE,
+                            _ if topic == "orders/eu" => StatusCode::OK,
+                            _ => StatusCode::NOT_FOUND,
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/users",
+                get(
+                    |State(received): State<Shared>, RawQuery(query): RawQuery| async move {
+                        received.lock().unwrap().query = query;
+                        Json(PaginatedResult::<()>::default())
+                    },
+                ),
+            )
+            .route(
+                "/topics",
+                get(|| async { Json(json!({"topics": [{"name": "orders", "length": 3}]})) }),
+            )
+            .with_state(received.clone());
+    (serve(router).await, received)
+}
+
+#[derive(Deserialize, LogAndParse)]
+struct Topics {
+    topics: Vec<TopicInfo>,
+}
+
+#[tokio::test]
+async fn maps_error_body() {
+    let (url, _) = server().await;
+    let client = ApiClient::new(&url).unwrap();
+    match client.get_topics().await {
+        Err(error @ ClientError::Api { .. }) => {
+            assert_eq!(error.status(), Some(StatusCode::FORBIDDEN));
+            assert_eq!(error.code(), Some("forbidden"));
+            assert_eq!(
+                error.to_string(),
+                "Api responded 403 Forbidden: Admin access is required"
+            );
+        }
+        other => panic!("expected an api error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn retries_with_one_idempotency_key() {
+    let (url, received) = server().await;
+    let client = ApiClient::new(&url)
+        .unwrap()
+        .with_auth(Auth::Bearer("admin".to_string()))
+        .with_retries(RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        });
+    client.add_topic("orders/eu").await.unwrap();
+
+    let received = received.lock().unwrap();
+    assert_eq!(received.headers.len(), 2);
+    let keys: Vec<_> = received
+        .headers
+        .iter()
+        .map(|headers| headers["idempotency-key"].clone())
+        .collect();
+    assert_eq!(keys[0], keys[1]);
+    assert_eq!(received.headers[1]["authorization"], "Bearer admin");
+}
+
+#[tokio::test]
+async fn sends_flattened_query() {
+    let (url, received) = server().await;
+    let client = ApiClient::new(&url).unwrap();
+    let query = ReadModelQuery {
+        email: Some("ann@example.com".to_string()),
+        paging: Some(Paging {
+            page: Some(JsonNum::I(2)),
+            ..Paging::default()
+        }),
+        ..ReadModelQuery::default()
+    };
+    client.get_users(&query).await.unwrap();
+    assert_eq!(
+        received.lock().unwrap().query.as_deref(),
+        Some("email=ann%40example.com&page=2")
+    );
+}
+
+#[tokio::test]
+async fn derives_log_and_parse() {
+    let (url, _) = server().await;
+    let response = reqwest::get(format!("{}/topics", url)).await.unwrap();
+    let Topics { topics } = Topics::log_and_parse(response).await.unwrap();
+    assert_eq!(topics[0].name, "orders");
+
+    let response = reqwest::get(format!("{}/admin/broker/topics", url))
+        .await
+        .unwrap();
+    let error = Topics::log_and_parse(response).await.err().unwrap();
+    assert_eq!(error.status(), Some(StatusCode::FORBIDDEN));
+}
@@ -1,8 +1,21 @@
 use proc_macro2::{Ident, TokenStream};
 use quote::quote;
-use syn::{parse_macro_input, Data, DataEnum, DeriveInput};
+use syn::{
+    parse_macro_input, Attribute, Data, DataEnum, DeriveInput, Fields, LitStr, Type, Variant,
+};
 
-#[proc_macro_derive(ErrorResponse, attributes(status))]
+/// Responses are RFC 7807 `application/problem+json` documents:
+///
+/// - `#[status(StatusCode::...)]` sets the status of a variant, 500 by default
+/// - `#[status(transparent)]` takes the status, code and field errors of the error the
+///   variant wraps, which must also derive `ErrorResponse`
+/// - `#[error_code("...")]` sets the `code` clients match on, the variant name in snake
+///   case by default
+/// - `#[field_errors]` on a field adds it as the `errors` of the problem, for details of
+///   which fields of a request are invalid
+///
+/// The `detail` of 5xx responses is left out so internal errors are only logged
+#[proc_macro_derive(ErrorResponse, attributes(status, error_code, field_errors))]
 pub fn derive_error_response(tokens: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(tokens as DeriveInput);
     let ident = input.ident;
@@ -14,65 +27,308 @@ pub fn derive_error_response(tokens: proc_macro::TokenStream) -> proc_macro::Tok
     }
 }
 
-fn derive_error_response_for_enum(ident: Ident, enum_data: DataEnum) -> TokenStream {
-    let status_codes = enum_data.variants.into_iter().map(|variant| {
-        let variant_name = variant.ident;
-        let attr = variant.attrs.into_iter().find(|attr| {
-            attr.path
-                .get_ident()
-                .map(|ident| *ident == "status")
-                .unwrap_or_default()
-        });
-        let match_fields = match variant.fields {
-            syn::Fields::Named(_) => quote!({ .. }),
-            syn::Fields::Unnamed(fields) => {
-                let fields = fields.unnamed.into_iter().map(|_| quote!(_));
-                quote! {
-                    (#(#fields,)*)
+/// The message of `#[error("...")]`, `None` for `#[error(transparent)]`
+fn error_message(attrs: &[Attribute]) -> Option<LitStr> {
+    let attr = attrs.iter().find(|attr| attr.path.is_ident("error"))?;
+    let group = attr.tokens.clone().into_iter().next()?;
+    let proc_macro2::TokenTree::Group(group) = group else {
+        return None;
+    };
+    syn::parse2::<LitStr>(group.stream().into_iter().next()?.into()).ok()
+}
+
+/// `#[status(...)]` of the variant or 500
+fn status(variant: &Variant) -> TokenStream {
+    variant
+        .attrs
+        .iter()
+        .find(|attr| attr.path.is_ident("status"))
+        .map(|attr| attr.tokens.clone())
+        .unwrap_or_else(|| quote!(::axum::http::StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+/// The type wrapped by a `#[status(transparent)]` variant
+fn delegated(variant: &Variant) -> Option<&Type> {
+    let attr = variant
+        .attrs
+        .iter()
+        .find(|attr| attr.path.is_ident("status"))?;
+    let transparent = attr
+        .parse_args::<Ident>()
+        .is_ok_and(|arg| arg == "transparent");
+    if !transparent {
+        return None;
+    }
+    match &variant.fields {
+        Fields::Unnamed(fields) if fields.unnamed.len() == 1 => Some(&fields.unnamed[0].ty),
+        _ => panic!(
+            "#[status(transparent)] variant {} must wrap exactly one error",
+            variant.ident
+        ),
+    }
+}
+
+/// `#[error_code("...")]` of the variant or its name in snake case
+fn error_code(variant: &Variant) -> LitStr {
+    let attr = variant
+        .attrs
+        .iter()
+        .find(|attr| attr.path.is_ident("error_code"));
+    if let Some(attr) = attr {
+        return attr
+            .parse_args::<LitStr>()
+            .expect("error_code must be a string literal, e.g. #[error_code(\"not_found\")]");
+    }
+    let name = variant.ident.to_string();
+    let mut code = String::with_capacity(name.len() + 4);
+    let mut previous: Option<char> = None;
+    for c in name.chars() {
+        if c.is_uppercase() && previous.is_some_and(|p| p.is_lowercase() || p.is_numeric()) {
+            code.push('_');
+        }
+        code.extend(c.to_lowercase());
+        previous = Some(c);
+    }
+    LitStr::new(&code, variant.ident.span())
+}
+
+/// Pattern matching any value of the variant
+fn match_fields(fields: &Fields) -> TokenStream {
+    match fields {
+        Fields::Named(_) => quote!({ .. }),
+        Fields::Unnamed(fields) => {
+            let fields = fields.unnamed.iter().map(|_| quote!(_));
+            quote!((#(#fields,)*))
+        }
+        Fields::Unit => quote!(),
+    }
+}
+
+/// Pattern binding the `#[field_errors]` field of the variant to `errors`
+fn match_field_errors(fields: &Fields) -> Option<TokenStream> {
+    let is_field_errors = |field: &syn::Field| {
+        field
+            .attrs
+            .iter()
+            .any(|attr| attr.path.is_ident("field_errors"))
+    };
+    match fields {
+        Fields::Named(fields) => {
+            let field = fields.named.iter().find(|field| is_field_errors(field))?;
+            let name = field.ident.as_ref()?;
+            Some(quote!({ #name: errors, .. }))
+        }
+        Fields::Unnamed(fields) => {
+            let index = fields.unnamed.iter().position(is_field_errors)?;
+            let fields = (0..fields.unnamed.len()).map(|i| {
+                if i == index {
+                    quote!(errors)
+                } else {
+                    quote!(_)
                 }
-            },
-            syn::Fields::Unit => quote! {},
+            });
+            Some(quote!((#(#fields,)*)))
+        }
+        Fields::Unit => None,
+    }
+}
+
+/// A response per status code the enum can return, describing the variants returning it
+fn documented_responses(ident: &Ident, enum_data: &DataEnum) -> TokenStream {
+    let errors = enum_data.variants.iter().map(|variant| {
+        if let Some(inner) = delegated(variant) {
+            return quote!(errors.extend(<#inner>::documented_errors()););
+        }
+        let status = status(variant);
+        let code = error_code(variant);
+        let message = match error_message(&variant.attrs) {
+            Some(message) => quote!(Some(#message)),
+            None => quote!(None),
         };
-        match attr {
-            Some(attr) => {
-                let status = attr.tokens;
-                quote! {
-                    Self::#variant_name #match_fields => {
-                        #[allow(unused_parens)]
-                        #status
+        quote! {
+            errors.push({
+                #[allow(unused_parens)]
+                let status: ::axum::http::StatusCode = #status;
+                (status, #code, #message)
+            });
+        }
+    });
+    quote! {
+        impl #ident {
+            /// Status, code and message of each error, for documenting them
+            #[doc(hidden)]
+            pub fn documented_errors(
+            ) -> Vec<(::axum::http::StatusCode, &'static str, Option<&'static str>)> {
+                let mut errors = Vec::new();
+                #(#errors)*
+                errors
+            }
+        }
+
+        impl ::utoipa::IntoResponses for #ident {
+            fn responses() -> ::std::collections::BTreeMap<
+                String,
+                ::utoipa::openapi::RefOr<::utoipa::openapi::response::Response>,
+            > {
+                use ::utoipa::openapi::{schema::Type, ObjectBuilder};
+
+                let mut described =
+                    ::std::collections::BTreeMap::<u16, (Vec<&str>, Vec<&str>)>::new();
+                for (status, code, message) in Self::documented_errors() {
+                    let (codes, messages) = described.entry(status.as_u16()).or_default();
+                    codes.push(code);
+                    if let Some(message) = message {
+                        messages.push(message);
                     }
                 }
-            },
-            None => {
-                quote! { Self::#variant_name #match_fields => ::axum::http::StatusCode::INTERNAL_SERVER_ERROR }
-            },
+                let string = || ObjectBuilder::new().schema_type(Type::String);
+                let field_error = ObjectBuilder::new()
+                    .property("field", string())
+                    .required("field")
+                    .property("message", string())
+                    .required("message")
+                    .build();
+                described
+                    .into_iter()
+                    .map(|(status, (codes, messages))| {
+                        let reason = ::axum::http::StatusCode::from_u16(status)
+                            .ok()
+                            .and_then(|status| status.canonical_reason())
+                            .unwrap_or_default()
+                            .to_string();
+                        let description = if messages.is_empty() {
+                            reason
+                        } else {
+                            messages.join(", or ")
+                        };
+                        let problem = ObjectBuilder::new()
+                            .property("type", string())
+                            .required("type")
+                            .property("title", string())
+                            .required("title")
+                            .property(
+                                "status",
+                                ObjectBuilder::new().schema_type(Type::Integer),
+                            )
+                            .required("status")
+                            .property("code", string().enum_values(Some(codes)))
+                            .required("code")
+                            .property("detail", string())
+                            .property("instance", string())
+                            .property(
+                                "errors",
+                                ::utoipa::openapi::ArrayBuilder::new().items(field_error.clone()),
+                            )
+                            .build();
+                        let response = ::utoipa::openapi::ResponseBuilder::new()
+                            .description(description)
+                            .content(
+                                "application/problem+json",
+                                ::utoipa::openapi::ContentBuilder::new()
+                                    .schema(Some(problem))
+                                    .build(),
+                            )
+                            .build();
+                        (status.to_string(), response.into())
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+fn derive_error_response_for_enum(ident: Ident, enum_data: DataEnum) -> TokenStream {
+    let responses = documented_responses(&ident, &enum_data);
+    let status_codes = enum_data.variants.iter().map(|variant| {
+        let variant_name = &variant.ident;
+        let match_fields = match_fields(&variant.fields);
+        if delegated(variant).is_some() {
+            return quote!(Self::#variant_name(inner) => inner.status_code());
+        }
+        let status = status(variant);
+        quote! {
+            Self::#variant_name #match_fields => {
+                #[allow(unused_parens)]
+                #status
+            }
+        }
+    });
+    let error_codes = enum_data.variants.iter().map(|variant| {
+        let variant_name = &variant.ident;
+        let match_fields = match_fields(&variant.fields);
+        if delegated(variant).is_some() {
+            return quote!(Self::#variant_name(inner) => inner.error_code());
         }
+        let code = error_code(variant);
+        quote!(Self::#variant_name #match_fields => #code)
+    });
+    let field_errors = enum_data.variants.iter().filter_map(|variant| {
+        let variant_name = &variant.ident;
+        if delegated(variant).is_some() {
+            return Some(quote!(Self::#variant_name(inner) => inner.field_errors()));
+        }
+        let match_field_errors = match_field_errors(&variant.fields)?;
+        Some(quote! {
+            Self::#variant_name #match_field_errors => ::serde_json::to_value(errors).ok()
+        })
     });
 
     quote! {
+        #responses
+
         impl #ident {
-            fn status_code(&self) -> ::axum::http::StatusCode {
+            pub fn status_code(&self) -> ::axum::http::StatusCode {
                 match self {
                     #(#status_codes,)*
                 }
             }
+
+            /// Stable code of the error for clients to match on
+            pub fn error_code(&self) -> &'static str {
+                match self {
+                    #(#error_codes,)*
+                }
+            }
+
+            /// The `#[field_errors]` of the error as json
+            pub fn field_errors(&self) -> Option<::serde_json::Value> {
+                #[allow(unreachable_patterns)]
+                match self {
+                    #(#field_errors,)*
+                    _ => None,
+                }
+            }
         }
 
         impl ::axum::response::IntoResponse for #ident {
             fn into_response(self) -> ::axum::response::Response {
                 let status = self.status_code();
-                let mut error_message = self.to_string();
+                let code = self.error_code();
+                let mut problem = ::serde_json::json!({
+                    "type": format!("urn:problem-type:{}", code),
+                    "title": status.canonical_reason().unwrap_or_default(),
+                    "status": status.as_u16(),
+                    "code": code,
+                });
 
                 if status.is_server_error() {
-                    ::tracing::error!(error_message, error_details = ?self, "internal server error");
+                    let error_message = self.to_string();
+                    ::tracing::error!(error_message, error_code = code, error_details = ?self, "internal server error");
+                } else {
+                    problem["detail"] = ::serde_json::Value::String(self.to_string());
+                }
+                if let Some(errors) = self.field_errors() {
+                    problem["errors"] = errors;
                 }
 
-                let body = ::axum::Json(::serde_json::json!({
-                    "code": status.as_u16(),
-                    "error": error_message,
-                }));
-
-                ::axum::response::IntoResponse::into_response((status, body))
+                ::axum::response::IntoResponse::into_response((
+                    status,
+                    [(
+                        ::axum::http::header::CONTENT_TYPE,
+                        ::axum::http::HeaderValue::from_static("application/problem+json"),
+                    )],
+                    problem.to_string(),
+                ))
             }
         }
 
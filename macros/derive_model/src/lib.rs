@@ -1,6 +1,6 @@
 use proc_macro2::{Ident, TokenStream};
 use quote::quote;
-use syn::{parse_macro_input, Data, DataStruct, DeriveInput, LitStr};
+use syn::{parse_macro_input, Data, DataStruct, DeriveInput, LitInt, LitStr, Path};
 
 #[proc_macro_derive(Model, attributes(model))]
 pub fn derive_model(tokens: proc_macro::TokenStream) -> proc_macro::TokenStream {
@@ -18,6 +18,8 @@ fn derive_model_struct(ident: Ident, input: &DeriveInput, struct_data: &DataStru
     let mut table_name: String = String::from("");
     let mut fields: Vec<String> = vec![];
     let mut select_fields: Vec<String> = vec![];
+    let mut read_models: Vec<(Path, Ident)> = vec![];
+    let mut cache_ttl: Option<u64> = None;
 
     for attr in &input.attrs {
         if attr.path().is_ident("model") {
@@ -29,6 +31,20 @@ fn derive_model_struct(ident: Ident, input: &DeriveInput, struct_data: &DataStru
                     table_name = lit.value();
                     return Ok(());
                 }
+                //#[model(cache_ttl = seconds)]
+                if meta.path.is_ident("cache_ttl") {
+                    let lit: LitInt = meta.value()?.parse()?;
+                    cache_ttl = Some(lit.base10_parse()?);
+                    return Ok(());
+                }
+                //#[model(read_models(ReadModelType = "field holding its key"))]
+                if meta.path.is_ident("read_models") {
+                    return meta.parse_nested_meta(|read_model| {
+                        let lit: LitStr = read_model.value()?.parse()?;
+                        read_models.push((read_model.path, Ident::new(&lit.value(), lit.span())));
+                        Ok(())
+                    });
+                }
                 Err(meta.error("unrecognized attribute"))
             });
         }
@@ -62,6 +78,75 @@ fn derive_model_struct(ident: Ident, input: &DeriveInput, struct_data: &DataStru
         }
     }
 
+    // writes to a model that read models depend on enqueue a materialize message for each of
+    // them in the same transaction, the outbox relay then publishes it through the broker.
+    // Messages are for the rows as written, an update moving a row to another key leaves the
+    // read model of the old key to be repaired
+    let (refresh_read_models, begin, commit) = if read_models.is_empty() {
+        (
+            TokenStream::new(),
+            quote!(let mut conn = db.get_conn().acquire().await.map_err(UtilError::from)?;),
+            TokenStream::new(),
+        )
+    } else {
+        let paths = read_models.iter().map(|(path, _)| path);
+        let keys = read_models.iter().map(|(_, key)| key);
+        (
+            quote! {
+                impl #ident {
+                    async fn refresh_read_models(&self, conn: &mut sqlx::PgConnection) -> Result<(), UtilError> {
+                        #(
+                            util::outbox::OutboxEntry::enqueue(
+                                &mut *conn,
+                                #table_name,
+                                &self.#keys.to_string(),
+                                &<#paths as broker::ReadModel>::topic(),
+                                &<#paths as broker::ReadModel>::key_query(self.#keys.clone()),
+                            )
+                            .await?;
+                        )*
+                        Ok(())
+                    }
+                }
+            },
+            quote!(let mut conn = db.begin().await?;),
+            quote!(conn.commit().await?;),
+        )
+    };
+    // models declared with a cache ttl cache query results when the db has a cache. Every
+    // write invalidates the table's cached queries and responses, cached or not
+    let cache_lookup = match cache_ttl {
+        Some(_) => {
+            quote!(let cache_key = util::store::QueryCache::key(db, &Self::table_name(), qb.sql(), &query.cache_key()).await;)
+        }
+        None => TokenStream::new(),
+    };
+    let invalidate = quote!(util::store::QueryCache::invalidate(db, &Self::table_name()).await;);
+    let cache_get = |ty: TokenStream| match cache_ttl {
+        Some(_) => quote! {
+            if let Some(hit) = util::store::QueryCache::get::<#ty>(db, &cache_key).await {
+                return Ok(hit);
+            }
+        },
+        None => TokenStream::new(),
+    };
+    let cache_set = |value: TokenStream| match cache_ttl {
+        Some(ttl) => quote!(util::store::QueryCache::set(db, &cache_key, &#value, #ttl).await;),
+        None => TokenStream::new(),
+    };
+    let query_cache_get = cache_get(quote!(PaginatedResult<Self>));
+    let get_cache_get = cache_get(quote!(Self));
+    let get_opt_cache_get = cache_get(quote!(Option<Self>));
+    let query_cache_set = cache_set(quote!(result));
+    let get_cache_set = cache_set(quote!(model));
+    let get_opt_cache_set = cache_set(quote!(model));
+
+    let refresh = if read_models.is_empty() {
+        TokenStream::new()
+    } else {
+        quote!(model.refresh_read_models(&mut *conn).await?;)
+    };
+
     quote! {
         use sqlx::{Postgres, FromRow,Row,QueryBuilder};
         use util::store::{Model, Pagination, SortDirection, ToSqlQuery, ToSqlSort};
@@ -104,6 +189,8 @@ fn derive_model_struct(ident: Ident, input: &DeriveInput, struct_data: &DataStru
                 } else {
                     Self::build_query(&query)
                 };
+                #cache_lookup
+                #query_cache_get
                 log::trace!(" SQL generated {:?}", qb.sql());
                 let built_query = qb.build();
                 let data = built_query
@@ -122,7 +209,9 @@ fn derive_model_struct(ident: Ident, input: &DeriveInput, struct_data: &DataStru
                         })
                     .filter_map(|r| r.ok())
                     .collect::<Vec<Self>>();
-                Self::paginated_result(records,total,query)
+                let result = Self::paginated_result(records,total,query)?;
+                #query_cache_set
+                Ok(result)
             }
 
             async fn get<Q>(query: Q,query_str: Option<String>,db: &RODB) -> Result<Self,UtilError>
@@ -134,6 +223,8 @@ fn derive_model_struct(ident: Ident, input: &DeriveInput, struct_data: &DataStru
                     QueryBuilder::new(Self::base_select())
                 };
                 query.add_where(&mut qb);
+                #cache_lookup
+                #get_cache_get
 
                 log::trace!("GET SQL generated {:?}", qb.sql());
                 let built_query = qb.build();
@@ -142,7 +233,9 @@ fn derive_model_struct(ident: Ident, input: &DeriveInput, struct_data: &DataStru
                     .await
                     .map_err(UtilError::from)?;
 
-                Ok(Self::from_row(&data)?)
+                let model = Self::from_row(&data)?;
+                #get_cache_set
+                Ok(model)
             }
 
             async fn get_opt<Q>(query: Q,query_str: Option<String>,db: &RODB) -> Result<Option<Self>,UtilError>
@@ -154,19 +247,34 @@ fn derive_model_struct(ident: Ident, input: &DeriveInput, struct_data: &DataStru
                     QueryBuilder::new(Self::base_select())
                 };
                 query.add_where(&mut qb);
+                #cache_lookup
+                #get_opt_cache_get
 
                 log::trace!("GET SQL generated {:?}", qb.sql());
                 let built_query = qb.build();
-                built_query
+                let model = built_query
                     .fetch_optional(db.get_conn())
                     .await
                     .map_err(UtilError::from)?
                     .map(|r| Self::from_row(&r))
                     .transpose()
-                    .map_err(UtilError::from)
+                    .map_err(UtilError::from)?;
+                #get_opt_cache_set
+                Ok(model)
             }
 
             async fn update<Q>(query: &Q,updated_model: impl UpdateModel,db: &RWDB) -> Result<Self,UtilError>
+            where
+                Q: ToSqlQuery + Pagination + ToSqlSort,
+            {
+                #begin
+                let model = Self::update_tx(query, updated_model, &mut conn).await?;
+                #commit
+                #invalidate
+                Ok(model)
+            }
+
+            async fn update_tx<Q>(query: &Q,updated_model: impl UpdateModel,conn: &mut sqlx::PgConnection) -> Result<Self,UtilError>
             where
                 Q: ToSqlQuery + Pagination + ToSqlSort,
             {
@@ -182,13 +290,23 @@ fn derive_model_struct(ident: Ident, input: &DeriveInput, struct_data: &DataStru
                 log::trace!("UPDATE SQL generated {:?}", qb.sql());
                 let built_query = qb.build();
                 let data = built_query
-                    .fetch_one(db.get_conn())
+                    .fetch_one(&mut *conn)
                     .await
                     .map_err(UtilError::from)?;
-                Self::from_row(&data).map_err(UtilError::from)
+                let model = Self::from_row(&data).map_err(UtilError::from)?;
+                #refresh
+                Ok(model)
             }
 
             async fn insert(new_model: impl NewModel,db: &RWDB) -> Result<Self,UtilError> {
+                #begin
+                let model = Self::insert_tx(new_model, &mut conn).await?;
+                #commit
+                #invalidate
+                Ok(model)
+            }
+
+            async fn insert_tx(new_model: impl NewModel,conn: &mut sqlx::PgConnection) -> Result<Self,UtilError> {
                 let mut qb = QueryBuilder::new(
                 format!(
                     "INSERT INTO {} ",
@@ -201,13 +319,23 @@ fn derive_model_struct(ident: Ident, input: &DeriveInput, struct_data: &DataStru
                 log::trace!("Insert SQL generated {:?}", qb.sql());
                 let built_query = qb.build();
                 let data = built_query
-                    .fetch_one(db.get_conn())
+                    .fetch_one(&mut *conn)
                     .await
                     .map_err(UtilError::from)?;
-                Self::from_row(&data).map_err(UtilError::from)
+                let model = Self::from_row(&data).map_err(UtilError::from)?;
+                #refresh
+                Ok(model)
             }
 
             async fn upsert(new_model: impl NewModel + UpdateModel,db: &RWDB) -> Result<Self,UtilError> {
+                #begin
+                let model = Self::upsert_tx(new_model, &mut conn).await?;
+                #commit
+                #invalidate
+                Ok(model)
+            }
+
+            async fn upsert_tx(new_model: impl NewModel + UpdateModel,conn: &mut sqlx::PgConnection) -> Result<Self,UtilError> {
                 let mut qb = QueryBuilder::new(
                 format!(
                     "INSERT INTO {} ",
@@ -223,11 +351,55 @@ fn derive_model_struct(ident: Ident, input: &DeriveInput, struct_data: &DataStru
                 log::trace!("Upsert SQL generated {:?}", qb.sql());
                 let built_query = qb.build();
                 let data = built_query
-                    .fetch_one(db.get_conn())
+                    .fetch_one(&mut *conn)
                     .await
                     .map_err(UtilError::from)?;
-                Self::from_row(&data).map_err(UtilError::from)
+                let model = Self::from_row(&data).map_err(UtilError::from)?;
+                #refresh
+                Ok(model)
+            }
+
+            async fn delete<Q>(query: &Q,db: &RWDB) -> Result<Vec<Self>,UtilError>
+            where
+                Q: ToSqlQuery + Pagination + ToSqlSort,
+            {
+                #begin
+                let models = Self::delete_tx(query, &mut conn).await?;
+                #commit
+                #invalidate
+                Ok(models)
+            }
+
+            async fn delete_tx<Q>(query: &Q,conn: &mut sqlx::PgConnection) -> Result<Vec<Self>,UtilError>
+            where
+                Q: ToSqlQuery + Pagination + ToSqlSort,
+            {
+                let mut filter = QueryBuilder::<sqlx::Postgres>::new("");
+                query.add_where(&mut filter);
+                if filter.sql().is_empty() {
+                    return Err(UtilError::UnfilteredDelete(Self::table_name()));
+                }
+
+                let mut qb = QueryBuilder::new(format!("DELETE FROM {}", Self::table_name()));
+                query.add_where(&mut qb);
+                qb.push(format!(" RETURNING {}",Self::select_fields_str()));
+
+                log::trace!("DELETE SQL generated {:?}", qb.sql());
+                let built_query = qb.build();
+                let data = built_query
+                    .fetch_all(&mut *conn)
+                    .await
+                    .map_err(UtilError::from)?;
+                let mut models = Vec::with_capacity(data.len());
+                for row in &data {
+                    let model = Self::from_row(row).map_err(UtilError::from)?;
+                    #refresh
+                    models.push(model);
+                }
+                Ok(models)
             }
         }
+
+        #refresh_read_models
     }
 }
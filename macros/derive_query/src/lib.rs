@@ -141,11 +141,17 @@ fn derive_query_struct(ident: Ident, struct_data: &DataStruct) -> TokenStream {
         ));
     }
 
+    let key_fields = struct_data.fields.iter().filter_map(|f| f.ident.as_ref());
+
     quote! {
         impl ToSqlQuery for #ident {
             fn add_where(&self,qb: &mut QueryBuilder<Postgres>) {
                 #builder_ast
             }
+
+            fn cache_key(&self) -> String {
+                vec![#(format!("{:?}", self.#key_fields)),*].join("|")
+            }
         }
 
         #sort_ast
@@ -0,0 +1,127 @@
+use proc_macro2::{Ident, TokenStream};
+use quote::quote;
+use syn::{parse_macro_input, Data, DataStruct, DeriveInput, LitStr, Type};
+
+#[proc_macro_derive(ReadModel, attributes(read_model))]
+pub fn derive_read_model(tokens: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(tokens as DeriveInput);
+    let ident = input.ident.clone();
+
+    match &input.data {
+        Data::Union(_) => panic!("cannot derive ReadModel for unions"),
+        Data::Struct(data) => derive_read_model_struct(ident, &input, data).into(),
+        Data::Enum(_) => panic!("cannot derive ReadModel for enums"),
+    }
+}
+
+fn derive_read_model_struct(
+    ident: Ident,
+    input: &DeriveInput,
+    struct_data: &DataStruct,
+) -> TokenStream {
+    let mut source = String::new();
+    let mut key = String::from("id");
+    let mut topic = format!("Materialize{}", ident);
+    let mut query: Option<Type> = None;
+
+    for attr in &input.attrs {
+        if attr.path().is_ident("read_model") {
+            attr.parse_nested_meta(|meta| {
+                //#[read_model(source = "view_name")]
+                if meta.path.is_ident("source") {
+                    let lit: LitStr = meta.value()?.parse()?;
+                    source = lit.value();
+                    return Ok(());
+                }
+                //#[read_model(key = "column")]
+                if meta.path.is_ident("key") {
+                    let lit: LitStr = meta.value()?.parse()?;
+                    key = lit.value();
+                    return Ok(());
+                }
+                //#[read_model(topic = "topic")]
+                if meta.path.is_ident("topic") {
+                    let lit: LitStr = meta.value()?.parse()?;
+                    topic = lit.value();
+                    return Ok(());
+                }
+                //#[read_model(query = QueryType)]
+                if meta.path.is_ident("query") {
+                    query = Some(meta.value()?.parse()?);
+                    return Ok(());
+                }
+                Err(meta.error("unrecognized attribute"))
+            })
+            .unwrap_or_else(|e| panic!("invalid read_model attribute {e}"));
+        }
+    }
+    if source.is_empty() {
+        panic!("ReadModel `source` must be set");
+    }
+    let Some(query) = query else {
+        panic!("ReadModel `query` must be set");
+    };
+    let Some(key_field) = struct_data
+        .fields
+        .iter()
+        .find(|f| f.ident.as_ref().is_some_and(|i| *i == key))
+    else {
+        panic!("ReadModel `key` must name a field");
+    };
+    let key_ident = key_field.ident.clone();
+    let key_type = key_field.ty.clone();
+
+    quote! {
+        impl broker::ReadModel for #ident {
+            type Query = #query;
+            type Key = #key_type;
+
+            fn source_view() -> String {
+                #source.to_owned()
+            }
+
+            fn key_column() -> String {
+                #key.to_owned()
+            }
+
+            fn topic() -> String {
+                #topic.to_owned()
+            }
+
+            fn key_query(key: Self::Key) -> Self::Query {
+                #query {
+                    #key_ident: Some(key),
+                    ..Default::default()
+                }
+            }
+        }
+
+        impl broker::Subscriber for #ident {
+            type MessageType = #query;
+
+            fn handle_message(
+                &self,
+                message: Self::MessageType,
+                state: std::sync::Arc<impl util::AppState>,
+            ) -> impl std::future::Future<Output = Result<(), util::error::UtilError>> + Send {
+                log::trace!(
+                    "message received on {} attempting to materialize read model",
+                    <Self as broker::ReadModel>::topic()
+                );
+                let ro_db = state.get_ro_store().clone();
+                let rw_db = state.get_rw_store().clone();
+                async move {
+                    <Self as broker::ReadModel>::materialize(message, &ro_db, &rw_db).await
+                }
+            }
+
+            fn topic(&self) -> String {
+                <Self as broker::ReadModel>::topic()
+            }
+
+            fn group_name(&self) -> String {
+                <Self as broker::ReadModel>::topic()
+            }
+        }
+    }
+}
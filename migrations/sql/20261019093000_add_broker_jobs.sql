@@ -0,0 +1,30 @@
+create table broker_groups (
+  topic text not null,
+  group_name text not null,
+  created_at timestamptz not null default now(),
+  primary key (topic, group_name)
+);
+
+create table broker_messages (
+  id bigserial primary key,
+  topic text not null,
+  params jsonb not null,
+  created_at timestamptz not null default now()
+);
+
+create index broker_messages_topic_idx on broker_messages (topic, id);
+
+create table broker_jobs (
+  id bigserial primary key,
+  topic text not null,
+  group_name text not null,
+  message_id bigint not null references broker_messages (id) on delete cascade,
+  attempts int not null default 0,
+  claimed_by text,
+  claimed_at timestamptz,
+  created_at timestamptz not null default now(),
+  unique (topic, group_name, message_id),
+  foreign key (topic, group_name) references broker_groups (topic, group_name) on delete cascade
+);
+
+create index broker_jobs_claim_idx on broker_jobs (topic, group_name, id);
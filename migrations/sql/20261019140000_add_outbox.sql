@@ -0,0 +1,11 @@
+create table outbox (
+  id bigserial primary key,
+  aggregate_type text not null,
+  aggregate_id text not null,
+  topic text not null,
+  params jsonb not null,
+  created_at timestamptz not null default now(),
+  sent_at timestamptz
+);
+
+create index outbox_unsent_idx on outbox (id) where sent_at is null;
@@ -0,0 +1,10 @@
+alter table broker_messages add column message_id text;
+
+create table processed_messages (
+  group_name text not null,
+  message_id text not null,
+  processed_at timestamptz not null default now(),
+  primary key (group_name, message_id)
+);
+
+create index processed_messages_processed_at_idx on processed_messages (processed_at);
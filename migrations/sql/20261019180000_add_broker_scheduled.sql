@@ -0,0 +1,9 @@
+create table broker_scheduled (
+  message_id text primary key,
+  topic text not null,
+  params jsonb not null,
+  deliver_at timestamptz not null,
+  created_at timestamptz not null default now()
+);
+
+create index broker_scheduled_deliver_at_idx on broker_scheduled (deliver_at);
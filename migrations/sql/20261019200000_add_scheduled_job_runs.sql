@@ -0,0 +1,13 @@
+create table scheduled_job_runs (
+  id bigserial primary key,
+  job_name text not null,
+  instance text not null,
+  scheduled_for timestamptz not null,
+  started_at timestamptz not null default now(),
+  finished_at timestamptz,
+  duration_ms bigint,
+  succeeded bool,
+  error text
+);
+
+create index scheduled_job_runs_job_idx on scheduled_job_runs (job_name, started_at desc);
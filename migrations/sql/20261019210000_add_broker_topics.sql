@@ -0,0 +1,4 @@
+create table broker_topics (
+  topic text primary key,
+  created_at timestamptz not null default now()
+);
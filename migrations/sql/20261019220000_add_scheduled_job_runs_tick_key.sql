@@ -0,0 +1,2 @@
+alter table scheduled_job_runs
+  add constraint scheduled_job_runs_tick_key unique (job_name, scheduled_for);
@@ -1,10 +1,12 @@
+use derive_axum_errors::ErrorResponse;
 use thiserror::Error as ThisError;
 use util::error::UtilError;
 
-#[derive(Debug, ThisError)]
+#[derive(ThisError, ErrorResponse)]
 pub enum ModelError {
     #[error("Cant Materialize view no rows match query")]
     RowCantMaterialize,
     #[error(transparent)]
+    #[status(transparent)]
     Util(#[from] UtilError),
 }
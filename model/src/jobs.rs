@@ -0,0 +1,134 @@
+use crate::read_models;
+use broker::scheduler::{Job, JobRun};
+use std::sync::Arc;
+use util::{
+    error::UtilError,
+    store::{RODB, RWDB},
+    AppState,
+};
+
+/// Every job the scheduler runs, `jobs` returns one of each
+#[derive(Debug, Clone)]
+pub enum ModelJob {
+    PurgeBrokerHistory(PurgeBrokerHistory),
+    CheckReadModels(CheckReadModels),
+}
+
+impl Job for ModelJob {
+    fn name(&self) -> String {
+        match self {
+            Self::PurgeBrokerHistory(job) => job.name(),
+            Self::CheckReadModels(job) => job.name(),
+        }
+    }
+
+    fn schedule(&self) -> String {
+        match self {
+            Self::PurgeBrokerHistory(job) => job.schedule(),
+            Self::CheckReadModels(job) => job.schedule(),
+        }
+    }
+
+    fn run(
+        &self,
+        app_state: Arc<impl AppState>,
+    ) -> impl std::future::Future<Output = Result<(), UtilError>> + Send {
+        let job = self.clone();
+        let ro_db = app_state.get_ro_store().clone();
+        let rw_db = app_state.get_rw_store().clone();
+        async move {
+            match job {
+                Self::PurgeBrokerHistory(_) => PurgeBrokerHistory::purge(rw_db).await,
+                Self::CheckReadModels(job) => job.check(ro_db, rw_db).await,
+            }
+        }
+    }
+}
+
+/// Deletes sent outbox entries, dedup records and scheduled job runs once they are older
+/// than a week
+#[derive(Debug, Default, Clone)]
+pub struct PurgeBrokerHistory;
+
+impl Job for PurgeBrokerHistory {
+    fn name(&self) -> String {
+        "purge_broker_history".to_string()
+    }
+
+    fn schedule(&self) -> String {
+        "0 0 3 * * *".to_string()
+    }
+
+    fn run(
+        &self,
+        app_state: Arc<impl AppState>,
+    ) -> impl std::future::Future<Output = Result<(), UtilError>> + Send {
+        Self::purge(app_state.get_rw_store().clone())
+    }
+}
+
+impl PurgeBrokerHistory {
+    async fn purge(rw_db: RWDB) -> Result<(), UtilError> {
+        let db = rw_db.get_conn();
+        sqlx::query("DELETE FROM outbox WHERE sent_at < now() - interval '7 days'")
+            .execute(db)
+            .await?;
+        sqlx::query(
+            "DELETE FROM processed_messages WHERE processed_at < now() - interval '7 days'",
+        )
+        .execute(db)
+        .await?;
+        JobRun::purge(&rw_db, 7).await?;
+        Ok(())
+    }
+}
+
+/// Compares every read model with its source view, logging the rows that differ and with
+/// `repair` set enqueueing materialize messages to fix them
+#[derive(Debug, Default, Clone)]
+pub struct CheckReadModels {
+    pub repair: bool,
+}
+
+impl Job for CheckReadModels {
+    fn name(&self) -> String {
+        "check_read_models".to_string()
+    }
+
+    fn schedule(&self) -> String {
+        "0 30 3 * * *".to_string()
+    }
+
+    fn run(
+        &self,
+        app_state: Arc<impl AppState>,
+    ) -> impl std::future::Future<Output = Result<(), UtilError>> + Send {
+        self.clone().check(
+            app_state.get_ro_store().clone(),
+            app_state.get_rw_store().clone(),
+        )
+    }
+}
+
+impl CheckReadModels {
+    async fn check(self, ro_db: RODB, rw_db: RWDB) -> Result<(), UtilError> {
+        for read_model in read_models() {
+            let report = read_model.check(&ro_db).await?;
+            if report.is_consistent() {
+                continue;
+            }
+            log::warn!(
+                "Read model {} differs from {}, {} missing {} stale {} orphaned rows",
+                read_model.table,
+                read_model.source_view,
+                report.missing.len(),
+                report.stale.len(),
+                report.orphaned.len()
+            );
+            if self.repair {
+                read_model.repair(&report, &rw_db).await?;
+            }
+        }
+        Ok(())
+    }
+}
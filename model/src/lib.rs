@@ -1,26 +1,32 @@
 pub mod error;
+pub mod jobs;
 pub mod user;
 pub mod user_permission;
 pub mod user_readmodel;
 use minijinja::Environment as TemplateEnv;
 
-use crate::{error::ModelError, user_readmodel::UserReadModel};
-use broker::{BrokerLayer, RedisStream, Subscriber};
+use crate::{
+    error::ModelError,
+    jobs::{CheckReadModels, ModelJob, PurgeBrokerHistory},
+    user_readmodel::UserReadModel,
+};
+use broker::{scheduler::Job, Broker, BrokerLayer, ReadModelTable, Subscriber};
 use serde::{Deserialize, Serialize};
 use util::{
+    cache::AppCache,
     env::Env,
-    store::{CacheLayer, Pagination, Redis, RODB, RWDB},
+    store::{CacheLayer, Pagination, RODB, RWDB},
     AppState, JsonNum,
 };
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 
 #[derive(Clone)]
 pub struct State {
     pub rw_db: RWDB,
     pub ro_db: RODB,
     pub env: Env,
-    pub cache: Redis,
-    pub broker: Option<RedisStream>,
+    pub cache: AppCache,
+    pub broker: Option<Broker>,
     pub template_env: Option<TemplateEnv<'static>>,
 }
 
@@ -32,11 +38,12 @@ impl AppState for State {
         let mut template_env = TemplateEnv::new();
         template_env.set_loader(minijinja::path_loader("frontend/src/templates"));
 
+        let cache = AppCache::new(&env).await?;
         Ok(Self {
-            rw_db: RWDB::connect(&env).await?,
-            ro_db: RODB::connect(&env).await?,
-            cache: Redis::new(&env).await?,
-            broker: Some(RedisStream::new(&env).await?),
+            rw_db: RWDB::connect(&env).await?.with_cache(cache.clone()),
+            ro_db: RODB::connect(&env).await?.with_cache(cache.clone()),
+            cache,
+            broker: Some(Broker::new(&env).await?),
             env,
             template_env: Some(template_env),
         })
@@ -62,7 +69,20 @@ pub fn subscribers() -> Vec<impl Subscriber> {
     subs
 }
 
-#[derive(Serialize, PartialEq, Deserialize, ToSchema, Clone, Debug, Default)]
+/// Read models the cli can rebuild and check by table name
+pub fn read_models() -> Vec<ReadModelTable> {
+    vec![ReadModelTable::of::<UserReadModel>()]
+}
+
+pub fn jobs() -> Vec<impl Job> {
+    vec![
+        ModelJob::PurgeBrokerHistory(PurgeBrokerHistory),
+        ModelJob::CheckReadModels(CheckReadModels { repair: true }),
+    ]
+}
+
+#[derive(Serialize, PartialEq, Deserialize, ToSchema, IntoParams, Clone, Debug, Default)]
+#[into_params(parameter_in = Query)]
 pub struct Paging {
     pub page: Option<JsonNum>,
     pub limit: Option<JsonNum>,
@@ -175,6 +195,10 @@ mod test {
         };
 
         assert_eq!(TestModel::build_query(&query).sql(), r#"SELECT test,db_col_name AS test2,CAST(COUNT(*) OVER() AS BigInt) AS total FROM test_tbl  WHERE test = $1 AND test2 = $2 ORDER BY "Test" asc FETCH NEXT $3 ROWS ONLY OFFSET $4"#.to_string());
+        assert_eq!(
+            query.cache_key(),
+            r#"Some("some string")|"some string"|None|None"#
+        );
     }
 
     #[derive(Debug, Default, PartialEq, ToParams, FromParams)]
@@ -189,4 +213,18 @@ mod test {
         let query_vec = query.to_params();
         assert_eq!(Query2::from_params(query_vec), query);
     }
+
+    #[test]
+    fn registers_read_models() {
+        let tables = read_models();
+        assert_eq!(tables[0].table, "user_readmodels");
+        assert_eq!(tables[0].shadow_table(), "user_readmodels_shadow");
+    }
+
+    #[test]
+    fn job_schedules_parse() {
+        for job in jobs() {
+            assert!(broker::scheduler::parse_schedule(&job).is_ok());
+        }
+    }
 }
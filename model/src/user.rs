@@ -1,4 +1,4 @@
-use super::{error::ModelError, Paging};
+use super::{error::ModelError, user_readmodel::UserReadModel, Paging};
 use chrono::{DateTime, Utc};
 use derive_model::Model;
 use derive_new_model::NewModel;
@@ -10,11 +10,11 @@ use util::{
     macros::make_sort,
     store::{NewModel, PaginatedResult, UpdateModel, RODB, RWDB},
 };
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Default, Clone, Model, ToSchema)]
-#[model(table_name = "users")]
+#[model(table_name = "users", read_models(UserReadModel = "id"))]
 pub struct User {
     pub id: Uuid,
     pub external_id: Option<String>,
@@ -56,14 +56,17 @@ pub enum SortColumn {
 
 make_sort!(UserSort, SortColumn);
 
-#[derive(Debug, Serialize, Deserialize, ToSchema, Default, Clone, Query)]
+#[derive(Debug, Serialize, Deserialize, ToSchema, IntoParams, Default, Clone, Query)]
+#[into_params(parameter_in = Query)]
 pub struct Query {
     pub id: Option<Uuid>,
     pub display_name: Option<String>,
     pub email: Option<String>,
     #[serde(flatten)]
+    #[param(ignore)]
     pub sort: Option<UserSort>,
     #[serde(flatten)]
+    #[param(ignore)]
     pub paging: Option<Paging>,
 }
 
@@ -114,4 +117,21 @@ mod test {
             .await
             .unwrap();
     }
+
+    #[tokio::test]
+    async fn delete_requires_filter() {
+        let state = TestApiState::from_test_env().await.unwrap();
+        let user = User::insert(NewUser::default(), state.get_rw_store())
+            .await
+            .unwrap();
+
+        let deleted = User::delete(&Query::default(), state.get_rw_store()).await;
+        assert!(matches!(deleted, Err(UtilError::UnfilteredDelete(_))));
+        let query = Query {
+            id: Some(user.id),
+            ..Query::default()
+        };
+        let deleted = User::delete(&query, state.get_rw_store()).await.unwrap();
+        assert_eq!(deleted.len(), 1);
+    }
 }
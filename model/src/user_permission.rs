@@ -1,4 +1,4 @@
-use crate::Paging;
+use crate::{user_readmodel::UserReadModel, Paging};
 use chrono::{DateTime, Utc};
 use derive_model::Model;
 use derive_new_model::NewModel;
@@ -10,7 +10,7 @@ use util::{
     macros::make_sort,
     store::{NewModel, PaginatedResult, UpdateModel, RODB, RWDB},
 };
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize, ToSchema, sqlx::Type, Default, PartialEq, Clone)]
@@ -22,7 +22,10 @@ pub enum Target {
 }
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Default, Clone, Model, ToSchema)]
-#[model(table_name = "user_permissions")]
+#[model(
+    table_name = "user_permissions",
+    read_models(UserReadModel = "user_id")
+)]
 pub struct UserPermission {
     pub id: Uuid,
     pub created_at: DateTime<Utc>,
@@ -66,7 +69,8 @@ pub enum SortColumn {
 
 make_sort!(UserPermissionSort, SortColumn);
 
-#[derive(Debug, Serialize, Deserialize, ToSchema, Default, Clone, Query)]
+#[derive(Debug, Serialize, Deserialize, ToSchema, IntoParams, Default, Clone, Query)]
+#[into_params(parameter_in = Query)]
 pub struct Query {
     pub id: Option<Uuid>,
     pub user_id: Option<Uuid>,
@@ -76,7 +80,9 @@ pub struct Query {
     pub view_record: Option<bool>,
     pub delete_record: Option<bool>,
     #[serde(flatten)]
+    #[param(ignore)]
     pub sort: Option<UserPermissionSort>,
     #[serde(flatten)]
+    #[param(ignore)]
     pub paging: Option<Paging>,
 }
@@ -1,19 +1,18 @@
 use crate::{user_permission::Target, Paging};
-use broker::Subscriber;
 use derive_model::Model;
 use derive_new_model::NewModel;
 use derive_query::Query;
+use derive_read_model::ReadModel;
 use derive_update_model::UpdateModel;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
 use to_params::{FromParams, ToParams};
 use util::{
     error::UtilError,
     make_sort,
     store::{NewModel, PaginatedResult, UpdateModel, RODB, RWDB},
-    AppState, FromParams, ToParams,
+    FromParams, ToParams,
 };
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone, ToSchema)]
@@ -37,9 +36,11 @@ pub struct EmbeddedPermission {
     Model,
     NewModel,
     UpdateModel,
+    ReadModel,
     ToSchema,
 )]
-#[model(table_name = "user_readmodels")]
+#[model(table_name = "user_readmodels", cache_ttl = 60)]
+#[read_model(source = "user_readmodels_v", key = "id", query = Query)]
 pub struct UserReadModel {
     pub id: Uuid,
     pub external_id: Option<String>,
@@ -58,68 +59,31 @@ pub enum SortColumn {
 
 make_sort!(UserReadModelSort, SortColumn);
 
-#[derive(Debug, Serialize, Deserialize, ToSchema, Default, Clone, Query, FromParams, ToParams)]
+#[derive(
+    Debug, Serialize, Deserialize, ToSchema, IntoParams, Default, Clone, Query, FromParams, ToParams,
+)]
+#[into_params(parameter_in = Query)]
 pub struct Query {
     pub id: Option<Uuid>,
     pub display_name: Option<String>,
     pub email: Option<String>,
     #[serde(flatten)]
+    #[param(ignore)]
     pub sort: Option<UserReadModelSort>,
     #[serde(flatten)]
+    #[param(ignore)]
     pub paging: Option<Paging>,
 }
 
-impl Subscriber for UserReadModel {
-    type MessageType = Query;
-
-    fn handle_message(
-        &self,
-        message: Self::MessageType,
-        state: Arc<impl AppState>,
-    ) -> impl std::future::Future<Output = Result<(), UtilError>> + Send {
-        log::trace!(
-            "message received on {} attempting to materialize read model",
-            self.topic()
-        );
-        UserReadModel::materialize(
-            message,
-            state.get_ro_store().clone(),
-            state.get_rw_store().clone(),
-        )
-    }
-
-    fn topic(&self) -> String {
-        "MaterializeUserReadModel".to_string()
-    }
-    fn group_name(&self) -> String {
-        "MaterializeUserReadModel".to_string()
-    }
-}
-
-impl UserReadModel {
-    async fn materialize(query: Query, ro_db: RODB, rw_db: RWDB) -> Result<(), UtilError> {
-        let read_model = Self::get(
-            query,
-            Some(
-                "select id,external_id,display_name,email,permissions from user_readmodels_v"
-                    .to_owned(),
-            ),
-            &ro_db,
-        )
-        .await?;
-        let _ = Self::upsert(read_model.clone(), &rw_db).await?;
-        Ok(())
-    }
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::{
-        user::{NewUser, User},
+        user::{self, NewUser, UpdateUser, User},
         user_permission::{NewUserPermission, Target, UserPermission},
     };
-    use util::{tests::TestApiState, AppState};
+    use broker::{ReadModel, Subscriber};
+    use util::{cache::AppCache, tests::TestApiState, AppState};
 
     #[tokio::test]
     async fn materialize_user_read_model() {
@@ -133,13 +97,9 @@ mod test {
             id: Some(user.id),
             ..Query::default()
         };
-        UserReadModel::materialize(
-            query.clone(),
-            state.get_ro_store().clone(),
-            state.get_rw_store().clone(),
-        )
-        .await
-        .unwrap();
+        UserReadModel::materialize(query.clone(), state.get_ro_store(), state.get_rw_store())
+            .await
+            .unwrap();
 
         let new_perm = NewUserPermission {
             user_id: user.id,
@@ -151,12 +111,163 @@ mod test {
             .await
             .unwrap();
 
-        UserReadModel::materialize(
-            query,
-            state.get_ro_store().clone(),
-            state.get_rw_store().clone(),
+        UserReadModel::materialize(query, state.get_ro_store(), state.get_rw_store())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn materialize_removes_deleted_user() {
+        let state = TestApiState::from_test_env().await.unwrap();
+        let user = User::insert(NewUser::default(), state.get_rw_store())
+            .await
+            .unwrap();
+        let query = Query {
+            id: Some(user.id),
+            ..Query::default()
+        };
+        UserReadModel::materialize(query.clone(), state.get_ro_store(), state.get_rw_store())
+            .await
+            .unwrap();
+
+        let user_query = user::Query {
+            id: Some(user.id),
+            ..user::Query::default()
+        };
+        User::delete(&user_query, state.get_rw_store())
+            .await
+            .unwrap();
+        UserReadModel::materialize(query.clone(), state.get_ro_store(), state.get_rw_store())
+            .await
+            .unwrap();
+        assert!(
+            UserReadModel::get_opt(query.clone(), None, state.get_ro_store())
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(matches!(
+            UserReadModel::materialize(query, state.get_ro_store(), state.get_rw_store()).await,
+            Err(UtilError::RowCantMaterialize)
+        ));
+    }
+
+    #[tokio::test]
+    async fn materialize_removes_rows_leaving_the_view() {
+        let state = TestApiState::from_test_env().await.unwrap();
+        let display_name = Uuid::new_v4().to_string();
+        let mut users = Vec::new();
+        for _ in 0..2 {
+            let new_user = NewUser {
+                display_name: Some(display_name.clone()),
+                ..NewUser::default()
+            };
+            users.push(User::insert(new_user, state.get_rw_store()).await.unwrap());
+        }
+        let query = Query {
+            display_name: Some(display_name),
+            ..Query::default()
+        };
+        UserReadModel::materialize(query.clone(), state.get_ro_store(), state.get_rw_store())
+            .await
+            .unwrap();
+        let materialized = UserReadModel::query(query.clone(), None, state.get_ro_store())
+            .await
+            .unwrap();
+        assert_eq!(materialized.data.len(), 2);
+
+        let user_query = user::Query {
+            id: Some(users[0].id),
+            ..user::Query::default()
+        };
+        User::delete(&user_query, state.get_rw_store())
+            .await
+            .unwrap();
+        UserReadModel::materialize(query.clone(), state.get_ro_store(), state.get_rw_store())
+            .await
+            .unwrap();
+        let materialized = UserReadModel::query(query, None, state.get_ro_store())
+            .await
+            .unwrap();
+        assert_eq!(materialized.data.len(), 1);
+        assert_eq!(materialized.data[0].id, users[1].id);
+    }
+
+    #[tokio::test]
+    async fn permission_write_enqueues_materialize() {
+        let state = TestApiState::from_test_env().await.unwrap();
+        let user = User::insert(NewUser::default(), state.get_rw_store())
+            .await
+            .unwrap();
+        let new_perm = NewUserPermission {
+            user_id: user.id,
+            ..NewUserPermission::default()
+        };
+        UserPermission::insert(new_perm, state.get_rw_store())
+            .await
+            .unwrap();
+
+        let (topic, params) = sqlx::query_as::<_, (String, sqlx::types::Json<Vec<String>>)>(
+            "SELECT topic, params FROM outbox WHERE aggregate_type = 'user_permissions'
+            ORDER BY id DESC LIMIT 1",
         )
+        .fetch_one(state.get_rw_store().get_conn())
         .await
         .unwrap();
+        assert_eq!(topic, <UserReadModel as ReadModel>::topic());
+        assert_eq!(Query::from_params(params.0).id, Some(user.id));
+    }
+
+    #[tokio::test]
+    async fn user_write_invalidates_cached_query() {
+        let state = TestApiState::from_test_env().await.unwrap();
+        let cache = AppCache::Redis(state.cache.clone());
+        let ro_db = state.get_ro_store().clone().with_cache(cache.clone());
+        let rw_db = state.get_rw_store().clone().with_cache(cache);
+        let user = User::insert(NewUser::default(), &rw_db).await.unwrap();
+        let query = Query {
+            id: Some(user.id),
+            ..Query::default()
+        };
+        UserReadModel::materialize(query.clone(), &ro_db, &rw_db)
+            .await
+            .unwrap();
+        let cached = UserReadModel::get(query.clone(), None, &ro_db)
+            .await
+            .unwrap();
+        assert_eq!(cached.display_name, None);
+
+        let user_query = user::Query {
+            id: Some(user.id),
+            ..user::Query::default()
+        };
+        let update = UpdateUser {
+            display_name: Some("cached".to_string()),
+            ..UpdateUser::default()
+        };
+        User::update(&user_query, update, &rw_db).await.unwrap();
+        UserReadModel::materialize(query.clone(), &ro_db, &rw_db)
+            .await
+            .unwrap();
+        let refreshed = UserReadModel::get(query, None, &ro_db).await.unwrap();
+        assert_eq!(refreshed.display_name, Some("cached".to_string()));
+    }
+
+    #[test]
+    fn declares_read_model() {
+        assert_eq!(UserReadModel::source_view(), "user_readmodels_v");
+        assert_eq!(UserReadModel::key_column(), "id");
+        assert_eq!(
+            UserReadModel::default().topic(),
+            "MaterializeUserReadModel".to_string()
+        );
+        assert_eq!(
+            UserReadModel::source_select(),
+            "SELECT id,external_id,display_name,email,permissions FROM user_readmodels_v"
+        );
+        let id = Uuid::now_v7();
+        let query = UserReadModel::key_query(id);
+        assert_eq!(query.id, Some(id));
+        assert_eq!(query.email, None);
     }
 }
@@ -0,0 +1,690 @@
+use crate::{
+    env::Env,
+    error::UtilError,
+    store::{CacheEncoding, CacheLayer, RateLimit, Redis},
+};
+use futures::StreamExt;
+use redis::aio::PubSub;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+/// Used when `memory_cache_max_entries` is not set
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+/// Used when `memory_cache_ttl` is not set
+const DEFAULT_LOCAL_TTL: u64 = 60;
+const INVALIDATION_CHANNEL: &str = "cache_invalidation";
+
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+    /// Position in `Entries::recency`
+    used: u64,
+}
+
+/// Values by key with the keys ordered from least to most recently used
+#[derive(Default)]
+struct Entries {
+    values: HashMap<String, Entry>,
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl Entries {
+    fn get(&mut self, key: &str) -> Option<&[u8]> {
+        let expires_at = self.values.get(key)?.expires_at;
+        if expires_at.is_some_and(|at| at <= Instant::now()) {
+            self.remove(key);
+            return None;
+        }
+        self.clock += 1;
+        let entry = self.values.get_mut(key)?;
+        self.recency.remove(&entry.used);
+        entry.used = self.clock;
+        self.recency.insert(self.clock, key.to_owned());
+        Some(&entry.value)
+    }
+
+    fn insert(
+        &mut self,
+        key: &str,
+        value: Vec<u8>,
+        expires_at: Option<Instant>,
+        max_entries: usize,
+    ) {
+        self.remove(key);
+        while self.values.len() >= max_entries {
+            let Some((_, evicted)) = self.recency.pop_first() else {
+                break;
+            };
+            self.values.remove(&evicted);
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, key.to_owned());
+        self.values.insert(
+            key.to_owned(),
+            Entry {
+                value,
+                expires_at,
+                used: self.clock,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.values.remove(key)?;
+        self.recency.remove(&entry.used);
+        Some(entry)
+    }
+}
+
+fn expires_at(expires: Option<u64>) -> Option<Instant> {
+    expires.map(|seconds| Instant::now() + Duration::from_secs(seconds))
+}
+
+/// A cache held in the process, for tests and deployments with a single node. Holds at most
+/// `max_entries` values, evicting the least recently used when full, and drops values once
+/// they expire. Clones share the same values
+#[derive(Clone)]
+pub struct MemoryCache {
+    entries: Arc<Mutex<Entries>>,
+    pub max_entries: usize,
+    pub encoding: CacheEncoding,
+}
+
+impl MemoryCache {
+    pub fn with_capacity(max_entries: usize, encoding: CacheEncoding) -> Self {
+        Self {
+            entries: Arc::default(),
+            max_entries: max_entries.max(1),
+            encoding,
+        }
+    }
+
+    /// A panic while the lock was held cannot leave the entries inconsistent, so a poisoned
+    /// lock is used as is
+    fn entries(&self) -> MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn remove_all(&self, keys: &[String]) {
+        let mut entries = self.entries();
+        for key in keys {
+            entries.remove(key);
+        }
+    }
+
+    pub fn clear(&self) {
+        *self.entries() = Entries::default();
+    }
+
+    /// `Redis::rate_limit` counted in the process, the same GCRA schedule kept in `entries`
+    pub fn rate_limit(&self, key: &str, limit: u64, period: Duration) -> RateLimit {
+        let key = format!("rate_limit:{key}");
+        let period_ms = period.as_millis().max(1) as f64;
+        let interval = period_ms / limit.max(1) as f64;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as f64;
+        let mut entries = self.entries();
+        let tat = entries
+            .get(&key)
+            .and_then(|value| std::str::from_utf8(value).ok()?.parse::<f64>().ok())
+            .unwrap_or(now)
+            .max(now);
+        let next_tat = tat + interval;
+        if next_tat - period_ms > now {
+            return RateLimit {
+                allowed: false,
+                limit,
+                remaining: 0,
+                reset_after: Duration::from_millis((tat - now).ceil() as u64),
+                retry_after: Duration::from_millis((next_tat - period_ms - now).ceil() as u64),
+            };
+        }
+        let reset_after = Duration::from_millis((next_tat - now).ceil() as u64);
+        entries.insert(
+            &key,
+            next_tat.to_string().into_bytes(),
+            Some(Instant::now() + reset_after),
+            self.max_entries,
+        );
+        RateLimit {
+            allowed: true,
+            limit,
+            remaining: ((now + period_ms - next_tat) / interval).floor() as u64,
+            reset_after,
+            retry_after: Duration::ZERO,
+        }
+    }
+}
+
+impl CacheLayer for MemoryCache {
+    async fn new(env: &Env) -> Result<Self, UtilError> {
+        Ok(Self::with_capacity(
+            env.memory_cache_max_entries.unwrap_or(DEFAULT_MAX_ENTRIES),
+            CacheEncoding::from_env(env)?,
+        ))
+    }
+
+    fn encoding(&self) -> CacheEncoding {
+        self.encoding
+    }
+
+    async fn set_value(
+        &self,
+        key: &str,
+        value: &str,
+        expires: Option<u64>,
+    ) -> Result<(), UtilError> {
+        self.set_bytes(key, value.as_bytes(), expires).await
+    }
+
+    async fn set_value_if_absent(
+        &self,
+        key: &str,
+        value: &str,
+        expires: Option<u64>,
+    ) -> Result<bool, UtilError> {
+        let mut entries = self.entries();
+        if entries.get(key).is_some() {
+            return Ok(false);
+        }
+        entries.insert(
+            key,
+            value.as_bytes().to_vec(),
+            expires_at(expires),
+            self.max_entries,
+        );
+        Ok(true)
+    }
+
+    async fn delete_value(&self, key: &str) -> Result<(), UtilError> {
+        self.entries().remove(key);
+        Ok(())
+    }
+
+    async fn get_value(&self, key: &str) -> Result<Option<String>, UtilError> {
+        Ok(self
+            .entries()
+            .get(key)
+            .map(|value| String::from_utf8_lossy(value).into_owned()))
+    }
+
+    async fn value_exists(&self, key: &str) -> Result<bool, UtilError> {
+        Ok(self.entries().get(key).is_some())
+    }
+
+    /// Keeps the expiry of the current value like Redis `INCR`
+    async fn increment(&self, key: &str) -> Result<i64, UtilError> {
+        let mut entries = self.entries();
+        let current = match entries.get(key) {
+            Some(value) => std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse::<i64>().ok())
+                .ok_or_else(|| UtilError::Other(format!("{key} is not an integer")))?,
+            None => 0,
+        };
+        let expires_at = entries.values.get(key).and_then(|entry| entry.expires_at);
+        let next = current + 1;
+        entries.insert(
+            key,
+            next.to_string().into_bytes(),
+            expires_at,
+            self.max_entries,
+        );
+        Ok(next)
+    }
+
+    async fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>, UtilError> {
+        Ok(self.entries().get(key).map(<[u8]>::to_vec))
+    }
+
+    async fn set_bytes(
+        &self,
+        key: &str,
+        value: &[u8],
+        expires: Option<u64>,
+    ) -> Result<(), UtilError> {
+        self.entries()
+            .insert(key, value.to_vec(), expires_at(expires), self.max_entries);
+        Ok(())
+    }
+
+    async fn get_many_bytes(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, UtilError> {
+        let mut entries = self.entries();
+        Ok(keys
+            .iter()
+            .map(|key| entries.get(key).map(<[u8]>::to_vec))
+            .collect())
+    }
+
+    async fn set_many_bytes(
+        &self,
+        values: &[(String, Vec<u8>)],
+        expires: Option<u64>,
+    ) -> Result<(), UtilError> {
+        let mut entries = self.entries();
+        for (key, value) in values {
+            entries.insert(key, value.clone(), expires_at(expires), self.max_entries);
+        }
+        Ok(())
+    }
+}
+
+/// Keys written by one `TieredCache`, published so the others drop their copies
+#[derive(Debug, Serialize, Deserialize)]
+struct Invalidation {
+    origin: String,
+    keys: Vec<String>,
+}
+
+/// Stops listening for invalidations once the last clone of the cache is dropped
+struct Listener(JoinHandle<()>);
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Redis fronted by a `MemoryCache`. Reads are served locally when they can be, writes go
+/// to Redis and are published so every other node drops its local copy. Local copies are
+/// kept for at most `local_ttl` seconds, bounding how stale a node can be if it misses an
+/// invalidation
+#[derive(Clone)]
+pub struct TieredCache {
+    pub local: MemoryCache,
+    pub remote: Redis,
+    pub local_ttl: u64,
+    origin: String,
+    _listener: Arc<Listener>,
+}
+
+impl TieredCache {
+    /// Returns once subscribed to invalidations, so writes made by other nodes from then
+    /// on are not missed
+    pub async fn with_layers(
+        remote: Redis,
+        local: MemoryCache,
+        local_ttl: u64,
+    ) -> Result<Self, UtilError> {
+        let origin = Uuid::new_v4().to_string();
+        let pubsub = remote.subscribe(INVALIDATION_CHANNEL).await?;
+        let listener = tokio::spawn(Self::listen(
+            remote.clone(),
+            local.clone(),
+            origin.clone(),
+            pubsub,
+        ));
+        Ok(Self {
+            local,
+            remote,
+            local_ttl,
+            origin,
+            _listener: Arc::new(Listener(listener)),
+        })
+    }
+
+    /// Drop the keys other nodes write from the local cache. Invalidations published while
+    /// unsubscribed are missed so the local cache is cleared before subscribing again
+    async fn listen(remote: Redis, local: MemoryCache, origin: String, pubsub: PubSub) {
+        let mut subscribed = Ok(pubsub);
+        loop {
+            match subscribed {
+                Ok(pubsub) => {
+                    let mut messages = pubsub.into_on_message();
+                    while let Some(message) = messages.next().await {
+                        let invalidation = message
+                            .get_payload::<String>()
+                            .map_err(UtilError::from)
+                            .and_then(|payload| {
+                                serde_json::from_str::<Invalidation>(&payload)
+                                    .map_err(UtilError::from)
+                            });
+                        match invalidation {
+                            Ok(invalidation) if invalidation.origin != origin => {
+                                local.remove_all(&invalidation.keys)
+                            }
+                            Ok(_) => {}
+                            Err(e) => tracing::warn!("Invalid cache invalidation {:?}", e),
+                        }
+                    }
+                    tracing::warn!("Cache invalidation subscription closed");
+                }
+                Err(e) => tracing::warn!("Cache invalidation subscription failed {:?}", e),
+            }
+            local.clear();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            subscribed = remote.subscribe(INVALIDATION_CHANNEL).await;
+        }
+    }
+
+    /// A failure to publish is logged, the write has already been made and other nodes
+    /// drop their copies within `local_ttl`
+    async fn invalidate_others(&self, keys: Vec<String>) {
+        let invalidation = Invalidation {
+            origin: self.origin.clone(),
+            keys,
+        };
+        let published = match serde_json::to_string(&invalidation) {
+            Ok(message) => self.remote.publish(INVALIDATION_CHANNEL, &message).await,
+            Err(e) => Err(UtilError::from(e)),
+        };
+        if let Err(e) = published {
+            tracing::warn!("Publishing cache invalidation failed {:?}", e);
+        }
+    }
+
+    fn local_expires(&self, expires: Option<u64>) -> Option<u64> {
+        Some(expires.map_or(self.local_ttl, |expires| expires.min(self.local_ttl)))
+    }
+}
+
+impl CacheLayer for TieredCache {
+    async fn new(env: &Env) -> Result<Self, UtilError> {
+        Self::with_layers(
+            Redis::new(env).await?,
+            MemoryCache::new(env).await?,
+            env.memory_cache_ttl.unwrap_or(DEFAULT_LOCAL_TTL),
+        )
+        .await
+    }
+
+    fn encoding(&self) -> CacheEncoding {
+        self.remote.encoding
+    }
+
+    async fn set_value(
+        &self,
+        key: &str,
+        value: &str,
+        expires: Option<u64>,
+    ) -> Result<(), UtilError> {
+        self.set_bytes(key, value.as_bytes(), expires).await
+    }
+
+    async fn set_value_if_absent(
+        &self,
+        key: &str,
+        value: &str,
+        expires: Option<u64>,
+    ) -> Result<bool, UtilError> {
+        let set = self.remote.set_value_if_absent(key, value, expires).await?;
+        if set {
+            self.local
+                .set_value(key, value, self.local_expires(expires))
+                .await?;
+            self.invalidate_others(vec![key.to_owned()]).await;
+        }
+        Ok(set)
+    }
+
+    async fn delete_value(&self, key: &str) -> Result<(), UtilError> {
+        self.remote.delete_value(key).await?;
+        self.local.delete_value(key).await?;
+        self.invalidate_others(vec![key.to_owned()]).await;
+        Ok(())
+    }
+
+    async fn get_value(&self, key: &str) -> Result<Option<String>, UtilError> {
+        if let Some(value) = self.local.get_value(key).await? {
+            return Ok(Some(value));
+        }
+        let value = self.remote.get_value(key).await?;
+        if let Some(value) = &value {
+            self.local
+                .set_value(key, value, self.local_expires(None))
+                .await?;
+        }
+        Ok(value)
+    }
+
+    async fn value_exists(&self, key: &str) -> Result<bool, UtilError> {
+        Ok(self.local.value_exists(key).await? || self.remote.value_exists(key).await?)
+    }
+
+    /// Counters change on every node so they are only kept in Redis
+    async fn increment(&self, key: &str) -> Result<i64, UtilError> {
+        let value = self.remote.increment(key).await?;
+        self.local.delete_value(key).await?;
+        self.invalidate_others(vec![key.to_owned()]).await;
+        Ok(value)
+    }
+
+    async fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>, UtilError> {
+        if let Some(value) = self.local.get_bytes(key).await? {
+            return Ok(Some(value));
+        }
+        let value = self.remote.get_bytes(key).await?;
+        if let Some(value) = &value {
+            self.local
+                .set_bytes(key, value, self.local_expires(None))
+                .await?;
+        }
+        Ok(value)
+    }
+
+    async fn set_bytes(
+        &self,
+        key: &str,
+        value: &[u8],
+        expires: Option<u64>,
+    ) -> Result<(), UtilError> {
+        self.remote.set_bytes(key, value, expires).await?;
+        self.local
+            .set_bytes(key, value, self.local_expires(expires))
+            .await?;
+        self.invalidate_others(vec![key.to_owned()]).await;
+        Ok(())
+    }
+
+    async fn get_many_bytes(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, UtilError> {
+        let mut values = self.local.get_many_bytes(keys).await?;
+        let missing: Vec<usize> = (0..keys.len()).filter(|&i| values[i].is_none()).collect();
+        if missing.is_empty() {
+            return Ok(values);
+        }
+        let missing_keys: Vec<String> = missing.iter().map(|&i| keys[i].clone()).collect();
+        let fetched = self.remote.get_many_bytes(&missing_keys).await?;
+        let mut found = Vec::new();
+        for (i, value) in missing.into_iter().zip(fetched) {
+            if let Some(value) = &value {
+                found.push((keys[i].clone(), value.clone()));
+            }
+            values[i] = value;
+        }
+        self.local
+            .set_many_bytes(&found, self.local_expires(None))
+            .await?;
+        Ok(values)
+    }
+
+    async fn set_many_bytes(
+        &self,
+        values: &[(String, Vec<u8>)],
+        expires: Option<u64>,
+    ) -> Result<(), UtilError> {
+        self.remote.set_many_bytes(values, expires).await?;
+        self.local
+            .set_many_bytes(values, self.local_expires(expires))
+            .await?;
+        self.invalidate_others(values.iter().map(|(key, _)| key.clone()).collect())
+            .await;
+        Ok(())
+    }
+}
+
+/// The cache of an app selected by `cache_backend`. Without it Redis unless the broker runs
+/// in memory or no Redis is configured, then everything is kept in the process so a single
+/// node runs without Redis
+#[derive(Clone)]
+pub enum AppCache {
+    Redis(Redis),
+    Memory(MemoryCache),
+    Tiered(TieredCache),
+}
+
+impl AppCache {
+    /// Redis behind the cache, for what only Redis can share between replicas like locks
+    pub fn redis(&self) -> Option<&Redis> {
+        match self {
+            Self::Redis(redis) => Some(redis),
+            Self::Memory(_) => None,
+            Self::Tiered(tiered) => Some(&tiered.remote),
+        }
+    }
+
+    /// Count a request against `key`, see `Redis::rate_limit`
+    pub async fn rate_limit(
+        &self,
+        key: &str,
+        limit: u64,
+        period: Duration,
+    ) -> Result<RateLimit, UtilError> {
+        match self {
+            Self::Redis(redis) => redis.rate_limit(key, limit, period).await,
+            Self::Memory(memory) => Ok(memory.rate_limit(key, limit, period)),
+            Self::Tiered(tiered) => tiered.remote.rate_limit(key, limit, period).await,
+        }
+    }
+}
+
+impl CacheLayer for AppCache {
+    async fn new(env: &Env) -> Result<Self, UtilError> {
+        match env.cache_backend.as_deref() {
+            Some("redis") => return Ok(Self::Redis(Redis::new(env).await?)),
+            Some("memory") => return Ok(Self::Memory(MemoryCache::new(env).await?)),
+            Some("tiered") => return Ok(Self::Tiered(TieredCache::new(env).await?)),
+            Some(other) => {
+                return Err(UtilError::Other(format!(
+                    "Unknown cache backend {other}, expected redis, memory or tiered"
+                )))
+            }
+            None => {}
+        }
+        let memory_broker = env
+            .broker
+            .as_ref()
+            .and_then(|broker| broker.backend.as_deref())
+            == Some("memory");
+        let redis_configured = env.redis.as_ref().is_some_and(|redis| {
+            redis.host.is_some() || redis.hosts.is_some() || redis.sentinels.is_some()
+        });
+        if memory_broker || !redis_configured {
+            return Ok(Self::Memory(MemoryCache::new(env).await?));
+        }
+        Ok(Self::Redis(Redis::new(env).await?))
+    }
+
+    fn encoding(&self) -> CacheEncoding {
+        match self {
+            Self::Redis(redis) => redis.encoding(),
+            Self::Memory(memory) => memory.encoding(),
+            Self::Tiered(tiered) => tiered.encoding(),
+        }
+    }
+
+    async fn set_value(
+        &self,
+        key: &str,
+        value: &str,
+        expires: Option<u64>,
+    ) -> Result<(), UtilError> {
+        match self {
+            Self::Redis(redis) => redis.set_value(key, value, expires).await,
+            Self::Memory(memory) => memory.set_value(key, value, expires).await,
+            Self::Tiered(tiered) => tiered.set_value(key, value, expires).await,
+        }
+    }
+
+    async fn set_value_if_absent(
+        &self,
+        key: &str,
+        value: &str,
+        expires: Option<u64>,
+    ) -> Result<bool, UtilError> {
+        match self {
+            Self::Redis(redis) => redis.set_value_if_absent(key, value, expires).await,
+            Self::Memory(memory) => memory.set_value_if_absent(key, value, expires).await,
+            Self::Tiered(tiered) => tiered.set_value_if_absent(key, value, expires).await,
+        }
+    }
+
+    async fn delete_value(&self, key: &str) -> Result<(), UtilError> {
+        match self {
+            Self::Redis(redis) => redis.delete_value(key).await,
+            Self::Memory(memory) => memory.delete_value(key).await,
+            Self::Tiered(tiered) => tiered.delete_value(key).await,
+        }
+    }
+
+    async fn get_value(&self, key: &str) -> Result<Option<String>, UtilError> {
+        match self {
+            Self::Redis(redis) => redis.get_value(key).await,
+            Self::Memory(memory) => memory.get_value(key).await,
+            Self::Tiered(tiered) => tiered.get_value(key).await,
+        }
+    }
+
+    async fn value_exists(&self, key: &str) -> Result<bool, UtilError> {
+        match self {
+            Self::Redis(redis) => redis.value_exists(key).await,
+            Self::Memory(memory) => memory.value_exists(key).await,
+            Self::Tiered(tiered) => tiered.value_exists(key).await,
+        }
+    }
+
+    async fn increment(&self, key: &str) -> Result<i64, UtilError> {
+        match self {
+            Self::Redis(redis) => redis.increment(key).await,
+            Self::Memory(memory) => memory.increment(key).await,
+            Self::Tiered(tiered) => tiered.increment(key).await,
+        }
+    }
+
+    async fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>, UtilError> {
+        match self {
+            Self::Redis(redis) => redis.get_bytes(key).await,
+            Self::Memory(memory) => memory.get_bytes(key).await,
+            Self::Tiered(tiered) => tiered.get_bytes(key).await,
+        }
+    }
+
+    async fn set_bytes(
+        &self,
+        key: &str,
+        value: &[u8],
+        expires: Option<u64>,
+    ) -> Result<(), UtilError> {
+        match self {
+            Self::Redis(redis) => redis.set_bytes(key, value, expires).await,
+            Self::Memory(memory) => memory.set_bytes(key, value, expires).await,
+            Self::Tiered(tiered) => tiered.set_bytes(key, value, expires).await,
+        }
+    }
+
+    async fn get_many_bytes(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, UtilError> {
+        match self {
+            Self::Redis(redis) => redis.get_many_bytes(keys).await,
+            Self::Memory(memory) => memory.get_many_bytes(keys).await,
+            Self::Tiered(tiered) => tiered.get_many_bytes(keys).await,
+        }
+    }
+
+    async fn set_many_bytes(
+        &self,
+        values: &[(String, Vec<u8>)],
+        expires: Option<u64>,
+    ) -> Result<(), UtilError> {
+        match self {
+            Self::Redis(redis) => redis.set_many_bytes(values, expires).await,
+            Self::Memory(memory) => memory.set_many_bytes(values, expires).await,
+            Self::Tiered(tiered) => tiered.set_many_bytes(values, expires).await,
+        }
+    }
+}
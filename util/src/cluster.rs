@@ -0,0 +1,77 @@
+use deadpool::managed::{self, Metrics, Object, RecycleResult};
+use deadpool_redis::redis::{
+    aio::ConnectionLike, cluster::ClusterClient, cluster::ClusterClientBuilder,
+    cluster_async::ClusterConnection, cmd, Cmd, ConnectionInfo, Pipeline, RedisError, RedisFuture,
+    RedisResult, TlsCertificates, Value,
+};
+
+/// Pool of cluster connections, `deadpool_redis::cluster::Pool` builds its own client and so
+/// cannot be given certificates
+pub type Pool = managed::Pool<Manager, Connection>;
+
+/// Creates connections with a `ClusterClient` built from the nodes and certificates. The
+/// certificates are used for every node, including those found through the initial ones
+pub struct Manager {
+    client: ClusterClient,
+}
+
+impl Manager {
+    pub fn new(
+        nodes: Vec<ConnectionInfo>,
+        certificates: Option<TlsCertificates>,
+    ) -> RedisResult<Self> {
+        let mut builder = ClusterClientBuilder::new(nodes);
+        if let Some(certificates) = certificates {
+            builder = builder.certs(certificates);
+        }
+        Ok(Self {
+            client: builder.build()?,
+        })
+    }
+}
+
+impl managed::Manager for Manager {
+    type Type = ClusterConnection;
+    type Error = RedisError;
+
+    async fn create(&self) -> Result<ClusterConnection, RedisError> {
+        self.client.get_async_connection().await
+    }
+
+    async fn recycle(
+        &self,
+        conn: &mut ClusterConnection,
+        _: &Metrics,
+    ) -> RecycleResult<RedisError> {
+        cmd("PING").query_async::<()>(conn).await?;
+        Ok(())
+    }
+}
+
+/// A pooled cluster connection, returned to the pool when dropped
+pub struct Connection(Object<Manager>);
+
+impl From<Object<Manager>> for Connection {
+    fn from(conn: Object<Manager>) -> Self {
+        Self(conn)
+    }
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        self.0.req_packed_command(cmd)
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        self.0.req_packed_commands(cmd, offset, count)
+    }
+
+    fn get_db(&self) -> i64 {
+        self.0.get_db()
+    }
+}
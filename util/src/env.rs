@@ -48,6 +48,46 @@ pub struct Redis {
     pub insecure: Option<String>,
     #[serde(rename = "redis_stream_len")]
     pub stream_len: Option<String>,
+    /// ACL user, overrides one given in the urls
+    #[serde(rename = "redis_username")]
+    pub username: Option<String>,
+    #[serde(rename = "redis_password")]
+    pub password: Option<String>,
+    /// Database index, refused with `hosts` as clusters only have database 0
+    #[serde(rename = "redis_db")]
+    pub db: Option<i64>,
+    /// Path to a PEM CA certificate to trust instead of the system roots. Certificates are
+    /// refused with `sentinels`, the sentinel client connects to the master it finds without
+    /// them, so a master behind sentinels needs a certificate the system roots trust
+    #[serde(rename = "redis_ca_cert")]
+    pub ca_cert: Option<String>,
+    /// Path to a PEM client certificate, for servers that require mutual TLS
+    #[serde(rename = "redis_client_cert")]
+    pub client_cert: Option<String>,
+    /// Path to the PEM key of `client_cert`
+    #[serde(rename = "redis_client_key")]
+    pub client_key: Option<String>,
+    /// Comma separated urls of sentinels, takes precedence over `host` and `hosts`
+    #[serde(rename = "redis_sentinels")]
+    pub sentinels: Option<String>,
+    /// Name the sentinels monitor the master under, `mymaster` if not set
+    #[serde(rename = "redis_sentinel_master")]
+    pub sentinel_master: Option<String>,
+    /// `json` (the default) or `msgpack`, how typed cache values are serialized
+    #[serde(rename = "redis_cache_encoding")]
+    pub cache_encoding: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Broker {
+    #[serde(rename = "broker_backend")]
+    pub backend: Option<String>,
+    #[serde(rename = "broker_retry_after")]
+    pub retry_after: Option<String>,
+    #[serde(rename = "broker_max_attempts")]
+    pub max_attempts: Option<String>,
+    #[serde(rename = "broker_stream_len")]
+    pub stream_len: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -59,7 +99,21 @@ pub struct Env {
     pub auth: Option<Auth>,
     #[serde(flatten)]
     pub redis: Option<Redis>,
+    #[serde(flatten)]
+    pub broker: Option<Broker>,
     pub watch_topics: Option<String>,
+    /// Comma separated `path=limit/seconds:key` rules, key is `ip`, `user` or `api_key`
+    pub rate_limits: Option<String>,
+    /// Comma separated addresses or ranges of proxies whose `X-Forwarded-For` is trusted,
+    /// without any the peer address identifies the client
+    pub trusted_proxies: Option<String>,
+    /// `redis`, `memory` or `tiered` (Redis fronted by an in-process cache), chosen from the
+    /// broker backend and whether Redis is configured if not set
+    pub cache_backend: Option<String>,
+    /// Entries kept by an in-process cache before the least recently used are evicted
+    pub memory_cache_max_entries: Option<usize>,
+    /// Seconds an in-process cache in front of Redis keeps values
+    pub memory_cache_ttl: Option<u64>,
 }
 
 impl Env {
@@ -1,24 +1,53 @@
+use axum::http::StatusCode;
 use deadpool_redis::ConfigError;
 use derive_axum_errors::ErrorResponse;
 use redis::RedisError;
-use sqlx::error::{Error as SqlxError, ErrorKind as SqlxErrorKind};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    error::{DatabaseError, Error as SqlxError, ErrorKind as SqlxErrorKind},
+    postgres::PgDatabaseError,
+};
 use thiserror::Error as ThisError;
+use utoipa::ToSchema;
+
+/// Why a field of a request is invalid, see `#[field_errors]`
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct FieldError {
+    /// Path of the field, e.g. `paging.page`
+    pub field: String,
+    pub message: String,
+}
+
+/// The constraint violated, or the column for not null violations. The database message can
+/// hold the values of the row so it is only logged, the name is returned to clients
+fn violated(db_error: &dyn DatabaseError) -> String {
+    tracing::debug!("Database constraint violated {}", db_error.message());
+    db_error
+        .constraint()
+        .or_else(|| {
+            db_error
+                .try_downcast_ref::<PgDatabaseError>()
+                .and_then(PgDatabaseError::column)
+        })
+        .unwrap_or_default()
+        .to_owned()
+}
 
 impl From<SqlxError> for UtilError {
     fn from(database_error: SqlxError) -> Self {
         match &database_error {
             SqlxError::Database(db_error) => match db_error.kind() {
                 SqlxErrorKind::UniqueViolation => {
-                    UtilError::SqlDuplicateRecord(db_error.message().to_owned())
+                    UtilError::SqlDuplicateRecord(violated(db_error.as_ref()))
                 }
                 SqlxErrorKind::ForeignKeyViolation => {
-                    UtilError::SqlRelationMissing(db_error.message().to_owned())
+                    UtilError::SqlRelationMissing(violated(db_error.as_ref()))
                 }
                 SqlxErrorKind::NotNullViolation => {
-                    UtilError::SqlNotNullViolation(db_error.message().to_owned())
+                    UtilError::SqlNotNullViolation(violated(db_error.as_ref()))
                 }
                 SqlxErrorKind::CheckViolation => {
-                    UtilError::SqlCheckFailed(db_error.message().to_owned())
+                    UtilError::SqlCheckFailed(violated(db_error.as_ref()))
                 }
                 _ => UtilError::SqlError(format!("{:?}", database_error)),
             },
@@ -39,14 +68,24 @@ pub enum UtilError {
     #[error(transparent)]
     SqlMigrationError(#[from] sqlx::migrate::MigrateError),
     #[error("Duplicate record found {0}")]
+    #[error_code("duplicate_record")]
+    #[status(StatusCode::CONFLICT)]
     SqlDuplicateRecord(String),
     #[error("Required relationship between records violated {0}")]
+    #[error_code("relation_missing")]
+    #[status(StatusCode::UNPROCESSABLE_ENTITY)]
     SqlRelationMissing(String),
     #[error("Required field missing or null {0}")]
+    #[error_code("not_null_violation")]
+    #[status(StatusCode::UNPROCESSABLE_ENTITY)]
     SqlNotNullViolation(String),
     #[error("Database logic check failed {0}")]
+    #[error_code("check_failed")]
+    #[status(StatusCode::UNPROCESSABLE_ENTITY)]
     SqlCheckFailed(String),
     #[error("Record not found")]
+    #[error_code("not_found")]
+    #[status(StatusCode::NOT_FOUND)]
     SqlFailedToFindRecord,
     #[error(
         "Env var for Redis not set, host (for single instance) or hosts(for cluster) must be set"
@@ -63,11 +102,36 @@ pub enum UtilError {
     #[error(transparent)]
     DeadpoolCluserRedis(#[from] deadpool::managed::PoolError<RedisError>),
     #[error(transparent)]
+    DeadpoolBuild(#[from] deadpool::managed::BuildError),
+    #[error(transparent)]
     RedisError(#[from] RedisError),
+    #[error("Redis certificate {0}")]
+    RedisCertificate(String),
+    #[error("Redis certificates are not supported for {0} connections")]
+    RedisCertificatesUnsupported(String),
+    #[error("redis_db can not be set with redis_hosts, clusters only have database 0")]
+    RedisClusterDatabase,
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+    #[error(transparent)]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+    #[error("Unknown cache encoding {0}, expected json or msgpack")]
+    UnknownCacheEncoding(String),
     #[error("Redis stream params could not be converted into Vec<String>")]
     RedisStreamParams,
+    #[error("No reply to request on {0} before the timeout")]
+    RpcTimeout(String),
+    #[error("Responder failed {0}")]
+    RpcFailed(String),
+    #[error("Timed out waiting for lock {0}")]
+    LockTimeout(String),
     #[error("Cant Materialize view no rows match query")]
     RowCantMaterialize,
+    #[error("Refusing to delete every row of {0}, the query has no filter")]
+    #[status(StatusCode::BAD_REQUEST)]
+    UnfilteredDelete(String),
     #[error(transparent)]
     Template(#[from] minijinja::Error),
     #[error("Templates cannot be rendered as they have not been loaded into the env")]
@@ -1,6 +1,9 @@
+pub mod cache;
+pub mod cluster;
 pub mod env;
 pub mod error;
 pub mod macros;
+pub mod outbox;
 pub mod store;
 use crate::store::CacheLayer;
 use utoipa::ToSchema;
@@ -51,3 +54,19 @@ pub trait ToParams {
 pub trait FromParams: Default {
     fn from_params(params: Vec<String>) -> Self;
 }
+
+/// Params that have already been serialized, e.g. messages read back from the outbox
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct RawParams(pub Vec<String>);
+
+impl ToParams for RawParams {
+    fn to_params(&self) -> Vec<String> {
+        self.0.clone()
+    }
+}
+
+impl FromParams for RawParams {
+    fn from_params(params: Vec<String>) -> Self {
+        Self(params)
+    }
+}
@@ -3,8 +3,11 @@ pub use crate::*;
 #[macro_export]
 macro_rules! make_sort {
     ($name: ident, $r: ty) => {
-        #[derive(Debug, PartialEq, Serialize, Deserialize, ToSchema, Default, Clone)]
+        #[derive(
+            Debug, PartialEq, Serialize, Deserialize, ToSchema, IntoParams, Default, Clone,
+        )]
         #[serde(rename_all = "camelCase")]
+        #[into_params(parameter_in = Query)]
         pub struct $name {
             pub direction: Option<SortDirection>,
             pub sort_by: Option<$r>,
@@ -24,6 +27,10 @@ macro_rules! redis_op {
                 let mut conn = pool.get().await?;
                 $r.query_async(&mut conn).await.map_err(UtilError::from)
             }
+            ConnectionPool::Sentinel(pool) => {
+                let mut conn = pool.get().await?;
+                $r.query_async(&mut conn).await.map_err(UtilError::from)
+            }
         }
     };
     ($p: ident,$r: expr,$t: ty) => {
@@ -40,6 +47,12 @@ macro_rules! redis_op {
                     .await
                     .map_err(UtilError::from)
             }
+            ConnectionPool::Sentinel(pool) => {
+                let mut conn = pool.get().await?;
+                $r.query_async::<$t>(&mut conn)
+                    .await
+                    .map_err(UtilError::from)
+            }
         }
     };
 }
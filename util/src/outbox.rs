@@ -0,0 +1,58 @@
+use crate::{error::UtilError, ToParams};
+use chrono::{DateTime, Utc};
+use sqlx::{types::Json, PgConnection};
+
+/// A message waiting in the `outbox` table to be published through the broker.
+/// Writing it in the same transaction as the model change it describes means the
+/// message is only published if the change commits, and is never lost if it does
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub aggregate_type: String,
+    pub aggregate_id: String,
+    pub topic: String,
+    pub params: Json<Vec<String>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl OutboxEntry {
+    pub async fn enqueue(
+        conn: &mut PgConnection,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        topic: &str,
+        message: &impl ToParams,
+    ) -> Result<i64, UtilError> {
+        let (id,) = sqlx::query_as::<_, (i64,)>(
+            "INSERT INTO outbox (aggregate_type, aggregate_id, topic, params)
+            VALUES ($1, $2, $3, $4) RETURNING id",
+        )
+        .bind(aggregate_type)
+        .bind(aggregate_id)
+        .bind(topic)
+        .bind(Json(message.to_params()))
+        .fetch_one(conn)
+        .await?;
+        Ok(id)
+    }
+
+    /// Oldest unsent entries, in the order they were written
+    pub async fn unsent(conn: &mut PgConnection, limit: i64) -> Result<Vec<Self>, UtilError> {
+        sqlx::query_as::<_, Self>(
+            "SELECT id, aggregate_type, aggregate_id, topic, params, created_at FROM outbox
+            WHERE sent_at IS NULL ORDER BY id LIMIT $1",
+        )
+        .bind(limit)
+        .fetch_all(conn)
+        .await
+        .map_err(UtilError::from)
+    }
+
+    pub async fn mark_sent(conn: &mut PgConnection, ids: &[i64]) -> Result<(), UtilError> {
+        sqlx::query("UPDATE outbox SET sent_at = now() WHERE id = ANY($1)")
+            .bind(ids)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+}
@@ -1,18 +1,30 @@
 use crate::{
-    env::{Env, PostgresConfig},
+    cache::AppCache,
+    cluster::{Manager as ClusterManager, Pool as RedisClusterPool},
+    env::{Env, PostgresConfig, Redis as RedisConfig},
     error::UtilError,
     macros::redis_op,
     AppConfig,
 };
 use deadpool_redis::{
-    cluster::{Config as ClusterConfig, Pool as RedisClusterPool, Runtime},
-    redis::aio::ConnectionLike,
-    redis::cmd,
-    Config as InstanceConfig, Pool as RedisInstancePool,
+    redis::aio::{ConnectionLike, PubSub},
+    redis::sentinel::{Sentinel, SentinelNodeConnectionInfo},
+    redis::{
+        cmd, pipe, ClientTlsConfig, ConnectionInfo, IntoConnectionInfo, RedisConnectionInfo,
+        TlsCertificates, TlsMode,
+    },
+    sentinel::{Manager as SentinelManager, Pool as RedisSentinelPool, SentinelServerType},
+    Manager as InstanceManager, Pool as RedisInstancePool, Runtime,
 };
-use serde::{Deserialize, Serialize};
-use sqlx::{postgres::PgPoolOptions, PgPool, Postgres, QueryBuilder};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sqlx::{postgres::PgPoolOptions, PgConnection, PgPool, Postgres, QueryBuilder, Transaction};
+use std::future::Future;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::{sync::watch, task::JoinHandle};
 use utoipa::ToSchema;
+use uuid::Uuid;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, ToSchema, Clone, Default)]
 #[serde(rename_all = "lowercase")]
@@ -70,6 +82,31 @@ pub trait Model: Sized + Send {
         Q: ToSqlQuery + Pagination + ToSqlSort;
     async fn insert(new_model: impl NewModel, db: &RWDB) -> Result<Self, UtilError>;
     async fn upsert(new_model: impl NewModel + UpdateModel, db: &RWDB) -> Result<Self, UtilError>;
+    /// Same as `update` but runs on the given connection, pass a transaction to combine
+    /// the write with others such as `OutboxEntry::enqueue`
+    async fn update_tx<Q>(
+        query: &Q,
+        updated_model: impl UpdateModel,
+        conn: &mut PgConnection,
+    ) -> Result<Self, UtilError>
+    where
+        Q: ToSqlQuery + Pagination + ToSqlSort;
+    async fn insert_tx(
+        new_model: impl NewModel,
+        conn: &mut PgConnection,
+    ) -> Result<Self, UtilError>;
+    async fn upsert_tx(
+        new_model: impl NewModel + UpdateModel,
+        conn: &mut PgConnection,
+    ) -> Result<Self, UtilError>;
+    /// Delete every row matching the query, returning them. A query without a filter is
+    /// refused with `UnfilteredDelete`
+    async fn delete<Q>(query: &Q, db: &RWDB) -> Result<Vec<Self>, UtilError>
+    where
+        Q: ToSqlQuery + Pagination + ToSqlSort;
+    async fn delete_tx<Q>(query: &Q, conn: &mut PgConnection) -> Result<Vec<Self>, UtilError>
+    where
+        Q: ToSqlQuery + Pagination + ToSqlSort;
     fn build_query<Q>(query: &Q) -> QueryBuilder<'static, Postgres>
     where
         Q: ToSqlQuery + Pagination + ToSqlSort,
@@ -147,6 +184,8 @@ pub trait Pagination {
 
 pub trait ToSqlQuery {
     fn add_where(&self, qb: &mut QueryBuilder<Postgres>);
+    /// Every value of the query, including sort and paging, in a form fit for a cache key
+    fn cache_key(&self) -> String;
 }
 
 pub trait ToSqlSort {
@@ -161,15 +200,24 @@ pub trait ToSqlSort {
 }
 
 #[derive(Clone)]
-pub struct RWDB(PgPool);
+pub struct RWDB(PgPool, Option<AppCache>);
 #[derive(Clone)]
-pub struct RODB(PgPool);
+pub struct RODB(PgPool, Option<AppCache>);
 
 impl RODB {
     pub fn get_conn(&self) -> &PgPool {
         &self.0
     }
 
+    /// Cache results of models declared with `#[model(cache_ttl = ...)]` in `cache`
+    pub fn with_cache(self, cache: AppCache) -> Self {
+        Self(self.0, Some(cache))
+    }
+
+    pub fn cache(&self) -> Option<&AppCache> {
+        self.1.as_ref()
+    }
+
     pub fn connect_str(env: &PostgresConfig) -> String {
         format!(
             "postgres://{}:{}@{}:{}/{}",
@@ -183,7 +231,13 @@ impl RODB {
             .min_connections(5)
             .connect(&connection)
             .await?;
-        Ok(Self(pool))
+        Ok(Self(pool, None))
+    }
+
+    /// Pool that only connects once a query is made
+    pub fn connect_lazy(state: &impl AppConfig) -> Result<Self, UtilError> {
+        let connection = Self::connect_str(state.get_rw_store_settings());
+        Ok(Self(PgPoolOptions::new().connect_lazy(&connection)?, None))
     }
 }
 
@@ -192,6 +246,15 @@ impl RWDB {
         &self.0
     }
 
+    /// Invalidate cached query results of the tables written through this connection
+    pub fn with_cache(self, cache: AppCache) -> Self {
+        Self(self.0, Some(cache))
+    }
+
+    pub fn cache(&self) -> Option<&AppCache> {
+        self.1.as_ref()
+    }
+
     pub fn connect_str(env: &PostgresConfig) -> String {
         format!(
             "postgres://{}:{}@{}:{}/{}",
@@ -205,7 +268,17 @@ impl RWDB {
             .min_connections(5)
             .connect(&connection)
             .await?;
-        Ok(Self(pool))
+        Ok(Self(pool, None))
+    }
+
+    /// Pool that only connects once a query is made
+    pub fn connect_lazy(state: &impl AppConfig) -> Result<Self, UtilError> {
+        let connection = Self::connect_str(state.get_rw_store_settings());
+        Ok(Self(PgPoolOptions::new().connect_lazy(&connection)?, None))
+    }
+
+    pub async fn begin(&self) -> Result<Transaction<'static, Postgres>, UtilError> {
+        self.0.begin().await.map_err(UtilError::from)
     }
 
     pub async fn migrate(state: &impl AppConfig) -> Result<(), UtilError> {
@@ -215,30 +288,248 @@ impl RWDB {
     }
 }
 
+/// Results of model queries cached by table. Keys include a per-table version which every
+/// write through a model increments, so a write invalidates all cached queries of the
+/// table at once and the stale entries expire on their own. The versions also invalidate
+/// cached HTTP responses that declare the tables they read. A cache that cannot be reached
+/// is logged and the query runs against the database as if it missed
+pub struct QueryCache;
+
+impl QueryCache {
+    fn version_key(table: &str) -> String {
+        format!("query_cache:{table}:version")
+    }
+
+    /// Current version of the table, changes on every write through a model
+    pub async fn version(cache: &impl CacheLayer, table: &str) -> Result<String, UtilError> {
+        Ok(cache
+            .get_value(&Self::version_key(table))
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Key for the query, `None` when `db` has no cache or the version could not be read
+    pub async fn key(db: &RODB, table: &str, sql: &str, binds: &str) -> Option<String> {
+        let version = Self::version(db.cache()?, table)
+            .await
+            .inspect_err(|e| tracing::warn!("Query cache version of {} failed {:?}", table, e))
+            .ok()?;
+        let mut hasher = DefaultHasher::new();
+        sql.hash(&mut hasher);
+        binds.hash(&mut hasher);
+        Some(format!(
+            "query_cache:{table}:{version}:{:x}",
+            hasher.finish()
+        ))
+    }
+
+    pub async fn get<T: DeserializeOwned>(db: &RODB, key: &Option<String>) -> Option<T> {
+        let (Some(cache), Some(key)) = (db.cache(), key) else {
+            return None;
+        };
+        cache
+            .get(key)
+            .await
+            .inspect_err(|e| tracing::warn!("Query cache read of {} failed {:?}", key, e))
+            .ok()?
+    }
+
+    pub async fn set<T: Serialize>(db: &RODB, key: &Option<String>, value: &T, ttl: u64) {
+        let (Some(cache), Some(key)) = (db.cache(), key) else {
+            return;
+        };
+        if let Err(e) = cache.set(key, value, Some(ttl)).await {
+            tracing::warn!("Query cache write of {} failed {:?}", key, e);
+        }
+    }
+
+    /// Drop every cached query of the table, writes outside of `Model` such as `_tx`
+    /// methods in a caller's transaction call this once it commits
+    pub async fn invalidate(db: &RWDB, table: &str) {
+        let Some(cache) = db.cache() else {
+            return;
+        };
+        if let Err(e) = cache.increment(&Self::version_key(table)).await {
+            tracing::warn!("Query cache invalidation of {} failed {:?}", table, e);
+        }
+    }
+}
+
+/// How typed values are serialized into the cache
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CacheEncoding {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl FromStr for CacheEncoding {
+    type Err = UtilError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "msgpack" => Ok(Self::MessagePack),
+            _ => Err(UtilError::UnknownCacheEncoding(s.to_owned())),
+        }
+    }
+}
+
+impl CacheEncoding {
+    /// The encoding set by `redis_cache_encoding`, json if it is not set
+    pub fn from_env(env: &Env) -> Result<Self, UtilError> {
+        env.redis
+            .as_ref()
+            .and_then(|redis| redis.cache_encoding.as_deref())
+            .map(Self::from_str)
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, UtilError> {
+        match self {
+            Self::Json => Ok(serde_json::to_vec(value)?),
+            Self::MessagePack => Ok(rmp_serde::to_vec_named(value)?),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, UtilError> {
+        match self {
+            Self::Json => Ok(serde_json::from_slice(bytes)?),
+            Self::MessagePack => Ok(rmp_serde::from_slice(bytes)?),
+        }
+    }
+}
+
 #[allow(async_fn_in_trait)]
 pub trait CacheLayer: Sized {
-    async fn get_conn_pool(&self) -> &ConnectionPool;
     async fn new(env: &Env) -> Result<Self, UtilError>;
+    fn encoding(&self) -> CacheEncoding;
     async fn set_value(
         &self,
         key: &str,
         value: &str,
         expires: Option<u64>,
     ) -> Result<(), UtilError>;
+    /// Set the value only if the key does not exist, returns false if it already did
+    async fn set_value_if_absent(
+        &self,
+        key: &str,
+        value: &str,
+        expires: Option<u64>,
+    ) -> Result<bool, UtilError>;
     async fn delete_value(&self, key: &str) -> Result<(), UtilError>;
-    async fn get_value(&self, key: &str) -> Result<String, UtilError>;
+    async fn get_value(&self, key: &str) -> Result<Option<String>, UtilError>;
     async fn value_exists(&self, key: &str) -> Result<bool, UtilError>;
+    /// Add one to the integer value of the key, starting from zero, returns the new value
+    async fn increment(&self, key: &str) -> Result<i64, UtilError>;
+    async fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>, UtilError>;
+    async fn set_bytes(
+        &self,
+        key: &str,
+        value: &[u8],
+        expires: Option<u64>,
+    ) -> Result<(), UtilError>;
+    /// Values of the keys in the same order, `None` for those that are not set
+    async fn get_many_bytes(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, UtilError>;
+    async fn set_many_bytes(
+        &self,
+        values: &[(String, Vec<u8>)],
+        expires: Option<u64>,
+    ) -> Result<(), UtilError>;
+
+    async fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, UtilError> {
+        self.get_bytes(key)
+            .await?
+            .map(|bytes| self.encoding().decode(&bytes))
+            .transpose()
+    }
+
+    async fn set<T: Serialize>(
+        &self,
+        key: &str,
+        value: &T,
+        expires: Option<u64>,
+    ) -> Result<(), UtilError> {
+        self.set_bytes(key, &self.encoding().encode(value)?, expires)
+            .await
+    }
+
+    async fn get_many<T: DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<T>>, UtilError> {
+        self.get_many_bytes(keys)
+            .await?
+            .into_iter()
+            .map(|bytes| bytes.map(|b| self.encoding().decode(&b)).transpose())
+            .collect()
+    }
+
+    async fn set_many<T: Serialize>(
+        &self,
+        values: &[(String, T)],
+        expires: Option<u64>,
+    ) -> Result<(), UtilError> {
+        let encoded = values
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), self.encoding().encode(value)?)))
+            .collect::<Result<Vec<_>, UtilError>>()?;
+        self.set_many_bytes(&encoded, expires).await
+    }
+
+    /// Cache-aside read, returns the cached value or the result of `f` which is then cached.
+    /// The cache failing is logged and `f` used as if it missed, only errors from `f` fail
+    async fn get_or_set_with<T, F, Fut>(
+        &self,
+        key: &str,
+        expires: Option<u64>,
+        f: F,
+    ) -> Result<T, UtilError>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, UtilError>>,
+    {
+        match self.get(key).await {
+            Ok(Some(value)) => return Ok(value),
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Cache read of {} failed {:?}", key, e),
+        }
+        let value = f().await?;
+        if let Err(e) = self.set(key, &value, expires).await {
+            tracing::warn!("Cache write of {} failed {:?}", key, e);
+        }
+        Ok(value)
+    }
 }
 
 #[derive(Clone)]
 pub struct Redis {
     pub pool: ConnectionPool,
+    pub encoding: CacheEncoding,
+    /// Where `subscribe` connects
+    pub nodes: RedisNodes,
 }
 
 #[derive(Clone)]
 pub enum ConnectionPool {
     Cluster(RedisClusterPool),
     Instance(RedisInstancePool),
+    Sentinel(RedisSentinelPool),
+}
+
+/// The nodes a `Redis` was configured with
+#[derive(Clone)]
+pub enum RedisNodes {
+    /// The instance or the cluster nodes
+    Direct(Vec<ConnectionInfo>),
+    /// Sentinels monitoring `master`, connections to the master use `node`
+    Sentinel {
+        sentinels: Vec<ConnectionInfo>,
+        master: String,
+        node: SentinelNodeConnectionInfo,
+    },
 }
 
 impl ConnectionPool {
@@ -255,15 +546,183 @@ impl ConnectionPool {
         }
         Ok(None)
     }
+
+    pub async fn get_sentinel(&self) -> Result<Option<impl ConnectionLike>, UtilError> {
+        if let Self::Sentinel(pool) = self {
+            return Ok(Some(pool.get().await?));
+        }
+        Ok(None)
+    }
 }
 
-impl CacheLayer for Redis {
-    async fn get_conn_pool(&self) -> &ConnectionPool {
+impl Redis {
+    pub fn get_conn_pool(&self) -> &ConnectionPool {
         &self.pool
     }
 
+    /// Publish a message to every subscriber of the channel, on a cluster the message
+    /// reaches subscribers connected to any node
+    pub async fn publish(&self, channel: &str, message: &str) -> Result<(), UtilError> {
+        redis_op!(self, cmd("PUBLISH").arg(channel).arg(message))
+    }
+
+    /// Subscribe to the channel on a connection of its own, pooled connections cannot be
+    /// left subscribed. Behind Sentinel the subscription is made on the current master
+    pub async fn subscribe(&self, channel: &str) -> Result<PubSub, UtilError> {
+        let client = match &self.nodes {
+            RedisNodes::Direct(nodes) => {
+                let node = nodes.first().ok_or(UtilError::RedisNotConfigured)?;
+                redis::Client::open(node.clone())?
+            }
+            RedisNodes::Sentinel {
+                sentinels,
+                master,
+                node,
+            } => {
+                Sentinel::build(sentinels.clone())?
+                    .async_master_for(master, Some(node))
+                    .await?
+            }
+        };
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub.subscribe(channel).await?;
+        Ok(pubsub)
+    }
+
+    /// Certificates from the PEM files in `redis_ca_cert`, `redis_client_cert` and
+    /// `redis_client_key`, `None` if none of them are set
+    fn tls_certificates(redis_env: &RedisConfig) -> Result<Option<TlsCertificates>, UtilError> {
+        let read = |path: &String| {
+            std::fs::read(path)
+                .map_err(|e| UtilError::RedisCertificate(format!("{path} could not be read {e}")))
+        };
+        let root_cert = redis_env.ca_cert.as_ref().map(read).transpose()?;
+        let client_tls = match (&redis_env.client_cert, &redis_env.client_key) {
+            (Some(cert), Some(key)) => Some(ClientTlsConfig {
+                client_cert: read(cert)?,
+                client_key: read(key)?,
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(UtilError::RedisCertificate(
+                    "redis_client_cert and redis_client_key must be set together".to_owned(),
+                ))
+            }
+        };
+        if root_cert.is_none() && client_tls.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(TlsCertificates {
+            client_tls,
+            root_cert,
+        }))
+    }
+
+    /// Credentials and database set in the env take precedence over those in the url
+    fn with_credentials(
+        redis_env: &RedisConfig,
+        mut info: RedisConnectionInfo,
+    ) -> RedisConnectionInfo {
+        if let Some(username) = &redis_env.username {
+            info.username = Some(username.clone());
+        }
+        if let Some(password) = &redis_env.password {
+            info.password = Some(password.clone());
+        }
+        if let Some(db) = redis_env.db {
+            info.db = db;
+        }
+        info
+    }
+
+    fn connection_info(
+        redis_env: &RedisConfig,
+        url: &str,
+        certificates: Option<&TlsCertificates>,
+    ) -> Result<ConnectionInfo, UtilError> {
+        let mut info = url.into_connection_info()?;
+        info.redis = Self::with_credentials(redis_env, info.redis);
+        match certificates {
+            Some(certificates) => Ok(redis::Client::build_with_tls(info, certificates.clone())?
+                .get_connection_info()
+                .clone()),
+            None => Ok(info),
+        }
+    }
+
+    fn split_urls(urls: &str) -> Vec<&str> {
+        urls.split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .collect()
+    }
+
+    /// Connections to the master go through the sentinels, so after a failover new
+    /// connections reach the promoted replica and the pool drops those to the old master
+    /// when they fail their recycle check
+    fn sentinel(
+        redis_env: &RedisConfig,
+        sentinels: &str,
+        certificates: Option<&TlsCertificates>,
+        encoding: CacheEncoding,
+    ) -> Result<Self, UtilError> {
+        // connections to the master are made by the sentinel client, which takes no certificates
+        if certificates.is_some() {
+            return Err(UtilError::RedisCertificatesUnsupported(
+                "sentinel".to_owned(),
+            ));
+        }
+        let sentinels = Self::split_urls(sentinels)
+            .into_iter()
+            .map(|url| url.into_connection_info().map_err(UtilError::from))
+            .collect::<Result<Vec<_>, _>>()?;
+        let master = redis_env
+            .sentinel_master
+            .clone()
+            .unwrap_or_else(|| "mymaster".to_owned());
+        let node = SentinelNodeConnectionInfo {
+            tls_mode: (redis_env.insecure.as_deref() != Some("true")).then_some(TlsMode::Secure),
+            redis_connection_info: Some(Self::with_credentials(
+                redis_env,
+                RedisConnectionInfo::default(),
+            )),
+        };
+        let manager = SentinelManager::new(
+            sentinels.clone(),
+            master.clone(),
+            Some(node.clone()),
+            SentinelServerType::Master,
+        )?;
+        let pool = RedisSentinelPool::builder(manager)
+            .runtime(Runtime::Tokio1)
+            .build()?;
+        Ok(Self {
+            pool: ConnectionPool::Sentinel(pool),
+            encoding,
+            nodes: RedisNodes::Sentinel {
+                sentinels,
+                master,
+                node,
+            },
+        })
+    }
+}
+
+impl CacheLayer for Redis {
+    fn encoding(&self) -> CacheEncoding {
+        self.encoding
+    }
+
+    /// Connects to the master named `redis_sentinel_master` through `redis_sentinels`, a
+    /// single instance at `redis_host` or a cluster at `redis_hosts`, in that order
     async fn new(env: &Env) -> Result<Self, UtilError> {
-        let redis_env = env.redis.as_ref().expect("Env var for Redis not set, host (for single instance) or hosts(for cluster) must be set");
+        let redis_env = env.redis.as_ref().ok_or(UtilError::RedisNotConfigured)?;
+        let encoding = CacheEncoding::from_env(env)?;
+        let certificates = Self::tls_certificates(redis_env)?;
+        if let Some(sentinels) = &redis_env.sentinels {
+            return Self::sentinel(redis_env, sentinels, certificates.as_ref(), encoding);
+        }
+
         if let Some(url) = &redis_env.host {
             let url_prefix = if redis_env.insecure == Some("true".to_string()) {
                 "redis"
@@ -276,29 +735,35 @@ impl CacheLayer for Redis {
                 url,
                 redis_env.port.clone().unwrap_or("6379".to_owned())
             );
-            let cfg = InstanceConfig::from_url(con_str);
-            let pool = cfg
-                .create_pool(Some(Runtime::Tokio1))
-                .map_err(UtilError::from)?;
+            let info = Self::connection_info(redis_env, &con_str, certificates.as_ref())?;
+            let pool = RedisInstancePool::builder(InstanceManager::new(info.clone())?)
+                .runtime(Runtime::Tokio1)
+                .build()?;
             return Ok(Self {
                 pool: ConnectionPool::Instance(pool),
+                encoding,
+                nodes: RedisNodes::Direct(vec![info]),
             });
         }
 
         if let Some(url) = &redis_env.hosts {
-            let urls = url
-                .split(',')
-                .map(|s| s.to_owned())
-                .collect::<Vec<String>>();
-            let cfg = ClusterConfig::from_urls(urls);
-            let pool = cfg
-                .create_pool(Some(Runtime::Tokio1))
-                .map_err(UtilError::from)?;
+            if redis_env.db.is_some_and(|db| db != 0) {
+                return Err(UtilError::RedisClusterDatabase);
+            }
+            let nodes = Self::split_urls(url)
+                .into_iter()
+                .map(|url| Self::connection_info(redis_env, url, None))
+                .collect::<Result<Vec<_>, _>>()?;
+            let manager = ClusterManager::new(nodes.clone(), certificates)?;
+            let pool = RedisClusterPool::builder(manager)
+                .runtime(Runtime::Tokio1)
+                .build()?;
             return Ok(Self {
                 pool: ConnectionPool::Cluster(pool),
+                encoding,
+                nodes: RedisNodes::Direct(nodes),
             });
         }
-
         Err(UtilError::RedisNotConfigured)
     }
 
@@ -315,11 +780,25 @@ impl CacheLayer for Redis {
         redis_op!(self, cmd("SET").arg(&args_vec))
     }
 
+    async fn set_value_if_absent(
+        &self,
+        key: &str,
+        value: &str,
+        expires: Option<u64>,
+    ) -> Result<bool, UtilError> {
+        let mut args_vec: Vec<String> = vec![key.into(), value.into(), "NX".into()];
+        if let Some(expires) = expires {
+            args_vec.append(&mut vec!["EX".into(), expires.to_string()])
+        };
+        let set: Option<String> = redis_op!(self, cmd("SET").arg(&args_vec))?;
+        Ok(set.is_some())
+    }
+
     async fn delete_value(&self, key: &str) -> Result<(), UtilError> {
         redis_op!(self, cmd("DEL").arg(&[key]))
     }
 
-    async fn get_value(&self, key: &str) -> Result<String, UtilError> {
+    async fn get_value(&self, key: &str) -> Result<Option<String>, UtilError> {
         redis_op!(self, cmd("get").arg(&[key]))
     }
 
@@ -327,4 +806,366 @@ impl CacheLayer for Redis {
         let result: usize = redis_op!(self, cmd("EXISTS").arg(&[key]))?;
         Ok(result == 1)
     }
+
+    async fn increment(&self, key: &str) -> Result<i64, UtilError> {
+        redis_op!(self, cmd("INCR").arg(key))
+    }
+
+    async fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>, UtilError> {
+        redis_op!(self, cmd("GET").arg(key))
+    }
+
+    async fn set_bytes(
+        &self,
+        key: &str,
+        value: &[u8],
+        expires: Option<u64>,
+    ) -> Result<(), UtilError> {
+        let mut set = cmd("SET");
+        set.arg(key).arg(value);
+        if let Some(expires) = expires {
+            set.arg("EX").arg(expires);
+        }
+        redis_op!(self, set)
+    }
+
+    async fn get_many_bytes(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, UtilError> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        // a cluster refuses pipelines and MGET across slots, so keys are read one by one
+        if let ConnectionPool::Cluster(_) = self.pool {
+            let gets = keys.iter().map(|key| self.get_bytes(key));
+            return futures::future::try_join_all(gets).await;
+        }
+        let mut get = pipe();
+        for key in keys {
+            get.cmd("GET").arg(key);
+        }
+        redis_op!(self, get)
+    }
+
+    async fn set_many_bytes(
+        &self,
+        values: &[(String, Vec<u8>)],
+        expires: Option<u64>,
+    ) -> Result<(), UtilError> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        if let ConnectionPool::Cluster(_) = self.pool {
+            let sets = values
+                .iter()
+                .map(|(key, value)| self.set_bytes(key, value, expires));
+            futures::future::try_join_all(sets).await?;
+            return Ok(());
+        }
+        let mut set = pipe();
+        for (key, value) in values {
+            set.cmd("SET").arg(key).arg(value);
+            if let Some(expires) = expires {
+                set.arg("EX").arg(expires);
+            }
+            set.ignore();
+        }
+        redis_op!(self, set)
+    }
+}
+
+/// Sets the lock if it is free and takes the next fencing token. The hash tag in the keys
+/// keeps the lock and its fence in one cluster slot
+const LOCK_ACQUIRE: &str = r#"
+if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+    return redis.call('INCR', KEYS[2])
+end
+return 0
+"#;
+
+/// Extends the lock only while it is still held by the owner
+const LOCK_RENEW: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return 0
+"#;
+
+/// Deletes the lock only while it is still held by the owner
+const LOCK_RELEASE: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+/// A lock held in Redis, renewed in the background until it is released or dropped.
+/// Dropping the guard stops the renewal and leaves the lock to expire, call `release` to
+/// free it straight away. Holding the guard does not prove the lock is still held, Redis
+/// may have lost it or renewal may have failed, so pass `fencing_token` to the resource
+/// being protected and have it reject tokens older than the last one it saw
+pub struct LockGuard {
+    cache: Redis,
+    key: String,
+    owner: String,
+    /// Increases every time the lock is taken
+    pub fencing_token: i64,
+    held: watch::Receiver<bool>,
+    renewer: JoinHandle<()>,
+}
+
+impl LockGuard {
+    /// False once a renewal found the lock expired or taken by another owner
+    pub fn is_held(&self) -> bool {
+        *self.held.borrow()
+    }
+
+    /// Resolves when the lock is lost
+    pub async fn lost(&self) {
+        let _ = self.held.clone().wait_for(|held| !held).await;
+    }
+
+    /// Free the lock, returns false if it had already been lost
+    pub async fn release(self) -> Result<bool, UtilError> {
+        self.renewer.abort();
+        let cache = &self.cache;
+        let released: i64 = redis_op!(
+            cache,
+            cmd("EVAL")
+                .arg(LOCK_RELEASE)
+                .arg(1)
+                .arg(&self.key)
+                .arg(&self.owner)
+        )?;
+        Ok(released == 1)
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        self.renewer.abort();
+    }
+}
+
+impl Redis {
+    fn lock_key(name: &str) -> String {
+        format!("lock:{{{name}}}")
+    }
+
+    /// Take the lock if it is free, it expires `ttl` after the last renewal
+    pub async fn try_lock(
+        &self,
+        name: &str,
+        ttl: Duration,
+    ) -> Result<Option<LockGuard>, UtilError> {
+        let key = Self::lock_key(name);
+        let owner = Uuid::new_v4().to_string();
+        let ttl_ms = ttl.as_millis().max(1) as u64;
+        let fencing_token: i64 = redis_op!(
+            self,
+            cmd("EVAL")
+                .arg(LOCK_ACQUIRE)
+                .arg(2)
+                .arg(&key)
+                .arg(format!("{key}:fence"))
+                .arg(&owner)
+                .arg(ttl_ms)
+        )?;
+        if fencing_token == 0 {
+            return Ok(None);
+        }
+
+        let (held_tx, held) = watch::channel(true);
+        let renewer = tokio::spawn(Self::renew(
+            self.clone(),
+            key.clone(),
+            owner.clone(),
+            ttl,
+            held_tx,
+        ));
+        Ok(Some(LockGuard {
+            cache: self.clone(),
+            key,
+            owner,
+            fencing_token,
+            held,
+            renewer,
+        }))
+    }
+
+    /// Take the lock, retrying until it is free or `timeout` passes
+    pub async fn lock(
+        &self,
+        name: &str,
+        ttl: Duration,
+        timeout: Duration,
+    ) -> Result<LockGuard, UtilError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let retry = (ttl / 10).clamp(Duration::from_millis(10), Duration::from_millis(500));
+        loop {
+            if let Some(guard) = self.try_lock(name, ttl).await? {
+                return Ok(guard);
+            }
+            if tokio::time::Instant::now() + retry > deadline {
+                return Err(UtilError::LockTimeout(name.to_owned()));
+            }
+            tokio::time::sleep(retry).await;
+        }
+    }
+
+    async fn renew_once(
+        cache: &Redis,
+        key: &str,
+        owner: &str,
+        ttl_ms: u64,
+    ) -> Result<i64, UtilError> {
+        redis_op!(
+            cache,
+            cmd("EVAL")
+                .arg(LOCK_RENEW)
+                .arg(1)
+                .arg(key)
+                .arg(owner)
+                .arg(ttl_ms)
+        )
+    }
+
+    /// Renew at a third of the ttl so a single failed renewal does not lose the lock
+    async fn renew(
+        cache: Redis,
+        key: String,
+        owner: String,
+        ttl: Duration,
+        held: watch::Sender<bool>,
+    ) {
+        let ttl_ms = ttl.as_millis().max(1) as u64;
+        let mut interval = tokio::time::interval(ttl / 3);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let renewed = Self::renew_once(&cache, &key, &owner, ttl_ms).await;
+            match renewed {
+                Ok(1) => {}
+                Ok(_) => {
+                    tracing::warn!("Lock {} was lost before it was renewed", key);
+                    let _ = held.send(false);
+                    return;
+                }
+                Err(e) => tracing::warn!("Renewing lock {} failed {:?}", key, e),
+            }
+        }
+    }
+}
+
+/// Elects one leader among the processes campaigning under the same name. Leadership is a
+/// lock renewed for as long as the leader runs
+#[derive(Clone)]
+pub struct LeaderElection {
+    pub cache: Redis,
+    pub name: String,
+    /// How long leadership outlives a leader that stopped renewing it
+    pub ttl: Duration,
+}
+
+impl LeaderElection {
+    pub fn new(cache: Redis, name: &str, ttl: Duration) -> Self {
+        Self {
+            cache,
+            name: name.to_owned(),
+            ttl,
+        }
+    }
+
+    /// Wait until elected, the guard's `lost` resolves if leadership is lost
+    pub async fn campaign(&self) -> Result<LockGuard, UtilError> {
+        let name = format!("leader:{}", self.name);
+        loop {
+            match self.cache.try_lock(&name, self.ttl).await {
+                Ok(Some(guard)) => return Ok(guard),
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Campaign for {} failed {:?}", self.name, e),
+            }
+            tokio::time::sleep(self.ttl / 2).await;
+        }
+    }
+
+    /// Run `lead` whenever elected, passing the fencing token of the term. If leadership is
+    /// lost `lead` is cancelled and the election campaigned for again, returns once `lead`
+    /// completes during a term
+    pub async fn run<T, F, Fut>(&self, lead: F) -> Result<T, UtilError>
+    where
+        F: Fn(i64) -> Fut,
+        Fut: Future<Output = Result<T, UtilError>>,
+    {
+        loop {
+            let guard = self.campaign().await?;
+            tokio::select! {
+                result = lead(guard.fencing_token) => {
+                    if let Err(e) = guard.release().await {
+                        tracing::warn!("Stepping down as {} leader failed {:?}", self.name, e);
+                    }
+                    return result;
+                }
+                _ = guard.lost() => {
+                    tracing::warn!("Lost leadership of {}", self.name);
+                }
+            }
+        }
+    }
+}
+
+/// GCRA, each request moves the key's theoretical arrival time on by `period / limit` and is
+/// refused while that time is more than `period` ahead of now. Uses the server's clock so
+/// replicas with drifting clocks share one schedule
+const RATE_LIMIT: &str = r#"
+local limit = tonumber(ARGV[1])
+local period = tonumber(ARGV[2])
+local interval = period / limit
+local time = redis.call('TIME')
+local now = time[1] * 1000 + math.floor(time[2] / 1000)
+local tat = math.max(tonumber(redis.call('GET', KEYS[1])) or now, now)
+local next_tat = tat + interval
+if next_tat - period > now then
+    return {0, 0, math.ceil(tat - now), math.ceil(next_tat - period - now)}
+end
+redis.call('SET', KEYS[1], tostring(next_tat), 'PX', math.ceil(next_tat - now))
+return {1, math.floor((now + period - next_tat) / interval), math.ceil(next_tat - now), 0}
+"#;
+
+/// Outcome of counting a request against a rate limit
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RateLimit {
+    pub allowed: bool,
+    pub limit: u64,
+    /// Requests that can still be made straight away
+    pub remaining: u64,
+    /// Until the limit is fully replenished
+    pub reset_after: Duration,
+    /// Until the next request will be allowed, zero when this one was
+    pub retry_after: Duration,
+}
+
+impl Redis {
+    /// Count a request against `key`, allowing at most `limit` requests in any `period`
+    pub async fn rate_limit(
+        &self,
+        key: &str,
+        limit: u64,
+        period: Duration,
+    ) -> Result<RateLimit, UtilError> {
+        let (allowed, remaining, reset_ms, retry_ms): (u8, u64, u64, u64) = redis_op!(
+            self,
+            cmd("EVAL")
+                .arg(RATE_LIMIT)
+                .arg(1)
+                .arg(format!("rate_limit:{key}"))
+                .arg(limit.max(1))
+                .arg(period.as_millis().max(1) as u64)
+        )?;
+        Ok(RateLimit {
+            allowed: allowed == 1,
+            limit,
+            remaining,
+            reset_after: Duration::from_millis(reset_ms),
+            retry_after: Duration::from_millis(retry_ms),
+        })
+    }
 }
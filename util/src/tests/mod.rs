@@ -1,4 +1,5 @@
 use crate::{
+    cache::MemoryCache,
     env::{Env, PostgresConfig, Redis as RedisConfig},
     error::UtilError,
     store::{Redis, RODB, RWDB},
@@ -16,7 +17,7 @@ fn get_db_config() -> PostgresConfig {
     }
 }
 
-fn get_test_env() -> Env {
+pub fn get_test_env() -> Env {
     Env {
         postgres: get_db_config(),
         server_port: Some(3031),
@@ -27,7 +28,13 @@ fn get_test_env() -> Env {
             insecure: Some("true".to_string()),
             ..RedisConfig::default()
         }),
+        broker: None,
         watch_topics: None,
+        rate_limits: None,
+        trusted_proxies: None,
+        cache_backend: None,
+        memory_cache_max_entries: None,
+        memory_cache_ttl: None,
     }
 }
 
@@ -72,3 +79,46 @@ impl TestApiState {
         Self::from_env(get_test_env()).await
     }
 }
+
+/// State of a node running without Redis, the database is only reached once a query is made
+#[derive(Clone)]
+pub struct MemoryApiState {
+    pub rw_db: RWDB,
+    pub ro_db: RODB,
+    pub env: Env,
+    pub cache: MemoryCache,
+}
+
+impl AppState for MemoryApiState {
+    type StateType = MemoryApiState;
+    type ErrorType = UtilError;
+
+    async fn from_env(env: Env) -> Result<Self::StateType, Self::ErrorType> {
+        Ok(Self {
+            rw_db: RWDB::connect_lazy(&env)?,
+            ro_db: RODB::connect_lazy(&env)?,
+            cache: MemoryCache::new(&env).await?,
+            env,
+        })
+    }
+
+    fn get_rw_store(&self) -> &RWDB {
+        &self.rw_db
+    }
+    fn get_ro_store(&self) -> &RODB {
+        &self.ro_db
+    }
+    fn get_env(&self) -> &Env {
+        &self.env
+    }
+
+    fn cache(&self) -> Option<&impl CacheLayer> {
+        Some(&self.cache)
+    }
+}
+
+impl MemoryApiState {
+    pub async fn from_test_env() -> Result<Self, UtilError> {
+        Self::from_env(get_test_env()).await
+    }
+}
@@ -0,0 +1,139 @@
+use std::time::Duration;
+use util::{
+    cache::{AppCache, MemoryCache, TieredCache},
+    env::Broker,
+    store::{CacheEncoding, CacheLayer},
+    tests::*,
+};
+
+#[tokio::test]
+async fn memory_cache_evicts_least_recently_used() {
+    let cache = MemoryCache::with_capacity(2, CacheEncoding::Json);
+    cache.set("a", &1, None).await.unwrap();
+    cache.set("b", &2, None).await.unwrap();
+    assert_eq!(cache.get::<i64>("a").await.unwrap(), Some(1));
+    cache.set("c", &3, None).await.unwrap();
+
+    assert_eq!(cache.get::<i64>("b").await.unwrap(), None);
+    assert_eq!(
+        cache
+            .get_many::<i64>(&["a".to_string(), "c".to_string()])
+            .await
+            .unwrap(),
+        vec![Some(1), Some(3)]
+    );
+}
+
+#[tokio::test]
+async fn memory_cache_expires_values() {
+    let cache = MemoryCache::with_capacity(10, CacheEncoding::Json);
+    cache.set_value("short", "1", Some(0)).await.unwrap();
+    cache.set_value("long", "1", Some(60)).await.unwrap();
+    assert!(!cache.value_exists("short").await.unwrap());
+    assert!(cache.set_value_if_absent("short", "2", None).await.unwrap());
+    assert!(!cache.set_value_if_absent("long", "2", None).await.unwrap());
+
+    assert_eq!(cache.increment("count").await.unwrap(), 1);
+    assert_eq!(cache.increment("count").await.unwrap(), 2);
+    assert!(cache.increment("long").await.is_ok());
+    cache.set_value("name", "cached", None).await.unwrap();
+    assert!(cache.increment("name").await.is_err());
+}
+
+#[tokio::test]
+async fn memory_cache_rate_limits() {
+    let cache = MemoryCache::with_capacity(10, CacheEncoding::Json);
+    let period = Duration::from_secs(60);
+    let first = cache.rate_limit("client", 2, period);
+    assert!(first.allowed);
+    assert_eq!(first.remaining, 1);
+    assert!(cache.rate_limit("client", 2, period).allowed);
+
+    let refused = cache.rate_limit("client", 2, period);
+    assert!(!refused.allowed);
+    assert!(refused.retry_after > Duration::from_secs(25));
+    assert!(cache.rate_limit("other", 2, period).allowed);
+}
+
+#[tokio::test]
+async fn app_cache_runs_without_redis() {
+    let mut env = get_test_env();
+    env.broker = Some(Broker {
+        backend: Some("memory".to_string()),
+        ..Broker::default()
+    });
+    assert!(matches!(
+        AppCache::new(&env).await.unwrap(),
+        AppCache::Memory(_)
+    ));
+
+    let mut env = get_test_env();
+    env.redis = None;
+    let cache = AppCache::new(&env).await.unwrap();
+    assert!(cache.redis().is_none());
+    cache.set_value("key", "value", None).await.unwrap();
+    assert_eq!(
+        cache.get_value("key").await.unwrap().as_deref(),
+        Some("value")
+    );
+    assert!(
+        cache
+            .rate_limit("key", 1, Duration::from_secs(1))
+            .await
+            .unwrap()
+            .allowed
+    );
+
+    assert!(matches!(
+        AppCache::new(&get_test_env()).await.unwrap(),
+        AppCache::Redis(_)
+    ));
+}
+
+#[tokio::test]
+async fn tiered_caches_invalidate_each_other() {
+    let state = TestApiState::from_test_env().await.unwrap();
+    let layers = || {
+        TieredCache::with_layers(
+            state.cache.clone(),
+            MemoryCache::with_capacity(10, CacheEncoding::Json),
+            60,
+        )
+    };
+    let (first, second) = (layers().await.unwrap(), layers().await.unwrap());
+
+    first.set("tiered", &1, Some(60)).await.unwrap();
+    assert_eq!(second.get::<i64>("tiered").await.unwrap(), Some(1));
+    first.set("tiered", &2, Some(60)).await.unwrap();
+    // the invalidation is delivered asynchronously, it only has to arrive
+    let invalidated = async {
+        while second.local.get::<i64>("tiered").await.unwrap().is_some() {
+            tokio::task::yield_now().await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), invalidated)
+        .await
+        .unwrap();
+    assert_eq!(second.get::<i64>("tiered").await.unwrap(), Some(2));
+    second.delete_value("tiered").await.unwrap();
+
+    let mut env = get_test_env();
+    env.cache_backend = Some("tiered".to_string());
+    assert!(matches!(
+        AppCache::new(&env).await.unwrap(),
+        AppCache::Tiered(_)
+    ));
+}
+
+#[tokio::test]
+async fn app_cache_backend_is_configurable() {
+    let mut env = get_test_env();
+    env.cache_backend = Some("memory".to_string());
+    assert!(matches!(
+        AppCache::new(&env).await.unwrap(),
+        AppCache::Memory(_)
+    ));
+
+    env.cache_backend = Some("disk".to_string());
+    assert!(AppCache::new(&env).await.is_err());
+}
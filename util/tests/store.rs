@@ -1,6 +1,299 @@
-use util::tests::*;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use util::{
+    error::UtilError,
+    outbox::OutboxEntry,
+    store::{CacheEncoding, CacheLayer, ConnectionPool, LeaderElection, Redis, RedisNodes},
+    tests::*,
+    AppState, RawParams,
+};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Cached {
+    name: String,
+    count: i64,
+}
 
 #[tokio::test]
 async fn get_test_state() {
     TestApiState::from_test_env().await.unwrap();
 }
+
+#[tokio::test]
+async fn outbox_entries_commit_with_transaction() {
+    let state = TestApiState::from_test_env().await.unwrap();
+    let message = RawParams(vec!["id".to_string(), "null".to_string()]);
+
+    let mut tx = state.get_rw_store().begin().await.unwrap();
+    let rolled_back = OutboxEntry::enqueue(&mut tx, "test", "1", "test_topic", &message)
+        .await
+        .unwrap();
+    tx.rollback().await.unwrap();
+
+    let mut tx = state.get_rw_store().begin().await.unwrap();
+    let committed = OutboxEntry::enqueue(&mut tx, "test", "1", "test_topic", &message)
+        .await
+        .unwrap();
+    tx.commit().await.unwrap();
+
+    let mut conn = state.get_rw_store().get_conn().acquire().await.unwrap();
+    let unsent = OutboxEntry::unsent(&mut conn, i64::MAX).await.unwrap();
+    assert!(unsent.iter().any(|e| e.id == committed));
+    assert!(!unsent.iter().any(|e| e.id == rolled_back));
+    OutboxEntry::mark_sent(&mut conn, &[committed])
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn constraint_errors_name_the_constraint() {
+    let state = TestApiState::from_test_env().await.unwrap();
+    let mut tx = state.get_rw_store().begin().await.unwrap();
+    sqlx::query(
+        "CREATE TEMP TABLE accounts (
+            email text NOT NULL CONSTRAINT accounts_email_key UNIQUE,
+            age int CONSTRAINT accounts_age_check CHECK (age > 0)
+        ) ON COMMIT DROP",
+    )
+    .execute(&mut *tx)
+    .await
+    .unwrap();
+    let insert = "INSERT INTO accounts (email, age) VALUES ($1, $2)";
+    sqlx::query(insert)
+        .bind("secret@example.com")
+        .bind(1)
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+
+    let mut violations = Vec::new();
+    for (email, age) in [
+        (Some("secret@example.com"), 1),
+        (None, 1),
+        (Some("a@example.com"), 0),
+    ] {
+        sqlx::query("SAVEPOINT violation")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        let error = sqlx::query(insert)
+            .bind(email)
+            .bind(age)
+            .execute(&mut *tx)
+            .await
+            .unwrap_err();
+        violations.push(UtilError::from(error));
+        sqlx::query("ROLLBACK TO SAVEPOINT violation")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+    }
+    tx.rollback().await.unwrap();
+
+    assert!(
+        matches!(&violations[0], UtilError::SqlDuplicateRecord(name) if name == "accounts_email_key")
+    );
+    assert!(matches!(&violations[1], UtilError::SqlNotNullViolation(name) if name == "email"));
+    assert!(
+        matches!(&violations[2], UtilError::SqlCheckFailed(name) if name == "accounts_age_check")
+    );
+    for violation in violations {
+        assert!(!violation.to_string().contains("secret"));
+        assert!(!violation.to_string().contains("violates"));
+    }
+}
+
+#[test]
+fn cache_encodings_round_trip() {
+    let value = Cached {
+        name: "cached".to_string(),
+        count: 3,
+    };
+    for encoding in [CacheEncoding::Json, CacheEncoding::MessagePack] {
+        let bytes = encoding.encode(&value).unwrap();
+        assert_eq!(encoding.decode::<Cached>(&bytes).unwrap(), value);
+    }
+    assert_eq!(
+        "msgpack".parse::<CacheEncoding>().unwrap(),
+        CacheEncoding::MessagePack
+    );
+    assert!("xml".parse::<CacheEncoding>().is_err());
+}
+
+#[tokio::test]
+async fn typed_cache_round_trips() {
+    let state = TestApiState::from_test_env().await.unwrap();
+    let cache = state.cache().unwrap();
+    let value = Cached {
+        name: "cached".to_string(),
+        count: 1,
+    };
+    cache.delete_value("test:typed:missing").await.unwrap();
+    assert_eq!(
+        cache.get::<Cached>("test:typed:missing").await.unwrap(),
+        None
+    );
+    cache.set("test:typed", &value, Some(60)).await.unwrap();
+    assert_eq!(cache.get("test:typed").await.unwrap(), Some(value));
+
+    let computed = cache
+        .get_or_set_with("test:typed:missing", Some(60), || async { Ok(5) })
+        .await
+        .unwrap();
+    assert_eq!(computed, 5);
+    let cached = cache
+        .get_or_set_with("test:typed:missing", Some(60), || async { Ok(6) })
+        .await
+        .unwrap();
+    assert_eq!(cached, 5);
+
+    let keys = vec!["test:many:1".to_string(), "test:many:2".to_string()];
+    cache
+        .set_many(&[(keys[0].clone(), 1), (keys[1].clone(), 2)], Some(60))
+        .await
+        .unwrap();
+    assert_eq!(
+        cache.get_many::<i64>(&keys).await.unwrap(),
+        vec![Some(1), Some(2)]
+    );
+}
+
+#[tokio::test]
+async fn lock_excludes_other_owners() {
+    let state = TestApiState::from_test_env().await.unwrap();
+    let ttl = Duration::from_millis(300);
+    let first = state.cache.try_lock("test", ttl).await.unwrap().unwrap();
+    assert!(state.cache.try_lock("test", ttl).await.unwrap().is_none());
+
+    // renewal keeps the lock past its ttl
+    tokio::time::sleep(ttl * 2).await;
+    assert!(first.is_held());
+    assert!(state.cache.try_lock("test", ttl).await.unwrap().is_none());
+
+    let token = first.fencing_token;
+    assert!(first.release().await.unwrap());
+    let second = state
+        .cache
+        .lock("test", ttl, Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert!(second.fencing_token > token);
+    second.release().await.unwrap();
+}
+
+#[tokio::test]
+async fn leader_election_elects_one_leader() {
+    let state = TestApiState::from_test_env().await.unwrap();
+    let name = uuid::Uuid::new_v4().to_string();
+    let ttl = Duration::from_millis(300);
+    let first = LeaderElection::new(state.cache.clone(), &name, ttl);
+    let second = LeaderElection::new(state.cache.clone(), &name, ttl);
+
+    let leader = first.campaign().await.unwrap();
+    let waiting = tokio::time::timeout(ttl * 3, second.campaign()).await;
+    assert!(waiting.is_err());
+
+    let token = leader.fencing_token;
+    leader.release().await.unwrap();
+    let leader = second.campaign().await.unwrap();
+    assert!(leader.fencing_token > token);
+    leader.release().await.unwrap();
+}
+
+#[tokio::test]
+async fn leader_election_campaigns_again_when_lost() {
+    let state = TestApiState::from_test_env().await.unwrap();
+    let name = uuid::Uuid::new_v4().to_string();
+    let election = LeaderElection::new(state.cache.clone(), &name, Duration::from_millis(300));
+    let terms = std::sync::Mutex::new(Vec::new());
+
+    let token = election
+        .run(|token| {
+            let first_term = {
+                let mut terms = terms.lock().unwrap();
+                terms.push(token);
+                terms.len() == 1
+            };
+            let cache = state.cache.clone();
+            let lock = format!("lock:{{leader:{}}}", name);
+            async move {
+                if first_term {
+                    // another process taking over, lead is cancelled once renewal notices
+                    cache.delete_value(&lock).await?;
+                    std::future::pending::<()>().await;
+                }
+                Ok(token)
+            }
+        })
+        .await
+        .unwrap();
+
+    let terms = terms.into_inner().unwrap();
+    assert_eq!(terms.len(), 2);
+    assert!(terms[1] > terms[0]);
+    assert_eq!(token, terms[1]);
+}
+
+#[tokio::test]
+async fn rate_limit_refuses_over_limit() {
+    let state = TestApiState::from_test_env().await.unwrap();
+    let key = format!("test:{}", uuid::Uuid::new_v4());
+    let period = Duration::from_secs(60);
+    for remaining in (0..3).rev() {
+        let limited = state.cache.rate_limit(&key, 3, period).await.unwrap();
+        assert!(limited.allowed);
+        assert_eq!(limited.remaining, remaining);
+    }
+    let limited = state.cache.rate_limit(&key, 3, period).await.unwrap();
+    assert!(!limited.allowed);
+    assert!(limited.retry_after > Duration::ZERO && limited.retry_after <= period / 3);
+}
+
+#[tokio::test]
+async fn redis_connects_with_env_credentials() {
+    let mut env = get_test_env();
+    let redis_env = env.redis.as_mut().unwrap();
+    redis_env.username = Some("api".to_string());
+    redis_env.password = Some("secret".to_string());
+    redis_env.db = Some(2);
+    let Ok(Redis {
+        nodes: RedisNodes::Direct(nodes),
+        ..
+    }) = Redis::new(&env).await
+    else {
+        panic!("expected a single instance");
+    };
+    assert_eq!(nodes[0].redis.username.as_deref(), Some("api"));
+    assert_eq!(nodes[0].redis.password.as_deref(), Some("secret"));
+    assert_eq!(nodes[0].redis.db, 2);
+
+    let redis_env = env.redis.as_mut().unwrap();
+    redis_env.client_cert = Some("client.pem".to_string());
+    assert!(matches!(
+        Redis::new(&env).await,
+        Err(UtilError::RedisCertificate(_))
+    ));
+}
+
+#[tokio::test]
+async fn redis_cluster_refuses_database() {
+    let mut env = get_test_env();
+    let redis_env = env.redis.as_mut().unwrap();
+    redis_env.host = None;
+    redis_env.hosts = Some("redis://localhost:7000, redis://localhost:7001".to_string());
+    let Ok(Redis {
+        pool: ConnectionPool::Cluster(_),
+        nodes: RedisNodes::Direct(nodes),
+        ..
+    }) = Redis::new(&env).await
+    else {
+        panic!("expected a cluster");
+    };
+    assert_eq!(nodes.len(), 2);
+
+    env.redis.as_mut().unwrap().db = Some(2);
+    assert!(matches!(
+        Redis::new(&env).await,
+        Err(UtilError::RedisClusterDatabase)
+    ));
+}